@@ -1,17 +1,144 @@
-use clap::Parser;
+use std::{io::IsTerminal, process::ExitCode};
+
+use callisto_interpreter::{
+    diagnostic::Diagnostic,
+    format::{DEFAULT_WIDTH, format_str},
+    parser::parse_str_recovering,
+    vm::Vm,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The file to execute
-    #[clap(value_parser)]
-    file: String,
+    #[clap(value_parser, required = true)]
+    file: Option<String>,
+
+    /// Disable colored error output
+    #[clap(long, global = true)]
+    no_color: bool,
+
+    /// How errors are reported
+    #[clap(long, value_enum, global = true, default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Format files in the canonical style
+    Fmt {
+        /// The files to format
+        #[clap(value_parser, required = true)]
+        files: Vec<String>,
+
+        /// Report files that are not formatted instead of rewriting them
+        #[clap(long)]
+        check: bool,
+
+        /// The line width to aim for
+        #[clap(long, default_value_t = DEFAULT_WIDTH)]
+        width: usize,
+    },
 }
 
-fn main() -> anyhow::Result<()> {
-    let Args { file } = Args::try_parse()?;
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    /// Annotated source excerpts
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let args = Args::try_parse()?;
+    match &args.command {
+        Some(Command::Fmt {
+            files,
+            check,
+            width,
+        }) => fmt(&args, files, *check, *width),
+        None => run(&args, args.file.as_deref().unwrap_or_default()),
+    }
+}
+
+fn run(args: &Args, file: &str) -> anyhow::Result<ExitCode> {
     let input = std::fs::read_to_string(file)?;
-    let result = callisto_interpreter::vm::execute_str(&input)?;
-    println!("{:?}", result);
+    let (syntax_tree, errors) = parse_str_recovering(&input);
+    if !errors.is_empty() {
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+        report(args, &input, file, &diagnostics)?;
+        return Ok(ExitCode::FAILURE);
+    }
+    match Vm::new().execute_syntax(syntax_tree) {
+        Ok(result) => {
+            println!("{result}");
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(err) => {
+            report(args, &input, file, &[Diagnostic::from(&err)])?;
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Formats each file in place, or with `check`, lists the files that would change.
+fn fmt(args: &Args, files: &[String], check: bool, width: usize) -> anyhow::Result<ExitCode> {
+    let mut success = true;
+    for file in files {
+        let input = std::fs::read_to_string(file)?;
+        let formatted = match format_str(&input, width) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+                report(args, &input, file, &diagnostics)?;
+                success = false;
+                continue;
+            }
+        };
+        if formatted == input {
+            continue;
+        }
+        if check {
+            println!("{file} is not formatted");
+            success = false;
+        } else {
+            std::fs::write(file, formatted)?;
+        }
+    }
+    Ok(if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn report(
+    args: &Args,
+    source: &str,
+    origin: &str,
+    diagnostics: &[Diagnostic],
+) -> anyhow::Result<()> {
+    for diagnostic in diagnostics {
+        match args.error_format {
+            ErrorFormat::Human => {
+                let color = !args.no_color
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && std::io::stderr().is_terminal();
+                eprintln!("{}\n", diagnostic.render(source, origin, color));
+            }
+            ErrorFormat::Json => eprintln!("{}", serde_json::to_string(diagnostic)?),
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,166 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    hint::black_box,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use callisto_interpreter::{lexer::tokenize, parser::parse_str, vm::execute_str};
+use criterion::{
+    BenchmarkGroup, Criterion, Throughput, criterion_group, criterion_main,
+    measurement::{Measurement, ValueFormatter, WallTime},
+};
+
+/// Counts every allocation the benchmarks make, so they can be measured in allocations as
+/// well as in time.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Measures how many times a benchmark allocates or reallocates memory.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (count, unit) = match throughput {
+            Throughput::Bytes(bytes) | Throughput::BytesDecimal(bytes) => (*bytes, "allocs/B"),
+            Throughput::Elements(elements) => (*elements, "allocs/elem"),
+        };
+        for value in values {
+            *value /= count as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+/// Reads every file in `examples/`, sorted by name.
+fn examples() -> Vec<(String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../examples");
+    let mut examples: Vec<_> = fs::read_dir(dir)
+        .expect("examples directory")
+        .map(|entry| {
+            let path = entry.expect("examples directory entry").path();
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(&path).expect("example source"))
+        })
+        .collect();
+    examples.sort();
+    examples
+}
+
+/// Benchmarks each stage on the examples: lexing, parsing, which includes lexing, and
+/// executing, which includes both. Examples that fail to run, such as sketches of features
+/// that do not exist yet, are left out of executing, since only their error path would be
+/// measured.
+///
+/// The groups are named after the stage and `measure`, so that results in time and in
+/// allocations are kept apart.
+fn stages<M: Measurement>(c: &mut Criterion<M>, measure: &str) {
+    let examples = examples();
+    let running: Vec<_> = (examples.iter())
+        .filter(|(_, source)| execute_str(source).is_ok())
+        .cloned()
+        .collect();
+    let mut bench = |stage: &str, examples: &[(String, String)], run: fn(&str)| {
+        let mut group: BenchmarkGroup<M> = c.benchmark_group(format!("{stage}/{measure}"));
+        for (name, source) in examples {
+            group.throughput(Throughput::Bytes(source.len() as u64));
+            group.bench_function(name.as_str(), |b| b.iter(|| run(black_box(source))));
+        }
+        group.finish();
+    };
+    bench("tokenize", &examples, |source| {
+        black_box(tokenize(source));
+    });
+    bench("parse_str", &examples, |source| {
+        let _ = black_box(parse_str(source));
+    });
+    bench("execute_str", &running, |source| {
+        let _ = black_box(execute_str(source));
+    });
+}
+
+fn time(c: &mut Criterion<WallTime>) {
+    stages(c, "time");
+}
+
+fn allocations(c: &mut Criterion<Allocations>) {
+    stages(c, "allocations");
+}
+
+criterion_group!(time_benches, time);
+criterion_group! {
+    name = allocation_benches;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = allocations
+}
+criterion_main!(time_benches, allocation_benches);
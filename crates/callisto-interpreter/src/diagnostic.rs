@@ -0,0 +1,312 @@
+use annotate_snippets::{Level, Renderer, Snippet};
+
+use crate::{lexer::LexingError, parser::ParsingError, span::Span, vm::RuntimeError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+    Help,
+}
+
+impl Severity {
+    fn level(self) -> Level {
+        match self {
+            Severity::Error => Level::Error,
+            Severity::Warning => Level::Warning,
+            Severity::Note => Level::Note,
+            Severity::Help => Level::Help,
+        }
+    }
+}
+
+/// A message attached to a region of source code.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Label {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+        }
+    }
+}
+
+/// A user-facing report of a problem in a source file.
+///
+/// The primary label points at the cause of the problem, secondary labels point at
+/// related code (e.g. where an unclosed list was opened).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub primary: Option<Label>,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            primary: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.primary = Some(Label::new(span, message));
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.secondary.push(Label::new(span, message));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic as an annotated excerpt of `source`.
+    ///
+    /// `origin` is the name shown for the source, usually its file path.
+    pub fn render(&self, source: &str, origin: &str, color: bool) -> String {
+        let level = self.severity.level();
+        let mut message = level.title(&self.message);
+
+        let mut annotations = Vec::new();
+        if let Some(primary) = &self.primary {
+            annotations.push(
+                level
+                    .span(clamp(primary.span, source))
+                    .label(&primary.message),
+            );
+        }
+        for label in &self.secondary {
+            annotations.push(
+                Level::Info
+                    .span(clamp(label.span, source))
+                    .label(&label.message),
+            );
+        }
+        if !annotations.is_empty() {
+            message = message.snippet(
+                Snippet::source(source)
+                    .origin(origin)
+                    .fold(true)
+                    .annotations(annotations),
+            );
+        }
+        message = message.footers(self.notes.iter().map(|note| Level::Note.title(note)));
+
+        let renderer = if color {
+            Renderer::styled()
+        } else {
+            Renderer::plain()
+        };
+        renderer.render(message).to_string()
+    }
+}
+
+fn clamp(span: Span, source: &str) -> std::ops::Range<usize> {
+    let start = span.start.min(source.len());
+    let end = span.end.clamp(start, source.len());
+    start..end
+}
+
+impl From<&LexingError> for Diagnostic {
+    fn from(err: &LexingError) -> Self {
+        match err {
+            LexingError::InvalidToken { lexeme, span } => {
+                Diagnostic::error(format!("invalid token `{lexeme}`"))
+                    .with_primary(*span, "not recognized by the lexer")
+            }
+            LexingError::InvalidEscape { escape, span } => {
+                Diagnostic::error(format!("invalid escape sequence `{escape}`"))
+                    .with_primary(*span, "unknown escape")
+                    .with_note(r#"valid escapes are \n, \r, \t, \", \\ and \u{...}"#)
+            }
+            LexingError::InvalidNumber { lexeme, span } => {
+                Diagnostic::error(format!("invalid number `{lexeme}`"))
+                    .with_primary(*span, "malformed or out of range")
+            }
+            LexingError::AmbiguousSign { lexeme, span } => {
+                Diagnostic::error(format!("ambiguous sign in `{lexeme}`"))
+                    .with_primary(*span, "signed number directly follows another token")
+                    .with_note("add a space before the sign, or write `(- a b)` to subtract")
+            }
+            LexingError::UnterminatedComment { span } => {
+                Diagnostic::error("unterminated block comment")
+                    .with_primary(*span, "comment starts here and is never closed")
+                    .with_note("block comments nest, so every `#|` needs its own `|#`")
+            }
+            LexingError::EndOfInput { span } => {
+                Diagnostic::error("unexpected end of input").with_primary(*span, "input ends here")
+            }
+        }
+    }
+}
+
+impl From<&ParsingError> for Diagnostic {
+    fn from(err: &ParsingError) -> Self {
+        match err {
+            ParsingError::LexingError(err) => err.into(),
+            ParsingError::UnexpectedToken { token, span } => {
+                Diagnostic::error(format!("unexpected token {token:?}"))
+                    .with_primary(*span, "unexpected token")
+            }
+            ParsingError::UnclosedList { close, opened_at } => {
+                Diagnostic::error(format!("unclosed `{}`", opening_delimiter(*close)))
+                    .with_primary(*opened_at, "this delimiter is never closed")
+                    .with_note(format!("add a matching `{close}` to close it"))
+            }
+            ParsingError::MissingCommentedForm { span } => {
+                Diagnostic::error("datum comment is not followed by a form")
+                    .with_primary(*span, "nothing to comment out")
+            }
+            ParsingError::MissingQuotedForm { prefix, span } => {
+                Diagnostic::error(format!("`{prefix}` is not followed by a form"))
+                    .with_primary(*span, "nothing to quote")
+            }
+            ParsingError::MissingLetValue { name, span } => {
+                Diagnostic::error(format!("`let {name} =` is not followed by a value"))
+                    .with_primary(*span, "expected a value after `=`")
+            }
+            ParsingError::UnmatchedClose { close, span } => {
+                let open = opening_delimiter(*close);
+                Diagnostic::error(format!("unmatched closing `{close}`"))
+                    .with_primary(*span, format!("no matching `{open}`"))
+                    .with_note(format!(
+                        "remove this `{close}` or add a matching `{open}` before it"
+                    ))
+            }
+            ParsingError::MismatchedClose {
+                expected,
+                found,
+                span,
+                opened_at,
+            } => Diagnostic::error(format!("expected `{expected}`, found `{found}`"))
+                .with_primary(*span, "mismatched closing delimiter")
+                .with_secondary(
+                    *opened_at,
+                    format!("`{}` opened here", opening_delimiter(*expected)),
+                ),
+            ParsingError::OddMapEntries { span } => {
+                Diagnostic::error("map literal has an odd number of forms")
+                    .with_primary(*span, "every key needs a value")
+            }
+        }
+    }
+}
+
+fn opening_delimiter(close: char) -> char {
+    match close {
+        ']' => '[',
+        '}' => '{',
+        _ => '(',
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(err: &RuntimeError) -> Self {
+        match err {
+            RuntimeError::LexingError(err) => err.into(),
+            RuntimeError::ParsingError(err) => err.into(),
+            RuntimeError::Spanned { error, span } => {
+                let mut diagnostic = Diagnostic::from(error.as_ref());
+                diagnostic.primary = Some(Label::new(*span, runtime_label(error.inner())));
+                diagnostic
+            }
+            RuntimeError::StackOverflow { frames, .. } => Diagnostic::error(err.to_string())
+                .with_note(format!("innermost calls: {}", frames.join(" -> "))),
+            err => Diagnostic::error(err.to_string()),
+        }
+    }
+}
+
+fn runtime_label(err: &RuntimeError) -> &'static str {
+    match err {
+        RuntimeError::InvalidArgumentCount { .. }
+        | RuntimeError::InvalidOptionalArgumentCount { .. } => "wrong number of arguments",
+        RuntimeError::SyntaxError { .. } | RuntimeError::InvalidSyntax(_) => "invalid syntax here",
+        RuntimeError::TypeError { .. } => "mismatched types",
+        RuntimeError::UndefinedVariable(_) => "not defined in this scope",
+        RuntimeError::UndefinedFunction(_) | RuntimeError::UndefinedIdentifier(_) => "not defined",
+        RuntimeError::NotCallable { .. } => "not a function",
+        RuntimeError::DivisionByZero => "division by zero",
+        RuntimeError::InvalidOperation { .. } => "invalid operation",
+        RuntimeError::CannotConvertToNode(_) => "cannot be used as a graph node",
+        RuntimeError::InvalidPatchTarget(_) => "not an output or bus",
+        RuntimeError::UnknownChordQuality(_) => "unknown chord quality",
+        RuntimeError::UnquoteOutsideQuasiquote(_) => "not inside a quasiquote",
+        RuntimeError::OutsideLoop(_) => "not inside a loop",
+        RuntimeError::ZeroRangeStep => "step cannot be zero",
+        RuntimeError::StackOverflow { .. } => "too deeply nested",
+        _ => "error occurred here",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::execute_str;
+
+    #[test]
+    fn test_runtime_diagnostic() {
+        let err = execute_str("(define x 1)\n(+ x :foo)").unwrap_err();
+        let diagnostic = Diagnostic::from(&err);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(
+            diagnostic.primary,
+            Some(Label::new(Span::new(13, 23, 2, 1), "invalid operation"))
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow_diagnostic() {
+        let err = execute_str("(func f (n) (+ 1 (f n)))\n(f 1)").unwrap_err();
+        let diagnostic = Diagnostic::from(&err);
+        assert_eq!(diagnostic.primary.unwrap().message, "too deeply nested");
+        assert_eq!(
+            diagnostic.notes,
+            vec!["innermost calls: f -> f -> f -> f -> f -> f -> f -> f"]
+        );
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let source = "(define x 1)\n(+ x :foo)";
+        let err = execute_str(source).unwrap_err();
+        let rendered = Diagnostic::from(&err).render(source, "test.callisto", false);
+        assert!(rendered.starts_with("error: Invalid operation: +"));
+        assert!(rendered.contains("--> test.callisto:2:1"));
+        assert!(rendered.contains("^^^^^^^^^^ invalid operation"));
+    }
+
+    #[test]
+    fn test_render_end_of_input() {
+        let source = "(foo";
+        let diagnostic = Diagnostic::from(&LexingError::EndOfInput {
+            span: Span::new(4, 4, 1, 5),
+        });
+        let rendered = diagnostic.render(source, "test.callisto", false);
+        assert!(rendered.contains("input ends here"));
+    }
+}
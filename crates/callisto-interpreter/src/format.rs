@@ -0,0 +1,391 @@
+use crate::{
+    lexer::token::TokenKind,
+    parser::{
+        ParsingError,
+        cst::{CstElement, CstNode, CstNodeKind, CstToken, parse_cst},
+        parse_str_recovering,
+    },
+};
+
+/// The line width [`format_str`] aims for unless told otherwise.
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// Formats a source file in the canonical style.
+///
+/// Lists, vectors and maps are kept on one line if they fit within `width` and contain no
+/// line comments. Otherwise their elements are broken onto separate lines: special forms such as
+/// `define` keep their first arguments next to the name and indent the body by two spaces,
+/// calls align their arguments under the first one, and maps put each key-value pair on
+/// its own line. Comments and single blank lines are preserved.
+///
+/// Returns the parse errors instead if the source does not parse, since the formatter
+/// should never change what a program means.
+pub fn format_str(source: &str, width: usize) -> Result<String, Vec<ParsingError>> {
+    let (_, errors) = parse_str_recovering(source);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Formatter { width }.root(&parse_cst(source)))
+}
+
+/// Returns how many arguments of a special form stay on the same line as its name when
+/// the form is broken across lines, or `None` for ordinary calls.
+fn header_arguments(name: &str) -> Option<usize> {
+    match name {
+        "do" | "cond" => Some(0),
+        "define" | "let" | "if" | "when" | "unless" | "loop" | "while" | "dotimes" | "for"
+        | "fn" | "lambda" => Some(1),
+        "func" => Some(2),
+        _ => None,
+    }
+}
+
+/// A significant element of a sequence, together with the comments around it.
+#[derive(Default)]
+struct Item<'a> {
+    /// Whether a blank line directly precedes the element.
+    blank_line: bool,
+    /// Comments on their own lines before the element, each with whether a blank line
+    /// preceded it.
+    comments: Vec<(bool, &'a CstToken)>,
+    /// `None` for comments at the end of a sequence, which belong to no element.
+    element: Option<&'a CstElement>,
+    /// A comment on the same line, after the element.
+    trailing: Option<&'a CstToken>,
+}
+
+impl Item<'_> {
+    /// Returns true if nothing forces this item onto a line of its own.
+    fn is_plain(&self) -> bool {
+        !self.blank_line && self.comments.is_empty() && self.element.is_some()
+    }
+
+    fn atom(&self) -> Option<&str> {
+        match self.element {
+            Some(CstElement::Token(token))
+                if self.is_plain() && !self.trailing.is_some_and(|t| t.is_line_comment()) =>
+            {
+                Some(&token.text)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Groups the children of a node into items, attaching each comment to a neighboring
+/// element.
+fn items(children: &[CstElement]) -> Vec<Item<'_>> {
+    let mut items: Vec<Item> = Vec::new();
+    let mut pending = Item::default();
+    let mut newlines = 0;
+
+    for child in children {
+        match child {
+            CstElement::Token(token) if token.kind == Some(TokenKind::Whitespace) => {
+                newlines += token.text.matches('\n').count();
+            }
+            CstElement::Token(token) if token.is_comment() => {
+                let blank_line =
+                    newlines > 1 && (!items.is_empty() || !pending.comments.is_empty());
+                match items.last_mut() {
+                    Some(last)
+                        if newlines == 0
+                            && last.trailing.is_none()
+                            && pending.comments.is_empty() =>
+                    {
+                        last.trailing = Some(token);
+                    }
+                    _ => pending.comments.push((blank_line, token)),
+                }
+                newlines = 0;
+            }
+            element => {
+                pending.blank_line =
+                    newlines > 1 && (!items.is_empty() || !pending.comments.is_empty());
+                pending.element = Some(element);
+                items.push(std::mem::take(&mut pending));
+                newlines = 0;
+            }
+        }
+    }
+    if !pending.comments.is_empty() {
+        items.push(pending);
+    }
+    items
+}
+
+/// Returns the column the next character appended to `out` will be at, given that `out`
+/// starts at column `start`.
+fn column(out: &str, start: usize) -> usize {
+    match out.rfind('\n') {
+        Some(i) => out[i + 1..].chars().count(),
+        None => start + out.chars().count(),
+    }
+}
+
+fn newline(out: &mut String, indent: usize, blank_line: bool) {
+    out.push('\n');
+    if blank_line {
+        out.push('\n');
+    }
+    out.extend(std::iter::repeat_n(' ', indent));
+}
+
+struct Formatter {
+    width: usize,
+}
+
+impl Formatter {
+    fn root(&self, root: &CstNode) -> String {
+        let items = items(&root.children);
+        // Keep `let name = value` statements on one line.
+        let mut joined = vec![false; items.len()];
+        for (i, window) in items.windows(4).enumerate() {
+            if window[0].atom() == Some("let")
+                && window[1].atom().is_some()
+                && window[2].atom() == Some("=")
+                && window[3].is_plain()
+            {
+                joined[i + 1..i + 4].fill(true);
+            }
+        }
+
+        let mut out = String::new();
+        self.emit_items(&mut out, 0, &items, 0, |i| joined[i]);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Formats an element whose first character is at column `col`.
+    fn element(&self, element: &CstElement, col: usize) -> String {
+        match element {
+            CstElement::Token(token) => token.text.clone(),
+            CstElement::Node(node) if node.kind == CstNodeKind::Prefixed => {
+                self.prefixed(node, col)
+            }
+            CstElement::Node(node) => match self.flat(element) {
+                Some(flat) if col + flat.chars().count() <= self.width => flat,
+                _ => self.broken(node, col),
+            },
+        }
+    }
+
+    fn prefixed(&self, node: &CstNode, col: usize) -> String {
+        if node.has_comments() {
+            return node.text();
+        }
+        let mut significant = node.children.iter().filter(|child| !child.is_trivia());
+        let mut out = String::new();
+        if let Some(prefix) = significant.next() {
+            prefix.write_text(&mut out);
+        }
+        if let Some(form) = significant.next() {
+            let form = self.element(form, column(&out, col));
+            out.push_str(&form);
+        }
+        out
+    }
+
+    /// Formats an element on a single line, or returns `None` if it cannot be.
+    fn flat(&self, element: &CstElement) -> Option<String> {
+        let node = match element {
+            CstElement::Token(token) if token.text.contains('\n') => return None,
+            CstElement::Token(token) => return Some(token.text.clone()),
+            CstElement::Node(node) if node.kind == CstNodeKind::Prefixed && node.has_comments() => {
+                return None;
+            }
+            CstElement::Node(node) => node,
+        };
+        let mut parts = Vec::new();
+        for child in &node.children {
+            match child {
+                // A line comment ends the line, but a block comment can stay inline.
+                CstElement::Token(token) if token.is_line_comment() => return None,
+                CstElement::Token(token) if token.is_comment() => parts.push(self.flat(child)?),
+                child if child.is_trivia() => {}
+                child => parts.push(self.flat(child)?),
+            }
+        }
+        Some(match node.kind {
+            CstNodeKind::Prefixed => parts.concat(),
+            _ => match parts.as_slice() {
+                [open, elements @ .., close] => format!("{open}{}{close}", elements.join(" ")),
+                _ => parts.concat(),
+            },
+        })
+    }
+
+    /// Formats a list, vector or map across several lines.
+    fn broken(&self, node: &CstNode, col: usize) -> String {
+        let (open, rest) = node
+            .children
+            .split_first()
+            .expect("sequence without opening");
+        let (inner, close) = match rest.split_last() {
+            Some((CstElement::Token(close), inner)) if close.kind.is_some_and(is_close) => {
+                (inner, Some(close))
+            }
+            _ => (rest, None),
+        };
+        let items = items(inner);
+        let mut out = String::new();
+        open.write_text(&mut out);
+
+        let head = items.first().and_then(|item| item.atom());
+        let ends_with_line_comment = match (node.kind, head) {
+            (CstNodeKind::List, Some(head)) => match header_arguments(head) {
+                Some(header) => self.emit_items(&mut out, col, &items, col + 2, |i| i <= header),
+                None => {
+                    let indent = col + 1 + head.chars().count() + 1;
+                    self.emit_items(&mut out, col, &items, indent, |i| i == 1)
+                }
+            },
+            (CstNodeKind::Map, _) => {
+                self.emit_items(&mut out, col, &items, col + 1, |i| i % 2 == 1)
+            }
+            _ => self.emit_items(&mut out, col, &items, col + 1, |_| false),
+        };
+
+        if let Some(close) = close {
+            if ends_with_line_comment {
+                newline(&mut out, col, false);
+            }
+            out.push_str(&close.text);
+        }
+        out
+    }
+
+    /// Appends items to `out`, which starts at column `start`. Items are put on separate
+    /// lines indented to `indent`, except those for which `joined` returns true, which
+    /// stay on the previous line when possible.
+    ///
+    /// Returns true if the output ends with a line comment, so that whatever follows must
+    /// go on the next line.
+    fn emit_items(
+        &self,
+        out: &mut String,
+        start: usize,
+        items: &[Item],
+        indent: usize,
+        joined: impl Fn(usize) -> bool,
+    ) -> bool {
+        let mut after_line_comment = false;
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                let previous = &items[i - 1];
+                let breaks = previous.trailing.is_some_and(|t| t.is_line_comment());
+                if joined(i) && item.is_plain() && !breaks {
+                    out.push(' ');
+                } else {
+                    let blank_line = match item.comments.first() {
+                        Some((blank_line, _)) => *blank_line,
+                        None => item.blank_line,
+                    };
+                    newline(out, indent, blank_line);
+                }
+            }
+            for (j, (blank_line, comment)) in item.comments.iter().enumerate() {
+                if j > 0 {
+                    newline(out, indent, *blank_line);
+                }
+                out.push_str(&comment.text);
+                after_line_comment = comment.is_line_comment();
+            }
+            if let Some(element) = item.element {
+                if !item.comments.is_empty() {
+                    newline(out, indent, item.blank_line);
+                }
+                let formatted = self.element(element, column(out, start));
+                out.push_str(&formatted);
+                after_line_comment = false;
+            }
+            if let Some(trailing) = item.trailing {
+                out.push(' ');
+                out.push_str(&trailing.text);
+                after_line_comment = trailing.is_line_comment();
+            }
+        }
+        after_line_comment
+    }
+}
+
+fn is_close(kind: TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    fn format(source: &str) -> String {
+        format_str(source, DEFAULT_WIDTH).unwrap()
+    }
+
+    #[test]
+    fn test_format_flat() {
+        assert_eq!(format("(define   x\n  (+ 1   2))"), "(define x (+ 1 2))\n");
+        assert_eq!(format("[1  2 ]  { :a 1 }"), "[1 2]\n{:a 1}\n");
+        assert_eq!(format("' ( a  b )  #_  x"), "'(a b)\n#_x\n");
+        assert_eq!(format("let  foo =\n 5"), "let foo = 5\n");
+        assert_eq!(format(""), "");
+    }
+
+    #[test]
+    fn test_format_breaks_long_lists() {
+        let source = "(define chain (>> (saw (* 110 2)) (lpf 800) (hpf 200) (lpf 1200)))";
+        assert_eq!(
+            format_str(source, 40).unwrap(),
+            "(define chain\n  (>> (saw (* 110 2))\n      (lpf 800)\n      (hpf 200)\n      (lpf 1200)))\n"
+        );
+        let source = "(func play (note velocity) (do (sine (freq note)) (* velocity 0.5)))";
+        assert_eq!(
+            format_str(source, 40).unwrap(),
+            "(func play (note velocity)\n  (do\n    (sine (freq note))\n    (* velocity 0.5)))\n"
+        );
+        let source = "{:cutoff 800 :res 0.3 :drive 2}";
+        assert_eq!(
+            format_str(source, 20).unwrap(),
+            "{:cutoff 800\n :res 0.3\n :drive 2}\n"
+        );
+    }
+
+    #[test]
+    fn test_format_preserves_comments() {
+        let source =
+            ";; header\n\n\n(a) // trailing\n;; before b\n(b\n  ;; inside\n  c ;; after c\n)";
+        assert_eq!(
+            format(source),
+            ";; header\n\n(a) // trailing\n;; before b\n(b\n   ;; inside\n   c ;; after c\n)\n"
+        );
+        assert_eq!(format("(a #| x |#\n  b)"), "(a #| x |# b)\n");
+        assert_eq!(format("(a // x\n b)"), "(a // x\n b)\n");
+        assert_eq!(format("(a #| x\n y |# b)"), "(a #| x\n y |# b)\n");
+        assert_eq!(
+            format_str("(f #| x |# arg1 arg2)", 12).unwrap(),
+            "(f #| x |# arg1\n   arg2)\n"
+        );
+    }
+
+    #[test]
+    fn test_format_examples() {
+        for source in [
+            include_str!("../../../examples/ideas.callisto"),
+            include_str!("../../../examples/hello.callisto"),
+        ] {
+            let formatted = format(source);
+            assert_eq!(parse_str(&formatted), parse_str(source));
+            assert_eq!(format(&formatted), formatted);
+        }
+    }
+
+    #[test]
+    fn test_format_rejects_invalid_source() {
+        assert_eq!(format_str("(a", DEFAULT_WIDTH).unwrap_err().len(), 1);
+    }
+}
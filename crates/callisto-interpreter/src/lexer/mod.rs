@@ -1,85 +1,188 @@
 use logos::Logos;
 use thiserror::Error;
-use token::TokenKind;
+use token::{SpannedToken, TokenKind, block_comment_end};
 use token_stream::TokenStream;
 
+use crate::span::{LineIndex, Span};
+
 pub mod token;
 pub mod token_stream;
 
 #[derive(Debug, Clone, PartialEq, Error)]
 pub enum LexingError {
-    #[error("Invalid token: {0}")]
-    InvalidToken(String),
+    #[error("Invalid token: {lexeme}")]
+    InvalidToken { lexeme: String, span: Span },
+
+    #[error("Invalid escape sequence: {escape}")]
+    InvalidEscape { escape: String, span: Span },
+
+    #[error("Invalid number: {lexeme}")]
+    InvalidNumber { lexeme: String, span: Span },
+
+    #[error("Unterminated block comment")]
+    UnterminatedComment { span: Span },
+
+    #[error("Ambiguous sign: {lexeme}")]
+    AmbiguousSign { lexeme: String, span: Span },
 
     #[error("Unexpected end of input")]
-    EndOfInput,
+    EndOfInput { span: Span },
+}
+
+impl LexingError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexingError::InvalidToken { span, .. } => *span,
+            LexingError::InvalidEscape { span, .. } => *span,
+            LexingError::InvalidNumber { span, .. } => *span,
+            LexingError::UnterminatedComment { span } => *span,
+            LexingError::AmbiguousSign { span, .. } => *span,
+            LexingError::EndOfInput { span } => *span,
+        }
+    }
 }
 
 pub fn tokenize(input: &str) -> TokenStream {
     let mut tokens = Vec::new();
+    let lines = LineIndex::new(input);
     let lexer = TokenKind::lexer(input).spanned();
+    // The end of the previous token, if it was an atom that a sign could be read as
+    // continuing, as in `x-1`.
+    let mut atom_end = None;
 
-    for (kind, span) in lexer {
-        let lexeme = &input[span];
+    for (kind, range) in lexer {
+        let lexeme = &input[range.clone()];
+        let span = lines.span(range.clone());
+        if let Ok(kind) = kind
+            && kind.is_trivia()
+        {
+            atom_end = None;
+            if kind == TokenKind::BlockComment && block_comment_end(&lexeme[2..]).is_none() {
+                tokens.push(Err(LexingError::UnterminatedComment { span }));
+            }
+            continue;
+        }
+        let signed = lexeme.starts_with(['+', '-']);
+        let ambiguous = kind == Ok(TokenKind::Number) && signed && atom_end == Some(range.start);
+        atom_end = match kind {
+            Ok(
+                TokenKind::LeftParen
+                | TokenKind::LeftBracket
+                | TokenKind::LeftBrace
+                | TokenKind::DatumComment
+                | TokenKind::Quote
+                | TokenKind::Quasiquote
+                | TokenKind::Unquote
+                | TokenKind::UnquoteSplicing,
+            ) => None,
+            _ => Some(range.end),
+        };
+        if ambiguous {
+            tokens.push(Err(LexingError::AmbiguousSign {
+                lexeme: lexeme.to_string(),
+                span,
+            }));
+            continue;
+        }
         match kind {
-            Ok(token) => tokens.push(Ok(token.to_token(lexeme))),
-            Err(_) => tokens.push(Err(LexingError::InvalidToken(lexeme.to_string()))),
+            Ok(kind) => tokens.push(
+                kind.to_token(lexeme, span)
+                    .map(|token| SpannedToken::new(token, span)),
+            ),
+            Err(_) => tokens.push(Err(LexingError::InvalidToken {
+                lexeme: lexeme.to_string(),
+                span,
+            })),
         }
     }
 
-    TokenStream::new(tokens)
+    let eof = lines.span(input.len()..input.len());
+    TokenStream::new(tokens, eof)
+}
+
+/// A token exactly as it appears in the source, including whitespace and comments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawToken<'a> {
+    /// The kind of token, or `None` if the text is not a valid token.
+    pub kind: Option<TokenKind>,
+    pub text: &'a str,
+    pub span: Span,
+}
+
+/// Splits the input into tokens without discarding anything, so that concatenating their
+/// text reproduces the input exactly.
+///
+/// Unlike [`tokenize`], literals are not validated, so a malformed number is returned as a
+/// [`TokenKind::Number`] like any other.
+pub fn tokenize_lossless(input: &str) -> Vec<RawToken<'_>> {
+    let lines = LineIndex::new(input);
+    TokenKind::lexer(input)
+        .spanned()
+        .map(|(kind, range)| RawToken {
+            kind: kind.ok(),
+            text: &input[range.clone()],
+            span: lines.span(range),
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::{token::Token, *};
+    use crate::{
+        note::Note,
+        number::{Rational, Scalar},
+        quantity::{Quantity, Unit},
+    };
+
+    fn tokens(input: &str) -> Vec<Result<Token, LexingError>> {
+        tokenize(input)
+            .tokens
+            .into_iter()
+            .map(|token| token.map(|token| token.token))
+            .collect()
+    }
 
     #[test]
     fn test_tokenize_comment() {
         let input = r#";; this is a comment"#;
         let expected_tokens: Vec<Result<Token, LexingError>> = vec![];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
     fn test_tokenize_number() {
         let input = "42";
-        let expected_tokens = vec![Ok(Token::Number(42.0))];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        let expected_tokens = vec![Ok(Token::Integer(42))];
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
     fn test_tokenize_identifier() {
         let input = "x";
         let expected_tokens = vec![Ok(Token::Identifier("x".to_string()))];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
     fn test_tokenize_boolean() {
         let input = "true false";
         let expected_tokens = vec![Ok(Token::Boolean(true)), Ok(Token::Boolean(false))];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
     fn test_tokenize_symbol() {
         let input = ":symbol";
         let expected_tokens = vec![Ok(Token::Symbol("symbol".to_string()))];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
     fn test_tokenize_string() {
         let input = r#""hello world""#;
-        let expected_tokens = vec![Ok(Token::Symbol("hello world".to_string()))];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        let expected_tokens = vec![Ok(Token::String("hello world".to_string()))];
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
@@ -91,16 +194,14 @@ mod tests {
             Ok(Token::Operator("*".to_string())),
             Ok(Token::Operator("/".to_string())),
         ];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
     fn test_tokenize_parentheses() {
         let input = "( )";
         let expected_tokens = vec![Ok(Token::LeftParen), Ok(Token::RightParen)];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
     }
 
     #[test]
@@ -110,10 +211,501 @@ mod tests {
             Ok(Token::LeftParen),
             Ok(Token::Identifier("define".to_string())),
             Ok(Token::Identifier("x".to_string())),
-            Ok(Token::Number(42.0)),
+            Ok(Token::Integer(42)),
             Ok(Token::RightParen),
         ];
-        let token_stream = tokenize(input);
-        assert_eq!(token_stream.tokens, expected_tokens);
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_spans() {
+        let input = "(define x\n  42)";
+        let spans: Vec<Span> = tokenize(input)
+            .tokens
+            .into_iter()
+            .map(|token| token.unwrap().span)
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(0, 1, 1, 1),
+                Span::new(1, 7, 1, 2),
+                Span::new(8, 9, 1, 9),
+                Span::new(12, 14, 2, 3),
+                Span::new(14, 15, 2, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_invalid_token_span() {
+        let input = "(x\n  $)";
+        let errors: Vec<LexingError> = tokenize(input)
+            .tokens
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![LexingError::InvalidToken {
+                lexeme: "$".to_string(),
+                span: Span::new(5, 6, 2, 3),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_string_is_not_symbol() {
+        let input = r#":reverb "reverb""#;
+        let expected_tokens = vec![
+            Ok(Token::Symbol("reverb".to_string())),
+            Ok(Token::String("reverb".to_string())),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_string_escapes() {
+        let input = r#""a\nb\tc\"d\\e\u{1F3B5}\u{e9}""#;
+        let expected_tokens = vec![Ok(Token::String(
+            "a\nb\tc\"d\\e\u{1F3B5}\u{e9}".to_string(),
+        ))];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_empty_string() {
+        let input = r#""""#;
+        let expected_tokens = vec![Ok(Token::String(String::new()))];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_invalid_escape() {
+        let input = r#"(print "bad \q escape")"#;
+        let expected_tokens = vec![
+            Ok(Token::LeftParen),
+            Ok(Token::Identifier("print".to_string())),
+            Err(LexingError::InvalidEscape {
+                escape: r"\q".to_string(),
+                span: Span::new(12, 14, 1, 13),
+            }),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_invalid_unicode_escape() {
+        for (input, escape) in [
+            (r#""\u{110000}""#, r"\u{110000}"),
+            (r#""\u{zz}""#, r"\u{"),
+            (r#""\u{41""#, r"\u{41"),
+            (r#""\u41""#, r"\u"),
+        ] {
+            let errors: Vec<LexingError> =
+                tokens(input).into_iter().filter_map(Result::err).collect();
+            assert!(
+                matches!(&errors[..], [LexingError::InvalidEscape { escape: found, .. }] if found == escape),
+                "{input}: {errors:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tokenize_invalid_escape_multiline_span() {
+        let input = "\"first\nsecond \\x\"";
+        let errors: Vec<LexingError> = tokens(input).into_iter().filter_map(Result::err).collect();
+        assert_eq!(
+            errors,
+            vec![LexingError::InvalidEscape {
+                escape: r"\x".to_string(),
+                span: Span::new(14, 16, 2, 8),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_patch_operators() {
+        let input = "(~ dac mix) (>> a b)";
+        let expected_tokens = vec![
+            Ok(Token::LeftParen),
+            Ok(Token::Operator("~".to_string())),
+            Ok(Token::Identifier("dac".to_string())),
+            Ok(Token::Identifier("mix".to_string())),
+            Ok(Token::RightParen),
+            Ok(Token::LeftParen),
+            Ok(Token::Operator(">>".to_string())),
+            Ok(Token::Identifier("a".to_string())),
+            Ok(Token::Identifier("b".to_string())),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_slash_comment() {
+        let input = "// comment\nx // trailing comment\n//";
+        let expected_tokens = vec![Ok(Token::Identifier("x".to_string()))];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_comment_after_number() {
+        for input in ["42// note", "42;; note", "42#| note |#"] {
+            assert_eq!(tokens(input), vec![Ok(Token::Integer(42))], "{input}");
+        }
+        assert_eq!(
+            tokens("1/4//note"),
+            vec![Ok(Token::Rational(Rational::new(1, 4).unwrap()))]
+        );
+        assert_eq!(
+            tokens("4'x"),
+            vec![
+                Ok(Token::Integer(4)),
+                Ok(Token::Quote),
+                Ok(Token::Identifier("x".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_division_is_not_comment() {
+        let input = "(/ 4 2)";
+        let expected_tokens = vec![
+            Ok(Token::LeftParen),
+            Ok(Token::Operator("/".to_string())),
+            Ok(Token::Integer(4)),
+            Ok(Token::Integer(2)),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_block_comment() {
+        let input = "a #| one\ntwo |# b";
+        let expected_tokens = vec![
+            Ok(Token::Identifier("a".to_string())),
+            Ok(Token::Identifier("b".to_string())),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_nested_block_comment() {
+        let input = "a #| outer #| inner |# still outer |# b";
+        let expected_tokens = vec![
+            Ok(Token::Identifier("a".to_string())),
+            Ok(Token::Identifier("b".to_string())),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_unterminated_block_comment() {
+        let input = "a #| outer #| inner |# b";
+        let expected_tokens = vec![
+            Ok(Token::Identifier("a".to_string())),
+            Err(LexingError::UnterminatedComment {
+                span: Span::new(2, 24, 1, 3),
+            }),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_datum_comment() {
+        let input = "#_(x) y";
+        let expected_tokens = vec![
+            Ok(Token::DatumComment),
+            Ok(Token::LeftParen),
+            Ok(Token::Identifier("x".to_string())),
+            Ok(Token::RightParen),
+            Ok(Token::Identifier("y".to_string())),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_ideas_example() {
+        let input = include_str!("../../../../examples/ideas.callisto");
+        let errors: Vec<LexingError> = tokens(input).into_iter().filter_map(Result::err).collect();
+        assert_eq!(errors, vec![]);
+    }
+
+    #[test]
+    fn test_tokenize_notes() {
+        let input = ":C4 :C#4 :Bb-1 :F##3 :beat :maj7 :C10";
+        let expected_tokens = vec![
+            Ok(Token::Note(Note::new('C', 0, 4).unwrap())),
+            Ok(Token::Note(Note::new('C', 1, 4).unwrap())),
+            Ok(Token::Note(Note::new('B', -1, -1).unwrap())),
+            Ok(Token::Note(Note::new('F', 2, 3).unwrap())),
+            Ok(Token::Symbol("beat".to_string())),
+            Ok(Token::Symbol("maj7".to_string())),
+            Ok(Token::Symbol("C10".to_string())),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_rational() {
+        let input = "3/4 -1/16 4/2 (/ 3 4)";
+        let expected_tokens = vec![
+            Ok(Token::Rational(Rational::new(3, 4).unwrap())),
+            Ok(Token::Rational(Rational::new(-1, 16).unwrap())),
+            Ok(Token::Rational(Rational::new(2, 1).unwrap())),
+            Ok(Token::LeftParen),
+            Ok(Token::Operator("/".to_string())),
+            Ok(Token::Integer(3)),
+            Ok(Token::Integer(4)),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_zero_denominator() {
+        let input = "1/0";
+        let expected_tokens = vec![Err(LexingError::InvalidNumber {
+            lexeme: "1/0".to_string(),
+            span: Span::new(0, 3, 1, 1),
+        })];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_quantities() {
+        let exact = |numer, denom| Scalar::Exact(Rational::new(numer, denom).unwrap());
+        let input = "1.5b 1/16b 2bars 1bar 250ms 0.1s 440hz 440Hz";
+        let expected_tokens = vec![
+            Ok(Token::Quantity(Quantity::new(exact(3, 2), Unit::Beats))),
+            Ok(Token::Quantity(Quantity::new(exact(1, 16), Unit::Beats))),
+            Ok(Token::Quantity(Quantity::new(exact(2, 1), Unit::Bars))),
+            Ok(Token::Quantity(Quantity::new(exact(1, 1), Unit::Bars))),
+            Ok(Token::Quantity(Quantity::new(
+                exact(250, 1),
+                Unit::Milliseconds,
+            ))),
+            Ok(Token::Quantity(Quantity::new(exact(1, 10), Unit::Seconds))),
+            Ok(Token::Quantity(Quantity::new(exact(440, 1), Unit::Hertz))),
+            Ok(Token::Quantity(Quantity::new(exact(440, 1), Unit::Hertz))),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_integers() {
+        let input = "0 +5 -5 1_000 0x7F 0XfF -0x10 9223372036854775807 -9223372036854775808";
+        let expected_tokens = vec![
+            Ok(Token::Integer(0)),
+            Ok(Token::Integer(5)),
+            Ok(Token::Integer(-5)),
+            Ok(Token::Integer(1000)),
+            Ok(Token::Integer(127)),
+            Ok(Token::Integer(255)),
+            Ok(Token::Integer(-16)),
+            Ok(Token::Integer(i64::MAX)),
+            Ok(Token::Integer(i64::MIN)),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_floats() {
+        let input = "1.5 .5 -.5 +.25 5. 1e-3 1E3 2.5e+2 1_000.000_1 0.0";
+        let expected_tokens = vec![
+            Ok(Token::Float(1.5)),
+            Ok(Token::Float(0.5)),
+            Ok(Token::Float(-0.5)),
+            Ok(Token::Float(0.25)),
+            Ok(Token::Float(5.0)),
+            Ok(Token::Float(0.001)),
+            Ok(Token::Float(1000.0)),
+            Ok(Token::Float(250.0)),
+            Ok(Token::Float(1000.0001)),
+            Ok(Token::Float(0.0)),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_exponent_quantities() {
+        let exact = |numer, denom| Scalar::Exact(Rational::new(numer, denom).unwrap());
+        let input = "1e3ms -.5b 2.5e-1s";
+        let expected_tokens = vec![
+            Ok(Token::Quantity(Quantity::new(
+                exact(1000, 1),
+                Unit::Milliseconds,
+            ))),
+            Ok(Token::Quantity(Quantity::new(exact(-1, 2), Unit::Beats))),
+            Ok(Token::Quantity(Quantity::new(exact(1, 4), Unit::Seconds))),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_malformed_numbers() {
+        for lexeme in [
+            "1__0",
+            "1_",
+            "1._5",
+            "1e",
+            "1e+",
+            "1.2.3",
+            "0x",
+            "0x1G",
+            "0x10ms",
+            "5-3",
+            "1/2.5",
+            "1.5/2",
+            "3xyz",
+            "9223372036854775808",
+            "1e999",
+        ] {
+            let expected_tokens = vec![Err(LexingError::InvalidNumber {
+                lexeme: lexeme.to_string(),
+                span: Span::new(0, lexeme.len(), 1, 1),
+            })];
+            assert_eq!(tokens(lexeme), expected_tokens, "{lexeme}");
+        }
+    }
+
+    #[test]
+    fn test_tokenize_underscore_prefix_is_identifier() {
+        let input = "_1";
+        let expected_tokens = vec![Ok(Token::Identifier("_1".to_string()))];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_signed_operand() {
+        let input = "(- 5 -3)";
+        let expected_tokens = vec![
+            Ok(Token::LeftParen),
+            Ok(Token::Operator("-".to_string())),
+            Ok(Token::Integer(5)),
+            Ok(Token::Integer(-3)),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+
+        let input = "(-5)";
+        let expected_tokens = vec![
+            Ok(Token::LeftParen),
+            Ok(Token::Integer(-5)),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_minus_before_paren_or_identifier() {
+        let input = "(- x) -(x) -x (-x)";
+        let expected_tokens = vec![
+            Ok(Token::LeftParen),
+            Ok(Token::Operator("-".to_string())),
+            Ok(Token::Identifier("x".to_string())),
+            Ok(Token::RightParen),
+            Ok(Token::Operator("-".to_string())),
+            Ok(Token::LeftParen),
+            Ok(Token::Identifier("x".to_string())),
+            Ok(Token::RightParen),
+            Ok(Token::Operator("-".to_string())),
+            Ok(Token::Identifier("x".to_string())),
+            Ok(Token::LeftParen),
+            Ok(Token::Operator("-".to_string())),
+            Ok(Token::Identifier("x".to_string())),
+            Ok(Token::RightParen),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_ambiguous_sign() {
+        let input = "(x-1 (f)+2 :a-1)";
+        let errors: Vec<LexingError> = tokenize(input)
+            .tokens
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                LexingError::AmbiguousSign {
+                    lexeme: "-1".to_string(),
+                    span: Span::new(2, 4, 1, 3),
+                },
+                LexingError::AmbiguousSign {
+                    lexeme: "+2".to_string(),
+                    span: Span::new(8, 10, 1, 9),
+                },
+                LexingError::AmbiguousSign {
+                    lexeme: "-1".to_string(),
+                    span: Span::new(13, 15, 1, 14),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_quote_prefixes() {
+        let input = "'x `(a ,b ,@c) '-1";
+        let expected_tokens = vec![
+            Ok(Token::Quote),
+            Ok(Token::Identifier("x".to_string())),
+            Ok(Token::Quasiquote),
+            Ok(Token::LeftParen),
+            Ok(Token::Identifier("a".to_string())),
+            Ok(Token::Unquote),
+            Ok(Token::Identifier("b".to_string())),
+            Ok(Token::UnquoteSplicing),
+            Ok(Token::Identifier("c".to_string())),
+            Ok(Token::RightParen),
+            Ok(Token::Quote),
+            Ok(Token::Integer(-1)),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_brackets_and_braces() {
+        let input = "[1 -2]{:a 3}";
+        let expected_tokens = vec![
+            Ok(Token::LeftBracket),
+            Ok(Token::Integer(1)),
+            Ok(Token::Integer(-2)),
+            Ok(Token::RightBracket),
+            Ok(Token::LeftBrace),
+            Ok(Token::Symbol("a".to_string())),
+            Ok(Token::Integer(3)),
+            Ok(Token::RightBrace),
+        ];
+        assert_eq!(tokens(input), expected_tokens);
+    }
+
+    #[test]
+    fn test_tokenize_lossless_keeps_trivia() {
+        let input = "(a ;; note\n #| x |# $)";
+        let tokens = tokenize_lossless(input);
+        let kinds: Vec<_> = tokens.iter().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Some(TokenKind::LeftParen),
+                Some(TokenKind::Identifier),
+                Some(TokenKind::Whitespace),
+                Some(TokenKind::Comment),
+                Some(TokenKind::Whitespace),
+                Some(TokenKind::BlockComment),
+                Some(TokenKind::Whitespace),
+                None,
+                Some(TokenKind::RightParen),
+            ]
+        );
+        let text: String = tokens.iter().map(|token| token.text).collect();
+        assert_eq!(text, input);
+        assert_eq!(tokens[3].span, Span::new(3, 10, 1, 4));
     }
 }
@@ -1,51 +1,384 @@
-use logos::Logos;
+use logos::{Lexer, Logos};
+
+use crate::{
+    note::Note,
+    number::{Rational, Scalar},
+    quantity::{Quantity, Unit},
+    span::Span,
+};
+
+use super::LexingError;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Identifier(String),
     Symbol(String),
-    Number(f64),
+    Note(Note),
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Rational(Rational),
+    Quantity(Quantity),
     Boolean(bool),
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
     Operator(String),
+    /// `#_`, which comments out the form that follows it.
+    DatumComment,
+    /// `'`, read as `(quote form)`.
+    Quote,
+    /// `` ` ``, read as `(quasiquote form)`.
+    Quasiquote,
+    /// `,`, read as `(unquote form)`.
+    Unquote,
+    /// `,@`, read as `(unquote_splicing form)`.
+    UnquoteSplicing,
 }
 
-#[derive(Logos, Debug, Clone, PartialEq)]
-#[logos(skip r"[ \t\n\r]+")]
+/// A [`Token`] together with the region of source it was lexed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl SpannedToken {
+    pub fn new(token: Token, span: Span) -> Self {
+        Self { token, span }
+    }
+}
+
+#[derive(Logos, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenKind {
-    #[regex(r";;[^\n]*", logos::skip)]
+    #[regex(r"[ \t\n\r]+")]
+    Whitespace,
+    /// A `;;` or `//` line comment, not including the newline that ends it.
+    #[regex(r";;[^\n]*")]
+    #[regex(r"//[^\n]*", priority = 10)]
     Comment,
+    /// A nested `#| ... |#` block comment. If it is never closed, it runs to the end of the
+    /// input.
+    #[token("#|", block_comment)]
+    BlockComment,
+    #[token("#_")]
+    DatumComment,
+    #[token("'")]
+    Quote,
+    #[token("`")]
+    Quasiquote,
+    #[token(",")]
+    Unquote,
+    #[token(",@")]
+    UnquoteSplicing,
     #[regex(r"\(")]
     LeftParen,
     #[regex(r"\)")]
     RightParen,
+    #[token("[")]
+    LeftBracket,
+    #[token("]")]
+    RightBracket,
+    #[token("{")]
+    LeftBrace,
+    #[token("}")]
+    RightBrace,
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*")]
     Identifier,
     #[regex(r":[a-zA-Z_][a-zA-Z0-9_]*")]
     Symbol,
+    #[regex(r":[A-G](##|#|bb|b)?-?[0-9]", priority = 10)]
+    Note,
     #[regex(r#""([^"\\]|\\.)*""#)]
     StringLiteral,
-    #[regex(r"-?\d+(\.\d+)?")]
+    /// Anything that starts like a number, up to the next delimiter, comment or quote. The
+    /// lexeme is validated by [`parse_number`], so malformed numbers such as `1.2.3` or
+    /// `5-3` are reported as a single invalid token.
+    #[regex(r#"[+\-]?\.?[0-9]([^\s()\[\]{}";,/#|']|/[^\s()\[\]{}";,/#|'])*"#)]
     Number,
     #[regex(r"true|false")]
     Boolean,
-    #[regex(r"[+\-*/=<>!&|]+")]
+    #[regex(r"[+\-*/=<>!&|~]+")]
     Operator,
 }
 
 impl TokenKind {
-    pub fn to_token(self, lexeme: &str) -> Token {
-        match self {
-            TokenKind::Comment => unreachable!(),
+    /// Returns true for whitespace and comments, which the parser never sees.
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::Whitespace | TokenKind::Comment | TokenKind::BlockComment
+        )
+    }
+
+    pub fn to_token(self, lexeme: &str, span: Span) -> Result<Token, LexingError> {
+        let token = match self {
+            TokenKind::Whitespace | TokenKind::Comment => unreachable!(),
+            TokenKind::BlockComment => return Err(LexingError::UnterminatedComment { span }),
+            TokenKind::DatumComment => Token::DatumComment,
+            TokenKind::Quote => Token::Quote,
+            TokenKind::Quasiquote => Token::Quasiquote,
+            TokenKind::Unquote => Token::Unquote,
+            TokenKind::UnquoteSplicing => Token::UnquoteSplicing,
             TokenKind::LeftParen => Token::LeftParen,
             TokenKind::RightParen => Token::RightParen,
+            TokenKind::LeftBracket => Token::LeftBracket,
+            TokenKind::RightBracket => Token::RightBracket,
+            TokenKind::LeftBrace => Token::LeftBrace,
+            TokenKind::RightBrace => Token::RightBrace,
             TokenKind::Identifier => Token::Identifier(lexeme.to_string()),
             TokenKind::Symbol => Token::Symbol(lexeme[1..].to_string()),
-            TokenKind::StringLiteral => Token::Symbol(lexeme[1..lexeme.len() - 1].to_string()),
-            TokenKind::Number => Token::Number(lexeme.parse().unwrap()),
+            TokenKind::Note => Token::Note(lexeme[1..].parse().unwrap()),
+            TokenKind::StringLiteral => Token::String(unescape(lexeme, span)?),
+            TokenKind::Number => {
+                parse_number(lexeme).ok_or_else(|| invalid_number(lexeme, span))?
+            }
             TokenKind::Boolean => Token::Boolean(lexeme == "true"),
             TokenKind::Operator => Token::Operator(lexeme.to_string()),
+        };
+        Ok(token)
+    }
+}
+
+/// Parses a numeric literal, returning `None` if it does not follow the grammar:
+///
+/// ```text
+/// number   = sign? (hex | decimal | ratio) unit?
+/// sign     = "+" | "-"
+/// hex      = ("0x" | "0X") hex-digits
+/// decimal  = (digits ("." digits?)? | "." digits) exponent?
+/// exponent = ("e" | "E") sign? digits
+/// ratio    = digits "/" digits
+/// unit     = "b" | "bar" | "bars" | "ms" | "s" | "hz" | "Hz"
+/// ```
+///
+/// Digits may be separated by underscores (`1_000`). Hex literals cannot have a unit, since
+/// `b` is a hex digit. Decimals without a fraction or exponent are integers, and decimals
+/// with a unit are kept exact where possible.
+fn parse_number(lexeme: &str) -> Option<Token> {
+    let (negative, rest) = match lexeme.as_bytes().first() {
+        Some(b'-') => (true, &lexeme[1..]),
+        Some(b'+') => (false, &lexeme[1..]),
+        _ => (false, lexeme),
+    };
+    let sign = if negative { -1 } else { 1 };
+
+    if let Some(hex) = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+        let value = sign as i128 * i128::from_str_radix(&digits(hex, 16)?, 16).ok()?;
+        return Some(Token::Integer(value.try_into().ok()?));
+    }
+
+    let number = rest.trim_end_matches(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E');
+    let number = match number.strip_suffix(['e', 'E']) {
+        // a trailing `e` is never a valid exponent, so it must be part of the unit
+        Some(number) if !number.is_empty() => number,
+        _ => number,
+    };
+    let unit = match &rest[number.len()..] {
+        "" => None,
+        suffix => Some(Unit::from_suffix(suffix)?),
+    };
+
+    let amount = match number.split_once('/') {
+        Some((numer, denom)) => {
+            let numer: i64 = digits(numer, 10)?.parse().ok()?;
+            let denom: i64 = digits(denom, 10)?.parse().ok()?;
+            Scalar::Exact(Rational::new(sign * numer, denom)?)
+        }
+        None => {
+            let decimal = Decimal::parse(number)?;
+            if unit.is_none() {
+                return if decimal.integral {
+                    let value = sign as i128 * decimal.mantissa.parse::<i128>().ok()?;
+                    Some(Token::Integer(value.try_into().ok()?))
+                } else {
+                    Some(Token::Float(sign as f64 * decimal.to_f64()?))
+                };
+            }
+            match decimal.to_rational() {
+                Some(r) => Scalar::Exact(r.checked_mul(Rational::from_integer(sign))?),
+                None => Scalar::Float(sign as f64 * decimal.to_f64()?),
+            }
         }
+    };
+
+    match (unit, amount) {
+        (Some(unit), amount) => Some(Token::Quantity(Quantity::new(amount, unit))),
+        (None, Scalar::Exact(r)) => Some(Token::Rational(r)),
+        (None, Scalar::Float(f)) => Some(Token::Float(f)),
     }
 }
+
+/// Strips the underscores from a run of digits, returning `None` if it is empty, contains
+/// anything other than digits and underscores, or has an underscore that is not between
+/// two digits.
+fn digits(s: &str, radix: u32) -> Option<String> {
+    let valid = !s.is_empty()
+        && !s.starts_with('_')
+        && !s.ends_with('_')
+        && !s.contains("__")
+        && s.chars().all(|c| c == '_' || c.is_digit(radix));
+    valid.then(|| s.replace('_', ""))
+}
+
+/// An unsigned decimal literal, whose value is `mantissa * 10^exponent`.
+struct Decimal {
+    mantissa: String,
+    exponent: i32,
+    /// True if the literal had neither a fraction nor an exponent.
+    integral: bool,
+}
+
+impl Decimal {
+    fn parse(s: &str) -> Option<Decimal> {
+        let (s, exponent) = match s.split_once(['e', 'E']) {
+            Some((s, exponent)) => {
+                let (negative, exponent) = match exponent.as_bytes().first() {
+                    Some(b'-') => (true, &exponent[1..]),
+                    Some(b'+') => (false, &exponent[1..]),
+                    _ => (false, exponent),
+                };
+                let exponent: i32 = digits(exponent, 10)?.parse().ok()?;
+                (s, Some(if negative { -exponent } else { exponent }))
+            }
+            None => (s, None),
+        };
+        let (int, frac) = match s.split_once('.') {
+            Some(("", frac)) => (String::new(), digits(frac, 10)?),
+            Some((int, "")) => (digits(int, 10)?, String::new()),
+            Some((int, frac)) => (digits(int, 10)?, digits(frac, 10)?),
+            None => (digits(s, 10)?, String::new()),
+        };
+        Some(Decimal {
+            exponent: exponent.unwrap_or(0).checked_sub(frac.len() as i32)?,
+            integral: !s.contains('.') && exponent.is_none(),
+            mantissa: int + &frac,
+        })
+    }
+
+    /// Returns `None` if the literal is too large to be represented.
+    fn to_f64(&self) -> Option<f64> {
+        let value: f64 = format!("{}e{}", self.mantissa, self.exponent)
+            .parse()
+            .ok()?;
+        value.is_finite().then_some(value)
+    }
+
+    fn to_rational(&self) -> Option<Rational> {
+        let mantissa: i64 = self.mantissa.parse().ok()?;
+        let scale = 10i64.checked_pow(self.exponent.unsigned_abs())?;
+        if self.exponent >= 0 {
+            Some(Rational::from_integer(mantissa.checked_mul(scale)?))
+        } else {
+            Rational::new(mantissa, scale)
+        }
+    }
+}
+
+fn invalid_number(lexeme: &str, span: Span) -> LexingError {
+    LexingError::InvalidNumber {
+        lexeme: lexeme.to_string(),
+        span,
+    }
+}
+
+/// Extends a block comment to its closing `|#`, including any comments nested inside it.
+fn block_comment(lex: &mut Lexer<TokenKind>) {
+    let remainder = lex.remainder();
+    lex.bump(block_comment_end(remainder).unwrap_or(remainder.len()));
+}
+
+/// Returns the length of the rest of a block comment whose opening `#|` has already been
+/// read, up to and including its closing `|#`, or `None` if it is never closed.
+pub(super) fn block_comment_end(rest: &str) -> Option<usize> {
+    let mut depth = 1;
+    let mut i = 0;
+    while i < rest.len() {
+        match &rest.as_bytes()[i..] {
+            [b'#', b'|', ..] => {
+                depth += 1;
+                i += 2;
+            }
+            [b'|', b'#', ..] => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Resolves the escape sequences in a quoted string literal.
+fn unescape(lexeme: &str, span: Span) -> Result<String, LexingError> {
+    let body = &lexeme[1..lexeme.len() - 1];
+    let mut result = String::with_capacity(body.len());
+    let mut chars = body.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some((_, 'n')) => Some('\n'),
+            Some((_, 'r')) => Some('\r'),
+            Some((_, 't')) => Some('\t'),
+            Some((_, '"')) => Some('"'),
+            Some((_, '\\')) => Some('\\'),
+            Some((_, 'u')) if chars.next_if(|(_, c)| *c == '{').is_some() => {
+                let mut digits = String::new();
+                let mut closed = false;
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit() || *c == '}')
+                {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    digits.push(c);
+                }
+                if closed && digits.len() <= 6 {
+                    u32::from_str_radix(&digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        match escaped {
+            Some(c) => result.push(c),
+            None => {
+                let end = chars.peek().map_or(body.len(), |(i, _)| *i);
+                // offsets into the lexeme, skipping the opening quote
+                let (start, end) = (start + 1, end + 1);
+                return Err(LexingError::InvalidEscape {
+                    escape: lexeme[start..end].to_string(),
+                    span: sub_span(span, lexeme, start, end),
+                });
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns the span of `lexeme[start..end]`, given the span of the whole lexeme.
+fn sub_span(span: Span, lexeme: &str, start: usize, end: usize) -> Span {
+    let before = &lexeme[..start];
+    let (line, column) = match before.rfind('\n') {
+        Some(newline) => (
+            span.line + before.matches('\n').count(),
+            before[newline + 1..].chars().count() + 1,
+        ),
+        None => (span.line, span.column + before.chars().count()),
+    };
+    Span::new(span.start + start, span.start + end, line, column)
+}
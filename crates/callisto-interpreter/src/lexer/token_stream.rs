@@ -1,41 +1,54 @@
-use super::{LexingError, token::Token};
+use crate::span::Span;
+
+use super::{LexingError, token::SpannedToken};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TokenStream {
-    pub(crate) tokens: Vec<Result<Token, LexingError>>,
+    pub(crate) tokens: Vec<Result<SpannedToken, LexingError>>,
     current: usize,
+    eof: Span,
 }
 
 impl TokenStream {
-    pub fn new(tokens: Vec<Result<Token, LexingError>>) -> Self {
-        TokenStream { tokens, current: 0 }
+    pub fn new(tokens: Vec<Result<SpannedToken, LexingError>>, eof: Span) -> Self {
+        TokenStream {
+            tokens,
+            current: 0,
+            eof,
+        }
     }
 
-    pub fn bump(&mut self) -> Result<Token, LexingError> {
+    pub fn bump(&mut self) -> Result<SpannedToken, LexingError> {
         if self.current < self.tokens.len() {
             let token = self.tokens[self.current].clone();
             self.current += 1;
             token
         } else {
-            Err(LexingError::EndOfInput)
+            Err(LexingError::EndOfInput { span: self.eof })
         }
     }
 
-    pub fn peek(&self) -> Option<&Result<Token, LexingError>> {
-        if self.current < self.tokens.len() {
-            Some(&self.tokens[self.current])
-        } else {
-            None
-        }
+    pub fn peek(&self) -> Option<&Result<SpannedToken, LexingError>> {
+        self.peek_nth(0)
+    }
+
+    /// Looks `n` tokens past the next one without consuming anything.
+    pub fn peek_nth(&self, n: usize) -> Option<&Result<SpannedToken, LexingError>> {
+        self.tokens.get(self.current + n)
     }
 
     pub fn is_empty(&self) -> bool {
         self.current >= self.tokens.len()
     }
+
+    /// The (empty) span just past the end of the input.
+    pub fn eof(&self) -> Span {
+        self.eof
+    }
 }
 
 impl Iterator for TokenStream {
-    type Item = Result<Token, LexingError>;
+    type Item = Result<SpannedToken, LexingError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.is_empty() {
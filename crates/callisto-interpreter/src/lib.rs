@@ -1,3 +1,9 @@
+pub mod diagnostic;
+pub mod format;
 pub mod lexer;
+pub mod note;
+pub mod number;
 pub mod parser;
+pub mod quantity;
+pub mod span;
 pub mod vm;
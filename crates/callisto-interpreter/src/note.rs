@@ -0,0 +1,228 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// A pitch with a spelled name and octave, e.g. `C#4` or `Bb-1`.
+///
+/// Octaves follow scientific pitch notation, so `C4` is middle C (MIDI 60) and `A4` is 440 Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Note {
+    letter: char,
+    accidental: i8,
+    octave: i8,
+}
+
+const SHARP_NAMES: [(char, i8); 12] = [
+    ('C', 0),
+    ('C', 1),
+    ('D', 0),
+    ('D', 1),
+    ('E', 0),
+    ('F', 0),
+    ('F', 1),
+    ('G', 0),
+    ('G', 1),
+    ('A', 0),
+    ('A', 1),
+    ('B', 0),
+];
+
+impl Note {
+    /// Creates a note from its letter (`A`-`G`), accidental (sharps are positive, flats
+    /// negative) and octave. Returns `None` if the letter is not a note name.
+    pub fn new(letter: char, accidental: i8, octave: i8) -> Option<Self> {
+        let letter = letter.to_ascii_uppercase();
+        letter_semitone(letter)?;
+        Some(Self {
+            letter,
+            accidental,
+            octave,
+        })
+    }
+
+    /// Returns the note with the given MIDI number, spelled with sharps. Returns `None` if
+    /// its octave is out of range.
+    pub fn from_midi(midi: i32) -> Option<Self> {
+        let (letter, accidental) = SHARP_NAMES[midi.rem_euclid(12) as usize];
+        Some(Self {
+            letter,
+            accidental,
+            octave: i8::try_from(midi.div_euclid(12) - 1).ok()?,
+        })
+    }
+
+    pub fn letter(&self) -> char {
+        self.letter
+    }
+
+    pub fn accidental(&self) -> i8 {
+        self.accidental
+    }
+
+    pub fn octave(&self) -> i8 {
+        self.octave
+    }
+
+    /// The pitch class of the note, from 0 (C) to 11 (B).
+    pub fn pitch_class(&self) -> u8 {
+        self.midi().rem_euclid(12) as u8
+    }
+
+    pub fn midi(&self) -> i32 {
+        let semitone = letter_semitone(self.letter).unwrap_or_default();
+        (self.octave as i32 + 1) * 12 + semitone + self.accidental as i32
+    }
+
+    /// The frequency of the note in Hz, in twelve-tone equal temperament with A4 = 440 Hz.
+    pub fn frequency(&self) -> f64 {
+        440.0 * 2f64.powf((self.midi() - 69) as f64 / 12.0)
+    }
+
+    /// Returns the note the given number of semitones higher, or `None` if it is out of
+    /// range.
+    pub fn transpose(&self, semitones: i32) -> Option<Self> {
+        Self::from_midi(self.midi().checked_add(semitones)?)
+    }
+}
+
+fn letter_semitone(letter: char) -> Option<i32> {
+    match letter {
+        'C' => Some(0),
+        'D' => Some(2),
+        'E' => Some(4),
+        'F' => Some(5),
+        'G' => Some(7),
+        'A' => Some(9),
+        'B' => Some(11),
+        _ => None,
+    }
+}
+
+impl FromStr for Note {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let letter = chars.next().ok_or(())?;
+        let rest = chars.as_str();
+        let octave_start = rest.find(['-', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9']);
+        let (accidentals, octave) = rest.split_at(octave_start.ok_or(())?);
+        let accidental = match accidentals {
+            "" => 0,
+            "#" => 1,
+            "##" => 2,
+            "b" => -1,
+            "bb" => -2,
+            _ => return Err(()),
+        };
+        let octave = octave.parse().map_err(|_| ())?;
+        Note::new(letter, accidental, octave).ok_or(())
+    }
+}
+
+/// Orders notes by pitch, and notes of the same pitch by how they are spelled, so that
+/// the order agrees with equality, under which `C#4` and `Db4` differ.
+impl Ord for Note {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.midi(), self.letter, self.accidental).cmp(&(
+            other.midi(),
+            other.letter,
+            other.accidental,
+        ))
+    }
+}
+
+impl PartialOrd for Note {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.letter)?;
+        let accidental = if self.accidental < 0 { "b" } else { "#" };
+        for _ in 0..self.accidental.unsigned_abs() {
+            write!(f, "{accidental}")?;
+        }
+        write!(f, "{}", self.octave)
+    }
+}
+
+/// Returns the intervals, in semitones above the root, of a chord quality such as `maj7`.
+pub fn chord_intervals(quality: &str) -> Option<&'static [i32]> {
+    let intervals: &'static [i32] = match quality {
+        "maj" => &[0, 4, 7],
+        "min" => &[0, 3, 7],
+        "dim" => &[0, 3, 6],
+        "aug" => &[0, 4, 8],
+        "sus2" => &[0, 2, 7],
+        "sus4" => &[0, 5, 7],
+        "maj7" => &[0, 4, 7, 11],
+        "min7" => &[0, 3, 7, 10],
+        "dom7" => &[0, 4, 7, 10],
+        "dim7" => &[0, 3, 6, 9],
+        _ => return None,
+    };
+    Some(intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_note() {
+        let note: Note = "C#4".parse().unwrap();
+        assert_eq!(note.letter(), 'C');
+        assert_eq!(note.accidental(), 1);
+        assert_eq!(note.octave(), 4);
+        assert_eq!(note.midi(), 61);
+        assert_eq!(note.pitch_class(), 1);
+
+        let note: Note = "Bb-1".parse().unwrap();
+        assert_eq!(note.octave(), -1);
+        assert_eq!(note.midi(), 10);
+        assert_eq!(note.pitch_class(), 10);
+
+        assert!("H4".parse::<Note>().is_err());
+        assert!("C".parse::<Note>().is_err());
+        assert!("Cx4".parse::<Note>().is_err());
+    }
+
+    #[test]
+    fn test_note_octave_boundaries() {
+        let note: Note = "Cb4".parse().unwrap();
+        assert_eq!(note.midi(), 59);
+        assert_eq!(note.pitch_class(), 11);
+        let note: Note = "B#3".parse().unwrap();
+        assert_eq!(note.midi(), 60);
+        assert_eq!(note.pitch_class(), 0);
+    }
+
+    #[test]
+    fn test_note_frequency() {
+        let a4: Note = "A4".parse().unwrap();
+        assert_eq!(a4.frequency(), 440.0);
+        let a3: Note = "A3".parse().unwrap();
+        assert_eq!(a3.frequency(), 220.0);
+    }
+
+    #[test]
+    fn test_note_from_midi() {
+        assert_eq!(Note::from_midi(60).unwrap().to_string(), "C4");
+        assert_eq!(Note::from_midi(61).unwrap().to_string(), "C#4");
+        assert_eq!(Note::from_midi(0).unwrap().to_string(), "C-1");
+        assert_eq!("Bb2".parse::<Note>().unwrap().to_string(), "Bb2");
+        assert_eq!(Note::from_midi(-1524).unwrap().to_string(), "C-128");
+        assert_eq!(Note::from_midi(1547).unwrap().to_string(), "B127");
+        assert_eq!(Note::from_midi(-1525), None);
+        assert_eq!(Note::from_midi(1548), None);
+    }
+
+    #[test]
+    fn test_transpose_out_of_range() {
+        let c4: Note = "C4".parse().unwrap();
+        assert_eq!(c4.transpose(-12), "C3".parse().ok());
+        assert_eq!(c4.transpose(100_000), None);
+        assert_eq!(c4.transpose(i32::MAX), None);
+    }
+}
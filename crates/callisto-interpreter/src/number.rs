@@ -0,0 +1,273 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Mul, Sub},
+};
+
+/// An exact fraction, always stored in lowest terms with a positive denominator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rational {
+    numer: i64,
+    denom: i64,
+}
+
+impl Rational {
+    /// Creates a rational number, or returns `None` if `denom` is zero.
+    pub fn new(numer: i64, denom: i64) -> Option<Self> {
+        Self::reduce(numer as i128, denom as i128)
+    }
+
+    pub fn from_integer(n: i64) -> Self {
+        Self { numer: n, denom: 1 }
+    }
+
+    /// Converts an integral float, returning `None` if it has a fractional part or is too
+    /// large to be represented exactly.
+    pub fn from_integral(f: f64) -> Option<Self> {
+        const MAX_EXACT: f64 = (1u64 << 53) as f64;
+        (f.fract() == 0.0 && f.abs() <= MAX_EXACT).then(|| Self::from_integer(f as i64))
+    }
+
+    fn reduce(numer: i128, denom: i128) -> Option<Self> {
+        if denom == 0 {
+            return None;
+        }
+        let divisor = gcd(numer, denom) * denom.signum();
+        Some(Self {
+            numer: (numer / divisor).try_into().ok()?,
+            denom: (denom / divisor).try_into().ok()?,
+        })
+    }
+
+    pub fn numer(&self) -> i64 {
+        self.numer
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.denom
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.denom == 1
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.numer == 0
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.numer as f64 / self.denom as f64
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * d + c * b, b * d)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * d - c * b, b * d)
+    }
+
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * c, b * d)
+    }
+
+    /// Returns `None` on overflow or division by zero.
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * d, b * c)
+    }
+
+    fn widen(self, other: Self) -> (i128, i128, i128, i128) {
+        (
+            self.numer as i128,
+            self.denom as i128,
+            other.numer as i128,
+            other.denom as i128,
+        )
+    }
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs().max(1)
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b, c, d) = self.widen(*other);
+        (a * d).cmp(&(c * b))
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.numer)
+        } else {
+            write!(f, "{}/{}", self.numer, self.denom)
+        }
+    }
+}
+
+/// A number that is exact for as long as possible.
+///
+/// Arithmetic on two exact values stays exact unless it overflows, in which case the
+/// result is promoted to a float. Any operation involving a float produces a float.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scalar {
+    Exact(Rational),
+    Float(f64),
+}
+
+impl Scalar {
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Scalar::Exact(r) => r.to_f64(),
+            Scalar::Float(f) => f,
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.to_f64() == 0.0
+    }
+
+    /// Compares two scalars by value, so `1/2` and `0.5` are equal. Exact values are
+    /// compared exactly; the result is `None` only if a float is NaN.
+    pub fn compare(self, other: Scalar) -> Option<Ordering> {
+        match (self, other) {
+            (Scalar::Exact(a), Scalar::Exact(b)) => Some(a.cmp(&b)),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+
+    /// Returns `None` when dividing by zero.
+    pub fn checked_div(self, other: Scalar) -> Option<Scalar> {
+        if other.is_zero() {
+            return None;
+        }
+        Some(self.op(other, Rational::checked_div, |a, b| a / b))
+    }
+
+    fn op(
+        self,
+        other: Scalar,
+        exact: fn(Rational, Rational) -> Option<Rational>,
+        float: fn(f64, f64) -> f64,
+    ) -> Scalar {
+        if let (Scalar::Exact(a), Scalar::Exact(b)) = (self, other)
+            && let Some(result) = exact(a, b)
+        {
+            return Scalar::Exact(result);
+        }
+        Scalar::Float(float(self.to_f64(), other.to_f64()))
+    }
+}
+
+impl Add for Scalar {
+    type Output = Scalar;
+
+    fn add(self, other: Scalar) -> Scalar {
+        self.op(other, Rational::checked_add, |a, b| a + b)
+    }
+}
+
+impl Sub for Scalar {
+    type Output = Scalar;
+
+    fn sub(self, other: Scalar) -> Scalar {
+        self.op(other, Rational::checked_sub, |a, b| a - b)
+    }
+}
+
+impl Mul for Scalar {
+    type Output = Scalar;
+
+    fn mul(self, other: Scalar) -> Scalar {
+        self.op(other, Rational::checked_mul, |a, b| a * b)
+    }
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scalar::Exact(r) => write!(f, "{r}"),
+            Scalar::Float(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(numer: i64, denom: i64) -> Rational {
+        Rational::new(numer, denom).unwrap()
+    }
+
+    #[test]
+    fn test_rational_normalizes() {
+        assert_eq!(r(2, 4), r(1, 2));
+        assert_eq!(r(3, -6), r(-1, 2));
+        assert_eq!(r(0, 5), r(0, 1));
+        assert_eq!(r(-3, -6).denom(), 2);
+        assert_eq!(Rational::new(1, 0), None);
+    }
+
+    #[test]
+    fn test_rational_arithmetic() {
+        assert_eq!(r(1, 4).checked_add(r(1, 12)), Some(r(1, 3)));
+        assert_eq!(r(1, 4).checked_sub(r(3, 4)), Some(r(-1, 2)));
+        assert_eq!(r(3, 4).checked_mul(r(2, 3)), Some(r(1, 2)));
+        assert_eq!(r(3, 4).checked_div(r(3, 8)), Some(r(2, 1)));
+        assert_eq!(r(3, 4).checked_div(r(0, 1)), None);
+        assert!(r(1, 3) < r(1, 2));
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let sixteenth = r(1, 16);
+        let mut time = Rational::from_integer(0);
+        for _ in 0..16_000 {
+            time = time.checked_add(sixteenth).unwrap();
+        }
+        assert_eq!(time, Rational::from_integer(1000));
+    }
+
+    #[test]
+    fn test_scalar_overflow_promotes_to_float() {
+        let big = Scalar::Exact(r(i64::MAX, 1));
+        assert_eq!(
+            big + Scalar::Exact(r(1, 1)),
+            Scalar::Float(i64::MAX as f64 + 1.0)
+        );
+        assert_eq!(
+            Scalar::Exact(r(1, 2)) + Scalar::Float(0.25),
+            Scalar::Float(0.75)
+        );
+        assert_eq!(Scalar::Exact(r(1, 2)).checked_div(Scalar::Float(0.0)), None);
+    }
+
+    #[test]
+    fn test_scalar_compare() {
+        assert_eq!(
+            Scalar::Exact(r(1, 2)).compare(Scalar::Float(0.5)),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            Scalar::Exact(r(1, 3)).compare(Scalar::Exact(r(1, 2))),
+            Some(Ordering::Less)
+        );
+        assert_eq!(Scalar::Float(f64::NAN).compare(Scalar::Float(0.0)), None);
+    }
+}
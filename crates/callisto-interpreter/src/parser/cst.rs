@@ -0,0 +1,223 @@
+use std::iter::Peekable;
+
+use crate::{
+    lexer::{RawToken, token::TokenKind, tokenize_lossless},
+    span::Span,
+};
+
+/// A lossless concrete syntax tree.
+///
+/// Unlike [`Syntax`](super::syntax::Syntax), the tree keeps every token of the source,
+/// including whitespace, comments and delimiters, so [`CstNode::text`] reproduces the input
+/// exactly. It is built even from invalid input: stray closing delimiters are kept as
+/// tokens, and a list that is never closed simply has no closing token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstNode {
+    pub kind: CstNodeKind,
+    pub children: Vec<CstElement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CstNodeKind {
+    /// The whole source file.
+    Root,
+    List,
+    Vector,
+    Map,
+    /// A quote prefix or `#_` datum comment, followed by the form it applies to.
+    Prefixed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CstElement {
+    Node(CstNode),
+    Token(CstToken),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstToken {
+    /// The kind of token, or `None` if the text is not a valid token.
+    pub kind: Option<TokenKind>,
+    pub text: String,
+    pub span: Span,
+}
+
+impl From<RawToken<'_>> for CstToken {
+    fn from(token: RawToken<'_>) -> Self {
+        Self {
+            kind: token.kind,
+            text: token.text.to_string(),
+            span: token.span,
+        }
+    }
+}
+
+impl CstToken {
+    pub fn is_trivia(&self) -> bool {
+        self.kind.is_some_and(|kind| kind.is_trivia())
+    }
+
+    /// Returns true for `;;` and `//` comments, which must be followed by a line break.
+    pub fn is_line_comment(&self) -> bool {
+        self.kind == Some(TokenKind::Comment)
+    }
+
+    pub fn is_comment(&self) -> bool {
+        matches!(
+            self.kind,
+            Some(TokenKind::Comment | TokenKind::BlockComment)
+        )
+    }
+
+    fn is_close(&self) -> bool {
+        matches!(
+            self.kind,
+            Some(TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace)
+        )
+    }
+}
+
+impl CstElement {
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, CstElement::Token(token) if token.is_trivia())
+    }
+
+    /// Appends the exact source text of this element to `out`.
+    pub fn write_text(&self, out: &mut String) {
+        match self {
+            CstElement::Node(node) => node.write_text(out),
+            CstElement::Token(token) => out.push_str(&token.text),
+        }
+    }
+}
+
+impl CstNode {
+    /// Returns the exact source text the node was parsed from.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        self.write_text(&mut out);
+        out
+    }
+
+    pub fn write_text(&self, out: &mut String) {
+        for child in &self.children {
+            child.write_text(out);
+        }
+    }
+
+    /// Returns true if any token directly inside this node is a comment.
+    pub fn has_comments(&self) -> bool {
+        self.children
+            .iter()
+            .any(|child| matches!(child, CstElement::Token(token) if token.is_comment()))
+    }
+}
+
+/// Parses the input into a lossless concrete syntax tree. This never fails; see [`CstNode`]
+/// for how invalid input is represented.
+pub fn parse_cst(input: &str) -> CstNode {
+    let mut tokens = tokenize_lossless(input)
+        .into_iter()
+        .map(CstToken::from)
+        .peekable();
+    let mut children = Vec::new();
+    while let Some(token) = tokens.next() {
+        children.push(parse_element(token, &mut tokens));
+    }
+    CstNode {
+        kind: CstNodeKind::Root,
+        children,
+    }
+}
+
+fn parse_element<I>(token: CstToken, tokens: &mut Peekable<I>) -> CstElement
+where
+    I: Iterator<Item = CstToken>,
+{
+    let kind = match token.kind {
+        Some(TokenKind::LeftParen) => CstNodeKind::List,
+        Some(TokenKind::LeftBracket) => CstNodeKind::Vector,
+        Some(TokenKind::LeftBrace) => CstNodeKind::Map,
+        Some(
+            TokenKind::Quote
+            | TokenKind::Quasiquote
+            | TokenKind::Unquote
+            | TokenKind::UnquoteSplicing
+            | TokenKind::DatumComment,
+        ) => return parse_prefixed(token, tokens),
+        _ => return CstElement::Token(token),
+    };
+
+    // As in the parser, any closing delimiter ends the sequence, even a mismatched one.
+    let mut children = vec![CstElement::Token(token)];
+    while let Some(token) = tokens.next() {
+        let close = token.is_close();
+        children.push(parse_element(token, tokens));
+        if close {
+            break;
+        }
+    }
+    CstElement::Node(CstNode { kind, children })
+}
+
+fn parse_prefixed<I>(prefix: CstToken, tokens: &mut Peekable<I>) -> CstElement
+where
+    I: Iterator<Item = CstToken>,
+{
+    let mut children = vec![CstElement::Token(prefix)];
+    while let Some(token) = tokens.next_if(|token| !token.is_close()) {
+        let trivia = token.is_trivia();
+        children.push(parse_element(token, tokens));
+        if !trivia {
+            break;
+        }
+    }
+    CstElement::Node(CstNode {
+        kind: CstNodeKind::Prefixed,
+        children,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cst_is_lossless() {
+        for input in [
+            include_str!("../../../../examples/ideas.callisto"),
+            include_str!("../../../../examples/hello.callisto"),
+            "(a ;; comment\n  [b #| block |# {:c 1}])\n\n#_ 'x",
+            "(unclosed (list",
+            ") $ 1.2.3 (mismatched ]",
+            "' ",
+        ] {
+            assert_eq!(parse_cst(input).text(), input);
+        }
+    }
+
+    #[test]
+    fn test_cst_structure() {
+        let root = parse_cst("(a 'b) ;; c");
+        let kinds: Vec<_> = root
+            .children
+            .iter()
+            .map(|child| match child {
+                CstElement::Node(node) => Some(node.kind),
+                CstElement::Token(_) => None,
+            })
+            .collect();
+        assert_eq!(kinds, vec![Some(CstNodeKind::List), None, None]);
+        assert!(root.has_comments());
+
+        let CstElement::Node(list) = &root.children[0] else {
+            panic!("expected list");
+        };
+        assert_eq!(list.children.len(), 5);
+        let CstElement::Node(quoted) = &list.children[3] else {
+            panic!("expected prefixed form");
+        };
+        assert_eq!(quoted.kind, CstNodeKind::Prefixed);
+        assert_eq!(quoted.text(), "'b");
+    }
+}
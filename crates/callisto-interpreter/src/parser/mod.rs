@@ -1,8 +1,17 @@
-use syntax::Syntax;
+use syntax::{Syntax, SyntaxKind};
 use thiserror::Error;
 
-use crate::lexer::{LexingError, token::Token, token_stream::TokenStream, tokenize};
+use crate::{
+    lexer::{
+        LexingError,
+        token::{SpannedToken, Token},
+        token_stream::TokenStream,
+        tokenize,
+    },
+    span::Span,
+};
 
+pub mod cst;
 pub mod syntax;
 
 #[derive(Debug, Clone, PartialEq, Error)]
@@ -11,53 +20,303 @@ pub enum ParsingError {
     LexingError(#[from] LexingError),
 
     #[error("Unexpected token: {token:?}")]
-    UnexpectedToken { token: Token },
+    UnexpectedToken { token: Token, span: Span },
+
+    /// A list, vector or map that is never closed with `close`.
+    #[error("Unclosed list, expected `{close}`")]
+    UnclosedList { close: char, opened_at: Span },
+
+    #[error("Unmatched closing `{close}`")]
+    UnmatchedClose { close: char, span: Span },
+
+    #[error("Mismatched closing delimiter: expected `{expected}`, found `{found}`")]
+    MismatchedClose {
+        expected: char,
+        found: char,
+        span: Span,
+        opened_at: Span,
+    },
+
+    #[error("Map literal has an odd number of forms")]
+    OddMapEntries { span: Span },
+
+    #[error("Datum comment is not followed by a form")]
+    MissingCommentedForm { span: Span },
+
+    #[error("`{prefix}` is not followed by a form")]
+    MissingQuotedForm { prefix: String, span: Span },
+
+    #[error("`let {name} =` is not followed by a value")]
+    MissingLetValue { name: String, span: Span },
+}
+
+impl ParsingError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParsingError::LexingError(err) => err.span(),
+            ParsingError::UnexpectedToken { span, .. } => *span,
+            ParsingError::UnclosedList { opened_at, .. } => *opened_at,
+            ParsingError::UnmatchedClose { span, .. } => *span,
+            ParsingError::MismatchedClose { span, .. } => *span,
+            ParsingError::OddMapEntries { span } => *span,
+            ParsingError::MissingCommentedForm { span } => *span,
+            ParsingError::MissingQuotedForm { span, .. } => *span,
+            ParsingError::MissingLetValue { span, .. } => *span,
+        }
+    }
 }
 
 pub fn parse_str(input: &str) -> Result<Vec<Syntax>, ParsingError> {
     parse(&mut tokenize(input))
 }
 
+/// Parses the input, stopping at the first error.
 pub fn parse(input: &mut TokenStream) -> Result<Vec<Syntax>, ParsingError> {
+    let (syntax_tree, errors) = parse_recovering(input);
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(syntax_tree),
+    }
+}
+
+pub fn parse_str_recovering(input: &str) -> (Vec<Syntax>, Vec<ParsingError>) {
+    parse_recovering(&mut tokenize(input))
+}
+
+/// Parses the input, collecting every error instead of stopping at the first one.
+///
+/// Invalid tokens and stray closing parentheses are skipped, and lists that are never
+/// closed are dropped. Returns the expressions that parsed successfully along with all
+/// errors in the order they were found.
+pub fn parse_recovering(input: &mut TokenStream) -> (Vec<Syntax>, Vec<ParsingError>) {
     let mut syntax_tree = Vec::new();
+    let mut errors = Vec::new();
 
     while !input.is_empty() {
-        let syntax = parse_expression(input)?;
-        syntax_tree.push(syntax);
+        if let Some(syntax) = parse_statement(input, &mut errors) {
+            syntax_tree.push(syntax);
+        }
     }
 
-    Ok(syntax_tree)
+    (syntax_tree, errors)
 }
 
-fn parse_expression(input: &mut TokenStream) -> Result<Syntax, ParsingError> {
-    let token = input.bump()?;
+/// Parses a top-level form. This is either an expression or a `let name = value`
+/// statement, which is read as `(define name value)`.
+fn parse_statement(input: &mut TokenStream, errors: &mut Vec<ParsingError>) -> Option<Syntax> {
+    let token = |n| input.peek_nth(n).and_then(|token| token.as_ref().ok());
+    let (Some(keyword), Some(name_token), Some(equals)) = (token(0), token(1), token(2)) else {
+        return parse_expression(input, errors);
+    };
+    let (Token::Identifier(keyword_name), Token::Identifier(name), Token::Operator(operator)) =
+        (&keyword.token, &name_token.token, &equals.token)
+    else {
+        return parse_expression(input, errors);
+    };
+    if keyword_name != "let" || operator != "=" {
+        return parse_expression(input, errors);
+    }
+
+    let (name, name_span) = (name.clone(), name_token.span);
+    let statement_span = keyword.span;
+    let header_span = keyword.span.to(equals.span);
+    input.bump().ok();
+    input.bump().ok();
+    input.bump().ok();
+
+    loop {
+        if at_close(input) {
+            errors.push(ParsingError::MissingLetValue {
+                name,
+                span: header_span,
+            });
+            return None;
+        }
+        if let Some(value) = parse_expression(input, errors) {
+            let span = statement_span.to(value.span);
+            let define = Syntax::new(SyntaxKind::Identifier("define".to_string()), statement_span);
+            let name = Syntax::new(SyntaxKind::Identifier(name), name_span);
+            return Some(Syntax::new(
+                SyntaxKind::List(vec![define, name, value]),
+                span,
+            ));
+        }
+    }
+}
+
+fn parse_expression(input: &mut TokenStream, errors: &mut Vec<ParsingError>) -> Option<Syntax> {
+    let SpannedToken { token, span } = match input.bump() {
+        Ok(token) => token,
+        Err(err) => {
+            errors.push(err.into());
+            return None;
+        }
+    };
+    let kind = match token {
+        Token::LeftParen => {
+            let (elements, span) = parse_sequence(input, errors, span, ')')?;
+            return Some(Syntax::new(SyntaxKind::List(elements), span));
+        }
+        Token::LeftBracket => {
+            let (elements, span) = parse_sequence(input, errors, span, ']')?;
+            return Some(Syntax::new(SyntaxKind::Vector(elements), span));
+        }
+        Token::LeftBrace => return parse_map(input, errors, span),
+        Token::Identifier(tok) => SyntaxKind::Identifier(tok),
+        Token::Symbol(tok) => SyntaxKind::Symbol(tok),
+        Token::Note(tok) => SyntaxKind::Note(tok),
+        Token::String(tok) => SyntaxKind::String(tok),
+        Token::Operator(tok) => SyntaxKind::Operator(tok),
+        Token::Boolean(tok) => SyntaxKind::Boolean(tok),
+        Token::Integer(tok) => SyntaxKind::Integer(tok),
+        Token::Float(tok) => SyntaxKind::Float(tok),
+        Token::Rational(tok) => SyntaxKind::Rational(tok),
+        Token::Quantity(tok) => SyntaxKind::Quantity(tok),
+        Token::RightParen | Token::RightBracket | Token::RightBrace => {
+            let close = closing_delimiter(&token).unwrap_or(')');
+            errors.push(ParsingError::UnmatchedClose { close, span });
+            return None;
+        }
+        Token::DatumComment => {
+            skip_form(input, errors, span);
+            return None;
+        }
+        Token::Quote => return parse_quoted(input, errors, span, "'", "quote"),
+        Token::Quasiquote => return parse_quoted(input, errors, span, "`", "quasiquote"),
+        Token::Unquote => return parse_quoted(input, errors, span, ",", "unquote"),
+        Token::UnquoteSplicing => {
+            return parse_quoted(input, errors, span, ",@", "unquote_splicing");
+        }
+    };
+    Some(Syntax::new(kind, span))
+}
+
+/// Discards the form following a `#_` datum comment.
+///
+/// A datum comment can itself be commented out, so `#_ #_ a b` skips both `a` and `b`.
+fn skip_form(input: &mut TokenStream, errors: &mut Vec<ParsingError>, comment: Span) {
+    if at_close(input) {
+        errors.push(ParsingError::MissingCommentedForm { span: comment });
+        return;
+    }
+    match input.peek() {
+        Some(Ok(SpannedToken {
+            token: Token::DatumComment,
+            span,
+        })) => {
+            let span = *span;
+            input.bump().ok();
+            skip_form(input, errors, span);
+            skip_form(input, errors, comment);
+        }
+        _ => {
+            parse_expression(input, errors);
+        }
+    }
+}
+
+/// Reads the form following a quote prefix such as `'`, wrapping it in a call to the
+/// matching special form, so `'x` becomes `(quote x)`.
+fn parse_quoted(
+    input: &mut TokenStream,
+    errors: &mut Vec<ParsingError>,
+    prefix_span: Span,
+    prefix: &str,
+    form: &str,
+) -> Option<Syntax> {
+    loop {
+        if at_close(input) {
+            errors.push(ParsingError::MissingQuotedForm {
+                prefix: prefix.to_string(),
+                span: prefix_span,
+            });
+            return None;
+        }
+        if let Some(quoted) = parse_expression(input, errors) {
+            let span = prefix_span.to(quoted.span);
+            let form = Syntax::new(SyntaxKind::Identifier(form.to_string()), prefix_span);
+            return Some(Syntax::new(SyntaxKind::List(vec![form, quoted]), span));
+        }
+    }
+}
+
+/// Returns the closing delimiter a token stands for, if it is one.
+fn closing_delimiter(token: &Token) -> Option<char> {
     match token {
-        Token::LeftParen => parse_list(input),
-        Token::Identifier(tok) => Ok(Syntax::Identifier(tok)),
-        Token::Symbol(tok) => Ok(Syntax::Symbol(tok)),
-        Token::Operator(tok) => Ok(Syntax::Operator(tok)),
-        Token::Boolean(tok) => Ok(Syntax::Boolean(tok)),
-        Token::Number(tok) => Ok(Syntax::Number(tok)),
-        token => Err(ParsingError::UnexpectedToken { token }),
+        Token::RightParen => Some(')'),
+        Token::RightBracket => Some(']'),
+        Token::RightBrace => Some('}'),
+        _ => None,
+    }
+}
+
+/// Returns true if the next token closes a list, vector or map, or there are no tokens left.
+fn at_close(input: &TokenStream) -> bool {
+    match input.peek() {
+        None => true,
+        Some(Ok(token)) => closing_delimiter(&token.token).is_some(),
+        Some(Err(_)) => false,
     }
 }
 
-fn parse_list(input: &mut TokenStream) -> Result<Syntax, ParsingError> {
+/// Parses the elements of a list, vector or map up to the `close` delimiter, returning them
+/// along with the span from the opening to the closing delimiter.
+///
+/// A different closing delimiter, as in `(1 2]`, is reported and then treated as the end of
+/// the sequence so that parsing can continue after it.
+fn parse_sequence(
+    input: &mut TokenStream,
+    errors: &mut Vec<ParsingError>,
+    open: Span,
+    close: char,
+) -> Option<(Vec<Syntax>, Span)> {
     let mut elements = Vec::new();
 
-    while !input.is_empty() {
-        let token = input
-            .peek()
-            .ok_or(ParsingError::LexingError(LexingError::EndOfInput))?;
-        if *token == Ok(Token::RightParen) {
-            input.bump()?; // consume the right parenthesis
-            break;
+    loop {
+        let Some(token) = input.peek() else {
+            errors.push(ParsingError::UnclosedList {
+                close,
+                opened_at: open,
+            });
+            return None;
+        };
+        if let Ok(SpannedToken { token, span }) = token
+            && let Some(found) = closing_delimiter(token)
+        {
+            let span = *span;
+            input.bump().ok(); // consume the closing delimiter
+            if found != close {
+                errors.push(ParsingError::MismatchedClose {
+                    expected: close,
+                    found,
+                    span,
+                    opened_at: open,
+                });
+            }
+            return Some((elements, open.to(span)));
+        }
+        if let Some(element) = parse_expression(input, errors) {
+            elements.push(element);
         }
-        let element = parse_expression(input)?;
-        elements.push(element);
     }
+}
 
-    Ok(Syntax::List(elements))
+fn parse_map(
+    input: &mut TokenStream,
+    errors: &mut Vec<ParsingError>,
+    open: Span,
+) -> Option<Syntax> {
+    let (elements, span) = parse_sequence(input, errors, open, '}')?;
+    if elements.len() % 2 != 0 {
+        errors.push(ParsingError::OddMapEntries { span });
+        return None;
+    }
+    let mut elements = elements.into_iter();
+    let mut entries = Vec::new();
+    while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+        entries.push((key, value));
+    }
+    Some(Syntax::new(SyntaxKind::Map(entries), span))
 }
 
 #[cfg(test)]
@@ -71,11 +330,11 @@ mod tests {
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
         assert_eq!(
-            syntax_tree[0],
-            Syntax::List(vec![
-                Syntax::Identifier("define".to_string()),
-                Syntax::Identifier("x".to_string()),
-                Syntax::Number(42.0),
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Identifier("define".to_string()).into(),
+                SyntaxKind::Identifier("x".to_string()).into(),
+                SyntaxKind::Integer(42).into(),
             ])
         );
     }
@@ -87,11 +346,11 @@ mod tests {
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
         assert_eq!(
-            syntax_tree[0],
-            Syntax::List(vec![
-                Syntax::Operator("+".to_string()),
-                Syntax::Number(1.0),
-                Syntax::Number(2.0),
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Operator("+".to_string()).into(),
+                SyntaxKind::Integer(1).into(),
+                SyntaxKind::Integer(2).into(),
             ])
         );
     }
@@ -102,7 +361,7 @@ mod tests {
         let mut token_stream = tokenize(input);
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
-        assert_eq!(syntax_tree[0], Syntax::Identifier("x".to_string()));
+        assert_eq!(syntax_tree[0].kind, SyntaxKind::Identifier("x".to_string()));
     }
 
     #[test]
@@ -111,8 +370,8 @@ mod tests {
         let mut token_stream = tokenize(input);
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 2);
-        assert_eq!(syntax_tree[0], Syntax::Boolean(true));
-        assert_eq!(syntax_tree[1], Syntax::Boolean(false));
+        assert_eq!(syntax_tree[0].kind, SyntaxKind::Boolean(true));
+        assert_eq!(syntax_tree[1].kind, SyntaxKind::Boolean(false));
     }
 
     #[test]
@@ -121,7 +380,10 @@ mod tests {
         let mut token_stream = tokenize(input);
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
-        assert_eq!(syntax_tree[0], Syntax::Symbol("symbol".to_string()));
+        assert_eq!(
+            syntax_tree[0].kind,
+            SyntaxKind::Symbol("symbol".to_string())
+        );
     }
 
     #[test]
@@ -130,10 +392,10 @@ mod tests {
         let mut token_stream = tokenize(input);
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 4);
-        assert_eq!(syntax_tree[0], Syntax::Operator("+".to_string()));
-        assert_eq!(syntax_tree[1], Syntax::Operator("-".to_string()));
-        assert_eq!(syntax_tree[2], Syntax::Operator("*".to_string()));
-        assert_eq!(syntax_tree[3], Syntax::Operator("/".to_string()));
+        assert_eq!(syntax_tree[0].kind, SyntaxKind::Operator("+".to_string()));
+        assert_eq!(syntax_tree[1].kind, SyntaxKind::Operator("-".to_string()));
+        assert_eq!(syntax_tree[2].kind, SyntaxKind::Operator("*".to_string()));
+        assert_eq!(syntax_tree[3].kind, SyntaxKind::Operator("/".to_string()));
     }
 
     #[test]
@@ -142,7 +404,7 @@ mod tests {
         let mut token_stream = tokenize(input);
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
-        assert_eq!(syntax_tree[0], Syntax::List(vec![]));
+        assert_eq!(syntax_tree[0].kind, SyntaxKind::List(vec![]));
     }
 
     #[test]
@@ -152,15 +414,16 @@ mod tests {
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
         assert_eq!(
-            syntax_tree[0],
-            Syntax::List(vec![
-                Syntax::Operator("+".to_string()),
-                Syntax::Number(1.0),
-                Syntax::List(vec![
-                    Syntax::Operator("*".to_string()),
-                    Syntax::Number(2.0),
-                    Syntax::Number(3.0),
-                ]),
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Operator("+".to_string()).into(),
+                SyntaxKind::Integer(1).into(),
+                SyntaxKind::List(vec![
+                    SyntaxKind::Operator("*".to_string()).into(),
+                    SyntaxKind::Integer(2).into(),
+                    SyntaxKind::Integer(3).into(),
+                ])
+                .into(),
             ])
         );
     }
@@ -172,19 +435,21 @@ mod tests {
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 1);
         assert_eq!(
-            syntax_tree[0],
-            Syntax::List(vec![
-                Syntax::Identifier("define".to_string()),
-                Syntax::List(vec![
-                    Syntax::Identifier("add".to_string()),
-                    Syntax::Identifier("a".to_string()),
-                    Syntax::Identifier("b".to_string()),
-                ]),
-                Syntax::List(vec![
-                    Syntax::Operator("+".to_string()),
-                    Syntax::Identifier("a".to_string()),
-                    Syntax::Identifier("b".to_string()),
-                ]),
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Identifier("define".to_string()).into(),
+                SyntaxKind::List(vec![
+                    SyntaxKind::Identifier("add".to_string()).into(),
+                    SyntaxKind::Identifier("a".to_string()).into(),
+                    SyntaxKind::Identifier("b".to_string()).into(),
+                ])
+                .into(),
+                SyntaxKind::List(vec![
+                    SyntaxKind::Operator("+".to_string()).into(),
+                    SyntaxKind::Identifier("a".to_string()).into(),
+                    SyntaxKind::Identifier("b".to_string()).into(),
+                ])
+                .into(),
             ])
         );
     }
@@ -196,20 +461,427 @@ mod tests {
         let syntax_tree = parse(&mut token_stream).unwrap();
         assert_eq!(syntax_tree.len(), 2);
         assert_eq!(
-            syntax_tree[0],
-            Syntax::List(vec![
-                Syntax::Identifier("define".to_string()),
-                Syntax::Identifier("x".to_string()),
-                Syntax::Number(42.0),
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Identifier("define".to_string()).into(),
+                SyntaxKind::Identifier("x".to_string()).into(),
+                SyntaxKind::Integer(42).into(),
             ])
         );
         assert_eq!(
-            syntax_tree[1],
-            Syntax::List(vec![
-                Syntax::Identifier("define".to_string()),
-                Syntax::Identifier("y".to_string()),
-                Syntax::Number(43.0),
+            syntax_tree[1].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Identifier("define".to_string()).into(),
+                SyntaxKind::Identifier("y".to_string()).into(),
+                SyntaxKind::Integer(43).into(),
             ])
         );
     }
+
+    #[test]
+    fn test_parse_spans() {
+        let input = "(+ 1\n   (* 2 3))";
+        let syntax_tree = parse_str(input).unwrap();
+        assert_eq!(syntax_tree[0].span, Span::new(0, 16, 1, 1));
+        let SyntaxKind::List(elements) = &syntax_tree[0].kind else {
+            panic!("expected list");
+        };
+        assert_eq!(elements[1].span, Span::new(3, 4, 1, 4));
+        assert_eq!(elements[2].span, Span::new(8, 15, 2, 4));
+    }
+
+    #[test]
+    fn test_parse_unmatched_close() {
+        let input = "x )";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnmatchedClose {
+                close: ')',
+                span: Span::new(2, 3, 1, 3),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unmatched_close_after_list() {
+        let input = "(+ 1 2))";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnmatchedClose {
+                close: ')',
+                span: Span::new(7, 8, 1, 8),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unclosed_list() {
+        let input = "(+ 1 2";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnclosedList {
+                close: ')',
+                opened_at: Span::new(0, 1, 1, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unclosed_outer_list() {
+        let input = "(do\n  (+ 1 2)\n  (* 3 4)";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnclosedList {
+                close: ')',
+                opened_at: Span::new(0, 1, 1, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unclosed_inner_list() {
+        let input = "(do (+ 1 2)\n  (* 3 4";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnclosedList {
+                close: ')',
+                opened_at: Span::new(14, 15, 2, 3),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_lone_open_paren() {
+        let input = "(";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnclosedList {
+                close: ')',
+                opened_at: Span::new(0, 1, 1, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_close_before_open() {
+        let input = ")(";
+        let err = parse_str(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnmatchedClose {
+                close: ')',
+                span: Span::new(0, 1, 1, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_balanced_deep_nesting() {
+        let input = "((((()))))";
+        let syntax_tree = parse_str(input).unwrap();
+        assert_eq!(syntax_tree.len(), 1);
+        assert_eq!(syntax_tree[0].span, Span::new(0, 10, 1, 1));
+    }
+
+    #[test]
+    fn test_parse_recovering_collects_all_errors() {
+        let input = "(+ 1 $)\n)\n(define x 42)\n(* 2 3";
+        let (syntax_tree, errors) = parse_str_recovering(input);
+        assert_eq!(
+            syntax_tree,
+            vec![
+                Syntax::from(SyntaxKind::List(vec![
+                    SyntaxKind::Operator("+".to_string()).into(),
+                    SyntaxKind::Integer(1).into(),
+                ])),
+                Syntax::from(SyntaxKind::List(vec![
+                    SyntaxKind::Identifier("define".to_string()).into(),
+                    SyntaxKind::Identifier("x".to_string()).into(),
+                    SyntaxKind::Integer(42).into(),
+                ])),
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec![
+                ParsingError::LexingError(LexingError::InvalidToken {
+                    lexeme: "$".to_string(),
+                    span: Span::new(5, 6, 1, 6),
+                }),
+                ParsingError::UnmatchedClose {
+                    close: ')',
+                    span: Span::new(8, 9, 2, 1),
+                },
+                ParsingError::UnclosedList {
+                    close: ')',
+                    opened_at: Span::new(24, 25, 4, 1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_recovering_nested_unclosed() {
+        let input = "(do (+ 1 2";
+        let (syntax_tree, errors) = parse_str_recovering(input);
+        assert!(syntax_tree.is_empty());
+        assert_eq!(
+            errors,
+            vec![
+                ParsingError::UnclosedList {
+                    close: ')',
+                    opened_at: Span::new(4, 5, 1, 5),
+                },
+                ParsingError::UnclosedList {
+                    close: ')',
+                    opened_at: Span::new(0, 1, 1, 1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_recovering_no_errors() {
+        let input = "(define x 42) x";
+        let (syntax_tree, errors) = parse_str_recovering(input);
+        assert_eq!(syntax_tree.len(), 2);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_parse_string() {
+        let input = r#"(print "hello" :hello)"#;
+        let syntax_tree = parse_str(input).unwrap();
+        assert_eq!(
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Identifier("print".to_string()).into(),
+                SyntaxKind::String("hello".to_string()).into(),
+                SyntaxKind::Symbol("hello".to_string()).into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_datum_comment() {
+        let input = "#_(play :C4) (play :E4)";
+        let syntax_tree = parse_str(input).unwrap();
+        assert_eq!(
+            syntax_tree,
+            vec![Syntax::from(SyntaxKind::List(vec![
+                SyntaxKind::Identifier("play".to_string()).into(),
+                SyntaxKind::Note("E4".parse().unwrap()).into(),
+            ]))]
+        );
+    }
+
+    #[test]
+    fn test_parse_datum_comment_in_list() {
+        let input = "(+ 1 #_ 2 3)";
+        let syntax_tree = parse_str(input).unwrap();
+        assert_eq!(
+            syntax_tree[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Operator("+".to_string()).into(),
+                SyntaxKind::Integer(1).into(),
+                SyntaxKind::Integer(3).into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_stacked_datum_comments() {
+        let input = "#_ #_ a b c";
+        let syntax_tree = parse_str(input).unwrap();
+        assert_eq!(
+            syntax_tree,
+            vec![Syntax::from(SyntaxKind::Identifier("c".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_parse_datum_comment_without_form() {
+        let input = "(a #_)";
+        let (syntax_tree, errors) = parse_str_recovering(input);
+        assert_eq!(
+            syntax_tree,
+            vec![Syntax::from(SyntaxKind::List(vec![
+                SyntaxKind::Identifier("a".to_string()).into(),
+            ]))]
+        );
+        assert_eq!(
+            errors,
+            vec![ParsingError::MissingCommentedForm {
+                span: Span::new(3, 5, 1, 4),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_quote_prefixes() {
+        let quoted = |form: &str, syntax: SyntaxKind| {
+            Syntax::from(SyntaxKind::List(vec![
+                SyntaxKind::Identifier(form.to_string()).into(),
+                syntax.into(),
+            ]))
+        };
+        let x = || SyntaxKind::Identifier("x".to_string());
+        let syntax_tree = parse_str("'x `(,x ,@x) ''x").unwrap();
+        assert_eq!(
+            syntax_tree,
+            vec![
+                quoted("quote", x()),
+                quoted(
+                    "quasiquote",
+                    SyntaxKind::List(vec![
+                        quoted("unquote", x()),
+                        quoted("unquote_splicing", x()),
+                    ])
+                ),
+                quoted("quote", quoted("quote", x()).kind),
+            ]
+        );
+        assert_eq!(syntax_tree[0].span, Span::new(0, 2, 1, 1));
+    }
+
+    #[test]
+    fn test_parse_quote_without_form() {
+        let (syntax_tree, errors) = parse_str_recovering("(a ') '");
+        assert_eq!(syntax_tree.len(), 1);
+        assert_eq!(
+            errors,
+            vec![
+                ParsingError::MissingQuotedForm {
+                    prefix: "'".to_string(),
+                    span: Span::new(3, 4, 1, 4),
+                },
+                ParsingError::MissingQuotedForm {
+                    prefix: "'".to_string(),
+                    span: Span::new(6, 7, 1, 7),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_vector_and_map() {
+        let syntax_tree = parse_str("[1 x] {:cutoff 800 :res [0.3]}").unwrap();
+        assert_eq!(
+            syntax_tree,
+            vec![
+                Syntax::from(SyntaxKind::Vector(vec![
+                    SyntaxKind::Integer(1).into(),
+                    SyntaxKind::Identifier("x".to_string()).into(),
+                ])),
+                Syntax::from(SyntaxKind::Map(vec![
+                    (
+                        SyntaxKind::Symbol("cutoff".to_string()).into(),
+                        SyntaxKind::Integer(800).into(),
+                    ),
+                    (
+                        SyntaxKind::Symbol("res".to_string()).into(),
+                        SyntaxKind::Vector(vec![SyntaxKind::Float(0.3).into()]).into(),
+                    ),
+                ])),
+            ]
+        );
+        assert_eq!(syntax_tree[1].span, Span::new(6, 30, 1, 7));
+    }
+
+    #[test]
+    fn test_parse_odd_map() {
+        let (syntax_tree, errors) = parse_str_recovering("{:a 1 :b} x");
+        assert_eq!(syntax_tree.len(), 1);
+        assert_eq!(
+            errors,
+            vec![ParsingError::OddMapEntries {
+                span: Span::new(0, 9, 1, 1),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_mismatched_close() {
+        let (syntax_tree, errors) = parse_str_recovering("(a [b) c] x");
+        assert_eq!(
+            errors,
+            vec![
+                ParsingError::MismatchedClose {
+                    expected: ']',
+                    found: ')',
+                    span: Span::new(5, 6, 1, 6),
+                    opened_at: Span::new(3, 4, 1, 4),
+                },
+                ParsingError::MismatchedClose {
+                    expected: ')',
+                    found: ']',
+                    span: Span::new(8, 9, 1, 9),
+                    opened_at: Span::new(0, 1, 1, 1),
+                },
+            ]
+        );
+        assert_eq!(syntax_tree.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_unclosed_vector() {
+        let err = parse_str("[1 2").unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnclosedList {
+                close: ']',
+                opened_at: Span::new(0, 1, 1, 1),
+            }
+        );
+        let err = parse_str("}").unwrap_err();
+        assert_eq!(
+            err,
+            ParsingError::UnmatchedClose {
+                close: '}',
+                span: Span::new(0, 1, 1, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_let_statement() {
+        let syntax_tree = parse_str("let foo = (+ 1 2)\nfoo").unwrap();
+        assert_eq!(syntax_tree, parse_str("(define foo (+ 1 2)) foo").unwrap());
+        assert_eq!(syntax_tree[0].span, Span::new(0, 17, 1, 1));
+        // only a statement at the top level
+        assert_eq!(
+            parse_str("(let x = 1)").unwrap()[0].kind,
+            SyntaxKind::List(vec![
+                SyntaxKind::Identifier("let".to_string()).into(),
+                SyntaxKind::Identifier("x".to_string()).into(),
+                SyntaxKind::Operator("=".to_string()).into(),
+                SyntaxKind::Integer(1).into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_let_statement_without_value() {
+        let (syntax_tree, errors) = parse_str_recovering("let x =\n)");
+        assert!(syntax_tree.is_empty());
+        assert_eq!(
+            errors,
+            vec![
+                ParsingError::MissingLetValue {
+                    name: "x".to_string(),
+                    span: Span::new(0, 7, 1, 1),
+                },
+                ParsingError::UnmatchedClose {
+                    close: ')',
+                    span: Span::new(8, 9, 2, 1),
+                },
+            ]
+        );
+    }
 }
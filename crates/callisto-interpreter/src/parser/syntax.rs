@@ -1,49 +1,240 @@
+use std::fmt;
+
+use crate::{note::Note, number::Rational, quantity::Quantity, span::Span};
+
+/// A node in the syntax tree, along with the region of source it was parsed from.
+///
+/// Equality only compares the node's structure; spans are ignored, so trees parsed from
+/// differently formatted sources compare equal.
+#[derive(Debug, Clone)]
+pub struct Syntax {
+    pub kind: SyntaxKind,
+    pub span: Span,
+}
+
+impl Syntax {
+    pub fn new(kind: SyntaxKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    pub fn syntax_type(&self) -> SyntaxType {
+        SyntaxType::from_syntax(self)
+    }
+}
+
+impl PartialEq for Syntax {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl From<SyntaxKind> for Syntax {
+    fn from(kind: SyntaxKind) -> Self {
+        Self::new(kind, Span::default())
+    }
+}
+
+impl fmt::Display for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Syntax {
-    Number(f64),
+pub enum SyntaxKind {
+    Integer(i64),
+    Float(f64),
+    Rational(Rational),
+    Quantity(Quantity),
     String(String),
     Boolean(bool),
     Identifier(String),
     Symbol(String),
+    Note(Note),
     Operator(String),
     List(Vec<Syntax>),
+    /// A `[...]` vector literal.
+    Vector(Vec<Syntax>),
+    /// A `{...}` map literal, as key-value pairs in source order.
+    Map(Vec<(Syntax, Syntax)>),
 }
 
-impl Syntax {
-    pub fn syntax_type(&self) -> SyntaxType {
+/// Prints the node as source that parses back to an equal node. Floats always keep a
+/// decimal point or exponent, and integral rationals are written as `n/1`, so that neither
+/// reads back as an integer.
+impl fmt::Display for SyntaxKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Syntax::Number(_) => SyntaxType::Number,
-            Syntax::String(_) => SyntaxType::String,
-            Syntax::Boolean(_) => SyntaxType::Boolean,
-            Syntax::Identifier(_) => SyntaxType::Identifier,
-            Syntax::Symbol(_) => SyntaxType::Symbol,
-            Syntax::Operator(_) => SyntaxType::Operator,
-            Syntax::List(_) => SyntaxType::List,
+            SyntaxKind::Integer(n) => write!(f, "{n}"),
+            SyntaxKind::Float(n) => write!(f, "{n:?}"),
+            SyntaxKind::Rational(r) if r.is_integer() => write!(f, "{r}/1"),
+            SyntaxKind::Rational(r) => write!(f, "{r}"),
+            SyntaxKind::Quantity(q) => write!(f, "{q}"),
+            SyntaxKind::String(s) => write_string_literal(f, s),
+            SyntaxKind::Boolean(b) => write!(f, "{b}"),
+            SyntaxKind::Identifier(name) | SyntaxKind::Operator(name) => write!(f, "{name}"),
+            SyntaxKind::Symbol(name) => write!(f, ":{name}"),
+            SyntaxKind::Note(note) => write!(f, ":{note}"),
+            SyntaxKind::List(elements) => write_sequence(f, "(", elements, ")"),
+            SyntaxKind::Vector(elements) => write_sequence(f, "[", elements, "]"),
+            SyntaxKind::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{key} {value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Writes the elements separated by spaces between `open` and `close`.
+pub(crate) fn write_sequence<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    open: &str,
+    elements: impl IntoIterator<Item = T>,
+    close: &str,
+) -> fmt::Result {
+    write!(f, "{open}")?;
+    for (i, element) in elements.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
         }
+        write!(f, "{element}")?;
     }
+    write!(f, "{close}")
+}
+
+/// Writes `s` as a quoted string literal, escaping it so that it reads back unchanged.
+pub(crate) fn write_string_literal(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum SyntaxType {
-    Number,
+    Integer,
+    Float,
+    Rational,
+    Quantity,
     String,
     Boolean,
     Identifier,
     Symbol,
+    Note,
     Operator,
     List,
+    Vector,
+    Map,
 }
 
 impl SyntaxType {
     pub fn from_syntax(syntax: &Syntax) -> Self {
-        match syntax {
-            Syntax::Number(_) => SyntaxType::Number,
-            Syntax::String(_) => SyntaxType::String,
-            Syntax::Boolean(_) => SyntaxType::Boolean,
-            Syntax::Identifier(_) => SyntaxType::Identifier,
-            Syntax::Symbol(_) => SyntaxType::Symbol,
-            Syntax::Operator(_) => SyntaxType::Operator,
-            Syntax::List(_) => SyntaxType::List,
+        match syntax.kind {
+            SyntaxKind::Integer(_) => SyntaxType::Integer,
+            SyntaxKind::Float(_) => SyntaxType::Float,
+            SyntaxKind::Rational(_) => SyntaxType::Rational,
+            SyntaxKind::Quantity(_) => SyntaxType::Quantity,
+            SyntaxKind::String(_) => SyntaxType::String,
+            SyntaxKind::Boolean(_) => SyntaxType::Boolean,
+            SyntaxKind::Identifier(_) => SyntaxType::Identifier,
+            SyntaxKind::Symbol(_) => SyntaxType::Symbol,
+            SyntaxKind::Note(_) => SyntaxType::Note,
+            SyntaxKind::Operator(_) => SyntaxType::Operator,
+            SyntaxKind::List(_) => SyntaxType::List,
+            SyntaxKind::Vector(_) => SyntaxType::Vector,
+            SyntaxKind::Map(_) => SyntaxType::Map,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        number::Scalar,
+        parser::parse_str,
+        quantity::{Quantity, Unit},
+    };
+
+    fn leaf() -> impl Strategy<Value = SyntaxKind> {
+        let identifier = "[a-z_][a-zA-Z0-9_]{0,8}"
+            .prop_filter("booleans are not identifiers", |s| {
+                s != "true" && s != "false"
+            });
+        let unit = prop::sample::select(vec![
+            Unit::Beats,
+            Unit::Bars,
+            Unit::Milliseconds,
+            Unit::Seconds,
+            Unit::Hertz,
+        ]);
+        prop_oneof![
+            any::<i64>().prop_map(SyntaxKind::Integer),
+            any::<f64>()
+                .prop_filter("only finite floats can be written", |n| n.is_finite())
+                .prop_map(SyntaxKind::Float),
+            (-1_000_000i64..1_000_000, 1i64..1_000)
+                .prop_map(|(n, d)| SyntaxKind::Rational(Rational::new(n, d).unwrap())),
+            (-1_000_000i64..1_000_000, 1i64..1_000, unit).prop_map(|(n, d, unit)| {
+                let amount = Scalar::Exact(Rational::new(n, d).unwrap());
+                SyntaxKind::Quantity(Quantity::new(amount, unit))
+            }),
+            any::<String>().prop_map(SyntaxKind::String),
+            any::<bool>().prop_map(SyntaxKind::Boolean),
+            identifier.clone().prop_map(SyntaxKind::Identifier),
+            identifier.prop_map(SyntaxKind::Symbol),
+            (0..128).prop_map(|midi| SyntaxKind::Note(Note::from_midi(midi).unwrap())),
+            prop::sample::select(vec!["+", "-", "*", "/", "~", ">>", "+=", "<="])
+                .prop_map(|op| SyntaxKind::Operator(op.to_string())),
+        ]
+    }
+
+    fn syntax() -> impl Strategy<Value = Syntax> {
+        leaf()
+            .prop_map(Syntax::from)
+            .prop_recursive(4, 64, 8, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..8)
+                        .prop_map(|elements| Syntax::from(SyntaxKind::List(elements))),
+                    prop::collection::vec(inner.clone(), 0..8)
+                        .prop_map(|elements| Syntax::from(SyntaxKind::Vector(elements))),
+                    prop::collection::vec((inner.clone(), inner), 0..4)
+                        .prop_map(|entries| Syntax::from(SyntaxKind::Map(entries))),
+                ]
+            })
+    }
+
+    #[test]
+    fn test_display() {
+        let syntax =
+            parse_str(r#"(f 42 -1.0 1e300 4/2 3/4 1/16b :C#4 :x "a\"b\n" [true] {:k v})"#).unwrap();
+        assert_eq!(
+            syntax[0].to_string(),
+            r#"(f 42 -1.0 1e300 2/1 3/4 1/16b :C#4 :x "a\"b\n" [true] {:k v})"#
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_display_round_trips(syntax in syntax()) {
+            prop_assert_eq!(parse_str(&syntax.to_string()), Ok(vec![syntax]));
         }
     }
 }
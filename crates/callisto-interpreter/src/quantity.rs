@@ -0,0 +1,139 @@
+use std::fmt;
+
+use crate::number::{Rational, Scalar};
+
+/// The unit of a suffixed number literal such as `1.5b` or `250ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Unit {
+    Beats,
+    Bars,
+    Milliseconds,
+    Seconds,
+    Hertz,
+}
+
+impl Unit {
+    pub fn from_suffix(suffix: &str) -> Option<Unit> {
+        match suffix {
+            "b" => Some(Unit::Beats),
+            "bar" | "bars" => Some(Unit::Bars),
+            "ms" => Some(Unit::Milliseconds),
+            "s" => Some(Unit::Seconds),
+            "hz" | "Hz" => Some(Unit::Hertz),
+            _ => None,
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Unit::Beats => "b",
+            Unit::Bars => "bar",
+            Unit::Milliseconds => "ms",
+            Unit::Seconds => "s",
+            Unit::Hertz => "hz",
+        }
+    }
+
+    /// Returns true for units that measure time.
+    pub fn is_duration(&self) -> bool {
+        !matches!(self, Unit::Hertz)
+    }
+}
+
+/// A number with a unit, e.g. `1/16b` or `440hz`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantity {
+    pub amount: Scalar,
+    pub unit: Unit,
+}
+
+impl Quantity {
+    pub fn new(amount: Scalar, unit: Unit) -> Self {
+        Self { amount, unit }
+    }
+
+    /// Returns the quantity in the given unit, if the two units can be converted without
+    /// knowing the tempo.
+    pub fn convert(&self, unit: Unit) -> Option<Quantity> {
+        let factor = match (self.unit, unit) {
+            (from, to) if from == to => return Some(*self),
+            (Unit::Seconds, Unit::Milliseconds) => Rational::from_integer(1000),
+            (Unit::Milliseconds, Unit::Seconds) => Rational::new(1, 1000)?,
+            _ => return None,
+        };
+        Some(Quantity::new(self.amount * Scalar::Exact(factor), unit))
+    }
+
+    /// Brings two quantities to a common unit, preferring the finer of the two.
+    pub fn unify(&self, other: &Quantity) -> Option<(Scalar, Scalar, Unit)> {
+        let unit = match (self.unit, other.unit) {
+            (a, b) if a == b => a,
+            (Unit::Seconds, Unit::Milliseconds) | (Unit::Milliseconds, Unit::Seconds) => {
+                Unit::Milliseconds
+            }
+            _ => return None,
+        };
+        Some((
+            self.convert(unit)?.amount,
+            other.convert(unit)?.amount,
+            unit,
+        ))
+    }
+
+    /// The length of this duration in beats, or `None` for frequencies.
+    pub fn beats(&self, bpm: f64, beats_per_bar: Scalar) -> Option<Scalar> {
+        let beat_seconds = Scalar::Float(60.0 / bpm);
+        match self.unit {
+            Unit::Beats => Some(self.amount),
+            Unit::Bars => Some(self.amount * beats_per_bar),
+            Unit::Seconds => self.amount.checked_div(beat_seconds),
+            Unit::Milliseconds => self
+                .amount
+                .checked_div(Scalar::Exact(Rational::from_integer(1000)))?
+                .checked_div(beat_seconds),
+            Unit::Hertz => None,
+        }
+    }
+
+    /// The length of this duration in seconds, or `None` for frequencies.
+    pub fn seconds(&self, bpm: f64, beats_per_bar: Scalar) -> Option<f64> {
+        Some(self.beats(bpm, beats_per_bar)?.to_f64() * 60.0 / bpm)
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.amount, self.unit.suffix())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exact(numer: i64, denom: i64) -> Scalar {
+        Scalar::Exact(Rational::new(numer, denom).unwrap())
+    }
+
+    #[test]
+    fn test_convert() {
+        let q = Quantity::new(exact(3, 2), Unit::Seconds);
+        assert_eq!(
+            q.convert(Unit::Milliseconds),
+            Some(Quantity::new(exact(1500, 1), Unit::Milliseconds))
+        );
+        assert_eq!(q.convert(Unit::Beats), None);
+    }
+
+    #[test]
+    fn test_beats() {
+        let four = exact(4, 1);
+        let q = Quantity::new(exact(1, 2), Unit::Bars);
+        assert_eq!(q.beats(120.0, four), Some(exact(2, 1)));
+        let q = Quantity::new(exact(250, 1), Unit::Milliseconds);
+        assert_eq!(q.beats(120.0, four).map(Scalar::to_f64), Some(0.5));
+        assert_eq!(q.seconds(120.0, four), Some(0.25));
+        let q = Quantity::new(exact(440, 1), Unit::Hertz);
+        assert_eq!(q.beats(120.0, four), None);
+    }
+}
@@ -0,0 +1,107 @@
+use std::ops::Range;
+
+/// A region of source text.
+///
+/// `start` and `end` are byte offsets into the source, `line` and `column` are the
+/// 1-based position of `start` (columns are counted in characters).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// Returns a span covering both `self` and `other`, positioned at `self`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Maps byte offsets in a source string to line/column positions.
+#[derive(Debug, Clone)]
+pub struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(
+            source
+                .char_indices()
+                .filter(|(_, c)| *c == '\n')
+                .map(|(i, _)| i + 1),
+        );
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    /// Returns the 1-based line and column of the given byte offset.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = self.source[line_start..offset].chars().count();
+        (line + 1, column + 1)
+    }
+
+    pub fn span(&self, range: Range<usize>) -> Span {
+        let (line, column) = self.line_col(range.start);
+        Span::new(range.start, range.end, line, column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let index = LineIndex::new("ab\ncd\n\nä x");
+        assert_eq!(index.line_col(0), (1, 1));
+        assert_eq!(index.line_col(1), (1, 2));
+        assert_eq!(index.line_col(3), (2, 1));
+        assert_eq!(index.line_col(6), (3, 1));
+        assert_eq!(index.line_col(7), (4, 1));
+        assert_eq!(index.line_col(10), (4, 3));
+    }
+
+    #[test]
+    fn test_span_to() {
+        let a = Span::new(0, 1, 1, 1);
+        let b = Span::new(4, 6, 1, 5);
+        assert_eq!(a.to(b), Span::new(0, 6, 1, 1));
+    }
+}
@@ -0,0 +1,174 @@
+use std::{collections::BTreeMap, rc::Rc};
+
+use crate::{parser::syntax::Syntax, span::Span};
+
+use super::{RuntimeError, value::Value};
+
+/// A single instruction of the stack machine.
+///
+/// Instructions operate on a stack of values. Local variables live in numbered slots of
+/// the function that declares them, globals are numbered across a whole
+/// [`Machine`](super::machine::Machine), and jump targets are indices into the code of
+/// the current function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// Pushes a value from the constant pool.
+    Constant(u32),
+    Null,
+    Pop,
+    /// Pops a value into a new binding in a local slot, replacing any earlier binding
+    /// there rather than changing it, so closures that captured that one keep it.
+    DefineLocal(u32),
+    GetLocal(u32),
+    /// Assigns the value on top of the stack to a local, leaving it on the stack.
+    SetLocal(u32),
+    /// Pushes a variable the current closure captured.
+    GetCapture(u32),
+    /// Pushes the closure that is running, which a local function calls itself through.
+    CurrentClosure,
+    SetCapture(u32),
+    GetGlobal(u32),
+    /// Pops a value into a global variable.
+    DefineGlobal(u32),
+    /// Assigns the value on top of the stack to an existing global variable, leaving it on
+    /// the stack.
+    SetGlobal(u32),
+    /// Pops a closure into a global function.
+    DefineFunction(u32),
+    Jump(u32),
+    /// Pops a value and jumps if it is falsy.
+    JumpIfFalse(u32),
+    /// Pops a value and jumps if it is truthy.
+    JumpIfTrue(u32),
+    /// Jumps if the value on top of the stack is falsy, and pops it otherwise.
+    JumpIfFalseOrPop(u32),
+    /// Jumps if the value on top of the stack is truthy, and pops it otherwise.
+    JumpIfTrueOrPop(u32),
+    /// Calls the value below the given number of arguments.
+    Call(u32),
+    /// Calls a global by name: its variable if it has one, otherwise its function,
+    /// otherwise the builtin of that name.
+    CallGlobal {
+        global: u32,
+        arguments: u32,
+    },
+    /// Like [`Instruction::Call`], but replaces the current call instead of nesting in it.
+    TailCall(u32),
+    TailCallGlobal {
+        global: u32,
+        arguments: u32,
+    },
+    /// Looks up the key on top of the stack in the map below it, if the global is the
+    /// builtin `get`. If the key is found, pushes its value in their place and jumps past
+    /// the code that evaluates the default; otherwise leaves both for that code to call
+    /// `get` with.
+    GetOrElse {
+        global: u32,
+        found: u32,
+    },
+    Return,
+    /// Creates a closure from a function in the function pool, capturing the variables it
+    /// uses from the enclosing function.
+    Closure(u32),
+    /// Pops the given number of values into a list.
+    List(u32),
+    /// Pushes an empty map.
+    Map,
+    /// Pops a value and a key and inserts them into the map below them.
+    MapInsert,
+    /// Pops a value and appends it to the list below it.
+    ListPush,
+    /// Pops a list and appends its elements to the list below it.
+    ListExtend,
+    Binary(BinaryOp),
+    /// Pops a count and stores an iterator from 0 up to it in a local slot.
+    IterCount(u32),
+    /// Pops a list or range and stores an iterator over it in a local slot.
+    IterSequence(u32),
+    /// Pushes the next element of the iterator in a local slot, or jumps if it is done.
+    Next {
+        slot: u32,
+        done: u32,
+    },
+    /// Pops a value, discards the stack above the given height and pushes the value back.
+    Unwind(u32),
+    /// Discards the stack above the given height.
+    Truncate(u32),
+    /// Fails with an error from the error pool. Used for forms that are invalid but must
+    /// only fail when they are evaluated.
+    Raise(u32),
+}
+
+/// The arithmetic behind `+=`, `-=`, `*=` and `/=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    pub fn apply(self, left: &Value, right: &Value) -> Result<Value, RuntimeError> {
+        match self {
+            BinaryOp::Add => left.add(right),
+            BinaryOp::Sub => left.sub(right),
+            BinaryOp::Mul => left.mul(right),
+            BinaryOp::Div => left.div(right),
+        }
+    }
+}
+
+/// Where a closure finds a variable it captures when it is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// A local slot of the enclosing function.
+    Local(u32),
+    /// A variable the enclosing function captured itself.
+    Enclosing(u32),
+    /// The closure of the enclosing function, which is a local function it refers to by
+    /// its name.
+    Current,
+}
+
+/// A compiled function, or the top level of a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// The name calls to the function appear under in a stack overflow: the name given to
+    /// `func`, or `fn` for closures.
+    pub name: String,
+    pub parameters: Vec<String>,
+    /// The source of the body, for printing closures. `None` for the top level.
+    pub body: Option<Syntax>,
+    pub code: Vec<Instruction>,
+    /// The source location of each instruction, for errors.
+    pub spans: Vec<Span>,
+    /// The source location of the callee of each call instruction, by the index of the
+    /// instruction, for reporting that it cannot be called.
+    pub callees: BTreeMap<usize, Span>,
+    pub constants: Vec<Value>,
+    pub functions: Vec<Rc<Function>>,
+    pub errors: Vec<RuntimeError>,
+    /// How many local slots a call needs, including those of the parameters, which come
+    /// first.
+    pub slots: usize,
+    pub captures: Vec<Capture>,
+}
+
+impl Function {
+    pub fn new(name: &str, parameters: Vec<String>, body: Option<Syntax>) -> Self {
+        Self {
+            name: name.to_string(),
+            slots: parameters.len(),
+            parameters,
+            body,
+            code: Vec::new(),
+            spans: Vec::new(),
+            callees: BTreeMap::new(),
+            constants: Vec::new(),
+            functions: Vec::new(),
+            errors: Vec::new(),
+            captures: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,1103 @@
+use std::rc::Rc;
+
+use crate::{
+    parser::syntax::{Syntax, SyntaxKind, SyntaxType},
+    span::Span,
+};
+
+use super::{
+    RuntimeError,
+    bytecode::{BinaryOp, Capture, Function, Instruction},
+    machine::Globals,
+    value::Value,
+};
+
+/// Compiles a program into the function its top level runs as.
+///
+/// Compiling never fails: a form that is invalid compiles to code that fails when it is
+/// evaluated, so a program behaves the same whether or not an invalid form is reached.
+pub fn compile(program: &[Syntax], globals: &mut Globals) -> Function {
+    let mut compiler = Compiler {
+        globals,
+        functions: vec![FunctionState::new(Function::new("", Vec::new(), None), 0)],
+    };
+    let span = program.last().map(|syntax| syntax.span).unwrap_or_default();
+    compiler.block(program, false, span);
+    compiler.emit(Instruction::Return, span);
+    compiler.functions.pop().expect("no function").function
+}
+
+/// The names of the special forms. They are resolved when compiling, before any variable,
+/// function or builtin of the same name.
+pub const SPECIAL_FORMS: &[&str] = &[
+    "define",
+    "do",
+    "if",
+    "when",
+    "unless",
+    "cond",
+    "and",
+    "or",
+    "func",
+    "loop",
+    "while",
+    "dotimes",
+    "for",
+    "break",
+    "continue",
+    "fn",
+    "lambda",
+    "let",
+    "apply",
+    "+=",
+    "-=",
+    "*=",
+    "/=",
+    "quote",
+    "quasiquote",
+    "unquote",
+    "unquote_splicing",
+];
+
+/// Whether a name is bound by `define` or `let` or by `func`. The two kinds are separate,
+/// so a variable and a function can share a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Binding {
+    Variable,
+    Function,
+}
+
+struct Local {
+    name: String,
+    binding: Binding,
+    slot: u32,
+    /// The depth of the scope that declared the local.
+    depth: usize,
+    /// Whether the local was declared ahead of its definition, which has not been compiled
+    /// yet. Until it is, only nested functions can see it: they run later, by which time
+    /// it has usually been defined, while code running before the definition still sees
+    /// any outer binding of the name.
+    pending: bool,
+}
+
+/// Where a name resolved to.
+#[derive(Debug, Clone, Copy)]
+enum Access {
+    Local(u32),
+    Capture(u32),
+    Global(u32),
+    /// The running closure, for a local function's own name.
+    Current,
+}
+
+struct Loop {
+    /// Where `continue` jumps to.
+    start: u32,
+    /// The height of the stack around the loop.
+    height: u32,
+    /// The jumps of `break`, to be patched to the end of the loop.
+    exits: Vec<usize>,
+}
+
+/// A function being compiled.
+struct FunctionState {
+    function: Function,
+    locals: Vec<Local>,
+    /// The depth of the current scope. The top level of a program is at depth 0, where
+    /// definitions are global; a function body starts at depth 1.
+    depth: usize,
+    loops: Vec<Loop>,
+    /// The height the stack will be at when the next instruction runs, relative to the
+    /// start of the call.
+    height: u32,
+    /// The names of the variables in `function.captures`.
+    captured: Vec<(Binding, String)>,
+    /// The name of a local function, which its body refers to as the running closure
+    /// rather than by capturing the local that holds it. The closure would otherwise keep
+    /// that local alive and the local the closure, so neither would ever be freed. Local
+    /// functions that call each other still capture each other, and are not freed.
+    own_name: Option<String>,
+}
+
+impl FunctionState {
+    fn new(function: Function, depth: usize) -> Self {
+        Self {
+            function,
+            locals: Vec::new(),
+            depth,
+            loops: Vec::new(),
+            height: 0,
+            captured: Vec::new(),
+            own_name: None,
+        }
+    }
+}
+
+struct Compiler<'g> {
+    globals: &'g mut Globals,
+    /// The function being compiled, after the functions it is nested in.
+    functions: Vec<FunctionState>,
+}
+
+impl Compiler<'_> {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("no function")
+    }
+
+    fn here(&mut self) -> u32 {
+        self.current().function.code.len() as u32
+    }
+
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        let state = self.current();
+        state.height = state
+            .height
+            .checked_add_signed(effect(instruction))
+            .expect("stack height underflow");
+        state.function.code.push(instruction);
+        state.function.spans.push(span);
+        state.function.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match &mut self.current().function.code[at] {
+            Instruction::Jump(to)
+            | Instruction::JumpIfFalse(to)
+            | Instruction::JumpIfTrue(to)
+            | Instruction::JumpIfFalseOrPop(to)
+            | Instruction::JumpIfTrueOrPop(to)
+            | Instruction::GetOrElse { found: to, .. }
+            | Instruction::Next { done: to, .. } => *to = target,
+            instruction => unreachable!("cannot patch {instruction:?}"),
+        }
+    }
+
+    fn set_height(&mut self, height: u32) {
+        self.current().height = height;
+    }
+
+    fn height(&mut self) -> u32 {
+        self.current().height
+    }
+
+    fn constant(&mut self, value: Value, span: Span) {
+        let constants = &mut self.current().function.constants;
+        constants.push(value);
+        let index = constants.len() as u32 - 1;
+        self.emit(Instruction::Constant(index), span);
+    }
+
+    /// Emits code that fails with `error` when it runs. It stands in for a value, so the
+    /// code around it compiles as if the form had produced one.
+    fn raise(&mut self, error: RuntimeError, span: Span) {
+        let errors = &mut self.current().function.errors;
+        errors.push(error);
+        let index = errors.len() as u32 - 1;
+        self.emit(Instruction::Raise(index), span);
+    }
+
+    fn argument_count(&mut self, expected: usize, found: usize, span: Span) {
+        self.raise(RuntimeError::InvalidArgumentCount { expected, found }, span);
+    }
+
+    fn syntax_error(&mut self, expected: SyntaxType, found: &Syntax, span: Span) {
+        let found = found.syntax_type();
+        self.raise(RuntimeError::SyntaxError { expected, found }, span);
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.current();
+        state.depth -= 1;
+        let depth = state.depth;
+        state.locals.retain(|local| local.depth <= depth);
+    }
+
+    /// Declares a new local in the current scope and returns its slot.
+    fn declare(&mut self, name: &str, binding: Binding) -> u32 {
+        let state = self.current();
+        let slot = state.function.slots as u32;
+        state.function.slots += 1;
+        state.locals.push(Local {
+            name: name.to_string(),
+            binding,
+            slot,
+            depth: state.depth,
+            pending: false,
+        });
+        slot
+    }
+
+    /// Declares the names a block defines with `define` and `func`, including in the `do`
+    /// forms within it, before any of the block is compiled. Functions in the block can
+    /// then refer to names defined after them, such as each other.
+    fn hoist(&mut self, body: &[Syntax]) {
+        if self.functions.len() == 1 && self.current().depth == 0 {
+            // Globals are looked up when the code runs, so they need no declaring.
+            return;
+        }
+        for expression in body {
+            let SyntaxKind::List(elements) = &expression.kind else {
+                continue;
+            };
+            let Some((head, arguments)) = elements.split_first() else {
+                continue;
+            };
+            let SyntaxKind::Identifier(form) = &head.kind else {
+                continue;
+            };
+            let binding = match form.as_str() {
+                "define" => Binding::Variable,
+                "func" => Binding::Function,
+                "do" => {
+                    self.hoist(arguments);
+                    continue;
+                }
+                _ => continue,
+            };
+            let Some(SyntaxKind::Identifier(name)) = arguments.first().map(|name| &name.kind)
+            else {
+                continue;
+            };
+            if self.declared(name, binding).is_none() {
+                self.declare(name, binding);
+                let local = self.current().locals.last_mut().expect("no local");
+                local.pending = true;
+            }
+        }
+    }
+
+    /// Marks a local declared ahead of its definition as defined.
+    fn defined(&mut self, slot: u32) {
+        let state = self.current();
+        if let Some(local) = state
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot)
+        {
+            local.pending = false;
+        }
+    }
+
+    /// Returns the slot of a local declared in the current scope itself.
+    fn declared(&mut self, name: &str, binding: Binding) -> Option<u32> {
+        let state = self.current();
+        state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == state.depth)
+            .find(|local| local.name == name && local.binding == binding)
+            .map(|local| local.slot)
+    }
+
+    /// Resolves a name to a local of the current function or, failing that, to a variable
+    /// captured from a function it is nested in. Returns `None` for a global.
+    fn resolve(&mut self, name: &str, binding: Binding) -> Option<Access> {
+        self.resolve_in(self.functions.len() - 1, name, binding)
+    }
+
+    fn resolve_in(&mut self, index: usize, name: &str, binding: Binding) -> Option<Access> {
+        let nested = index < self.functions.len() - 1;
+        let state = &self.functions[index];
+        if let Some(local) = state.locals.iter().rev().find(|local| {
+            local.name == name && local.binding == binding && (nested || !local.pending)
+        }) {
+            return Some(Access::Local(local.slot));
+        }
+        if let Some(capture) = state
+            .captured
+            .iter()
+            .position(|captured| *captured == (binding, name.to_string()))
+        {
+            return Some(Access::Capture(capture as u32));
+        }
+        if binding == Binding::Function && state.own_name.as_deref() == Some(name) {
+            return Some(Access::Current);
+        }
+        if index == 0 {
+            return None;
+        }
+        let capture = match self.resolve_in(index - 1, name, binding)? {
+            Access::Local(slot) => Capture::Local(slot),
+            Access::Capture(capture) => Capture::Enclosing(capture),
+            Access::Current => Capture::Current,
+            Access::Global(_) => unreachable!("globals are not captured"),
+        };
+        let state = &mut self.functions[index];
+        state.function.captures.push(capture);
+        state.captured.push((binding, name.to_string()));
+        Some(Access::Capture(state.captured.len() as u32 - 1))
+    }
+
+    fn get(&mut self, access: Access, span: Span) {
+        let instruction = match access {
+            Access::Local(slot) => Instruction::GetLocal(slot),
+            Access::Capture(capture) => Instruction::GetCapture(capture),
+            Access::Global(global) => Instruction::GetGlobal(global),
+            Access::Current => Instruction::CurrentClosure,
+        };
+        self.emit(instruction, span);
+    }
+
+    fn set(&mut self, access: Access, span: Span) {
+        let instruction = match access {
+            Access::Local(slot) => Instruction::SetLocal(slot),
+            Access::Capture(capture) => Instruction::SetCapture(capture),
+            Access::Global(global) => Instruction::SetGlobal(global),
+            Access::Current => unreachable!("functions are not assigned"),
+        };
+        self.emit(instruction, span);
+    }
+
+    fn variable(&mut self, name: &str) -> Access {
+        match self.resolve(name, Binding::Variable) {
+            Some(access) => access,
+            None => Access::Global(self.globals.intern(name)),
+        }
+    }
+
+    /// Compiles an expression, which leaves its value on the stack. `tail` is true if it is
+    /// the last thing a function body evaluates.
+    fn expression(&mut self, syntax: &Syntax, tail: bool) {
+        let span = syntax.span;
+        match &syntax.kind {
+            SyntaxKind::Integer(value) => self.constant(Value::Number(*value as f64), span),
+            SyntaxKind::Float(value) => self.constant(Value::Number(*value), span),
+            SyntaxKind::Rational(value) => self.constant(Value::Rational(*value), span),
+            SyntaxKind::Quantity(value) => self.constant(Value::Quantity(*value), span),
+            SyntaxKind::Boolean(value) => self.constant(Value::Boolean(*value), span),
+            SyntaxKind::Symbol(value) => self.constant(Value::Symbol(value.clone()), span),
+            SyntaxKind::Note(value) => self.constant(Value::Note(*value), span),
+            SyntaxKind::String(value) => self.constant(Value::String(value.clone()), span),
+            SyntaxKind::Identifier(name) => {
+                let access = self.variable(name);
+                self.get(access, span);
+            }
+            SyntaxKind::List(elements) => match elements.split_first() {
+                Some((head, arguments)) => self.call(head, arguments, span, tail),
+                None => {
+                    self.emit(Instruction::Null, span);
+                }
+            },
+            SyntaxKind::Vector(elements) => {
+                for element in elements {
+                    self.expression(element, false);
+                }
+                self.emit(Instruction::List(elements.len() as u32), span);
+            }
+            SyntaxKind::Map(entries) => {
+                self.emit(Instruction::Map, span);
+                for (key, value) in entries {
+                    self.expression(key, false);
+                    self.expression(value, false);
+                    self.emit(Instruction::MapInsert, key.span);
+                }
+            }
+            _ => self.raise(RuntimeError::InvalidSyntax(syntax.syntax_type()), span),
+        }
+    }
+
+    /// Compiles expressions in order, keeping the value of the last one, or null if there
+    /// are none.
+    fn block(&mut self, body: &[Syntax], tail: bool, span: Span) {
+        let Some((last, rest)) = body.split_last() else {
+            self.emit(Instruction::Null, span);
+            return;
+        };
+        self.hoist(body);
+        for expression in rest {
+            self.expression(expression, false);
+            self.emit(Instruction::Pop, expression.span);
+        }
+        self.expression(last, tail);
+    }
+
+    /// Compiles a call to whatever the head of a list refers to.
+    ///
+    /// A name is resolved as a special form first, then as a local variable or function,
+    /// and otherwise looked up among the globals when the call runs. Any other head is
+    /// evaluated, and the value it produces is called.
+    fn call(&mut self, head: &Syntax, arguments: &[Syntax], span: Span, tail: bool) {
+        let (SyntaxKind::Identifier(name) | SyntaxKind::Operator(name)) = &head.kind else {
+            self.expression(head, false);
+            self.call_value(arguments, span, head.span, tail);
+            return;
+        };
+        if self.special_form(name, arguments, span, tail) {
+            return;
+        }
+        let local = self
+            .resolve(name, Binding::Variable)
+            .or_else(|| self.resolve(name, Binding::Function));
+        if let Some(access) = local {
+            self.get(access, head.span);
+            self.call_value(arguments, span, head.span, tail);
+            return;
+        }
+
+        let global = self.globals.intern(name);
+        if name == "get" && arguments.len() == 3 {
+            // The default is only evaluated if the key is missing.
+            self.expression(&arguments[0], false);
+            self.expression(&arguments[1], false);
+            let found = self.emit(Instruction::GetOrElse { global, found: 0 }, span);
+            self.expression(&arguments[2], false);
+            self.emit_call(
+                Instruction::CallGlobal {
+                    global,
+                    arguments: 3,
+                },
+                span,
+                head.span,
+            );
+            self.patch(found);
+            return;
+        }
+        for argument in arguments {
+            self.expression(argument, false);
+        }
+        let arguments = arguments.len() as u32;
+        let instruction = if tail {
+            Instruction::TailCallGlobal { global, arguments }
+        } else {
+            Instruction::CallGlobal { global, arguments }
+        };
+        self.emit_call(instruction, span, head.span);
+    }
+
+    /// Compiles a call of the value on top of the stack, which came from `callee`.
+    fn call_value(&mut self, arguments: &[Syntax], span: Span, callee: Span, tail: bool) {
+        for argument in arguments {
+            self.expression(argument, false);
+        }
+        let count = arguments.len() as u32;
+        let instruction = if tail {
+            Instruction::TailCall(count)
+        } else {
+            Instruction::Call(count)
+        };
+        self.emit_call(instruction, span, callee);
+    }
+
+    fn emit_call(&mut self, instruction: Instruction, span: Span, callee: Span) {
+        let at = self.emit(instruction, span);
+        self.current().function.callees.insert(at, callee);
+    }
+
+    /// Compiles a special form. Returns false if `name` is not one of [`SPECIAL_FORMS`].
+    fn special_form(&mut self, name: &str, arguments: &[Syntax], span: Span, tail: bool) -> bool {
+        match name {
+            "define" => self.define(arguments, span),
+            "do" => {
+                if arguments.is_empty() {
+                    self.argument_count(1, 0, span);
+                } else {
+                    self.block(arguments, tail, span);
+                }
+            }
+            "if" => self.if_form(arguments, span, tail),
+            "when" | "unless" => self.when_unless(name, arguments, span, tail),
+            "cond" => self.cond(arguments, span, tail),
+            "and" | "or" => self.and_or(name, arguments, span, tail),
+            "func" => self.func(arguments, span),
+            "loop" => self.loop_form(arguments, span),
+            "while" => self.while_form(arguments, span),
+            "dotimes" | "for" => self.dotimes_for(name, arguments, span),
+            "break" => self.break_form(name, arguments, span),
+            "continue" => self.continue_form(name, arguments, span),
+            "fn" | "lambda" => self.lambda(arguments, span),
+            "let" => self.let_form(arguments, span, tail),
+            "apply" => self.apply(arguments, span, tail),
+            "+=" | "-=" | "*=" | "/=" => self.compound_assign(name, arguments, span),
+            "quote" => self.quote(arguments, span),
+            "quasiquote" => {
+                if arguments.len() != 1 {
+                    self.argument_count(1, arguments.len(), span);
+                } else {
+                    self.quasiquote(&arguments[0], 1);
+                }
+            }
+            "unquote" | "unquote_splicing" => self.raise(
+                RuntimeError::UnquoteOutsideQuasiquote(name.to_string()),
+                span,
+            ),
+            _ => return false,
+        }
+        true
+    }
+
+    fn define(&mut self, arguments: &[Syntax], span: Span) {
+        if arguments.len() != 2 {
+            return self.argument_count(2, arguments.len(), span);
+        }
+        let SyntaxKind::Identifier(name) = &arguments[0].kind else {
+            return self.syntax_error(SyntaxType::Identifier, &arguments[0], span);
+        };
+        // The value is compiled first, so it still sees any earlier binding of the name.
+        self.expression(&arguments[1], false);
+        if self.functions.len() == 1 && self.current().depth == 0 {
+            let global = self.globals.intern(name);
+            self.emit(Instruction::DefineGlobal(global), span);
+        } else if let Some(slot) = self.declared(name, Binding::Variable) {
+            self.emit(Instruction::SetLocal(slot), span);
+            self.emit(Instruction::Pop, span);
+            self.defined(slot);
+        } else {
+            let slot = self.declare(name, Binding::Variable);
+            self.emit(Instruction::DefineLocal(slot), span);
+        }
+        self.emit(Instruction::Null, span);
+    }
+
+    fn if_form(&mut self, arguments: &[Syntax], span: Span, tail: bool) {
+        if !(2..=3).contains(&arguments.len()) {
+            let found = arguments.len();
+            let err = RuntimeError::InvalidOptionalArgumentCount {
+                min: 2,
+                max: 3,
+                found,
+            };
+            return self.raise(err, span);
+        }
+        self.expression(&arguments[0], false);
+        let otherwise = self.emit(Instruction::JumpIfFalse(0), span);
+        let height = self.height();
+        self.expression(&arguments[1], tail);
+        let end = self.emit(Instruction::Jump(0), span);
+        self.set_height(height);
+        self.patch(otherwise);
+        match arguments.get(2) {
+            Some(otherwise) => self.expression(otherwise, tail),
+            None => {
+                self.emit(Instruction::Null, span);
+            }
+        }
+        self.patch(end);
+    }
+
+    fn when_unless(&mut self, name: &str, arguments: &[Syntax], span: Span, tail: bool) {
+        if arguments.len() < 2 {
+            return self.argument_count(2, arguments.len(), span);
+        }
+        self.expression(&arguments[0], false);
+        let skip = if name == "when" {
+            self.emit(Instruction::JumpIfFalse(0), span)
+        } else {
+            self.emit(Instruction::JumpIfTrue(0), span)
+        };
+        let height = self.height();
+        self.block(&arguments[1..], tail, span);
+        let end = self.emit(Instruction::Jump(0), span);
+        self.set_height(height);
+        self.patch(skip);
+        self.emit(Instruction::Null, span);
+        self.patch(end);
+    }
+
+    fn cond(&mut self, arguments: &[Syntax], span: Span, tail: bool) {
+        let height = self.height();
+        let mut ends = Vec::new();
+        let mut matched = false;
+        for clause in arguments {
+            let SyntaxKind::List(elements) = &clause.kind else {
+                self.syntax_error(SyntaxType::List, clause, span);
+                matched = true;
+                break;
+            };
+            let Some((test, body)) = elements.split_first() else {
+                self.argument_count(1, 0, span);
+                matched = true;
+                break;
+            };
+            if matches!(&test.kind, SyntaxKind::Identifier(name) if name == "else") {
+                self.block(body, tail, span);
+                matched = true;
+                break;
+            }
+            self.expression(test, false);
+            let next = self.emit(Instruction::JumpIfFalse(0), span);
+            self.block(body, tail, span);
+            ends.push(self.emit(Instruction::Jump(0), span));
+            self.set_height(height);
+            self.patch(next);
+        }
+        if !matched {
+            self.emit(Instruction::Null, span);
+        }
+        for end in ends {
+            self.patch(end);
+        }
+    }
+
+    fn and_or(&mut self, name: &str, arguments: &[Syntax], span: Span, tail: bool) {
+        let Some((last, rest)) = arguments.split_last() else {
+            return self.constant(Value::Boolean(name == "and"), span);
+        };
+        let mut ends = Vec::new();
+        for argument in rest {
+            self.expression(argument, false);
+            let end = if name == "and" {
+                self.emit(Instruction::JumpIfFalseOrPop(0), span)
+            } else {
+                self.emit(Instruction::JumpIfTrueOrPop(0), span)
+            };
+            ends.push(end);
+        }
+        self.expression(last, tail);
+        for end in ends {
+            self.patch(end);
+        }
+    }
+
+    fn func(&mut self, arguments: &[Syntax], span: Span) {
+        if arguments.len() != 3 {
+            return self.argument_count(3, arguments.len(), span);
+        }
+        let SyntaxKind::Identifier(name) = &arguments[0].kind else {
+            return self.syntax_error(SyntaxType::Identifier, &arguments[0], span);
+        };
+        let parameters = match parameters(&arguments[1]) {
+            Ok(parameters) => parameters,
+            Err(err) => return self.raise(err, span),
+        };
+        if self.functions.len() == 1 && self.current().depth == 0 {
+            let function = self.function(name, None, parameters, &arguments[2]);
+            self.emit(Instruction::Closure(function), span);
+            let global = self.globals.intern(name);
+            self.emit(Instruction::DefineFunction(global), span);
+        } else {
+            // The function is declared before its body is compiled, so other functions it
+            // calls can call it back.
+            let slot = match self.declared(name, Binding::Function) {
+                Some(slot) => slot,
+                None => {
+                    let slot = self.declare(name, Binding::Function);
+                    self.emit(Instruction::Null, span);
+                    self.emit(Instruction::DefineLocal(slot), span);
+                    slot
+                }
+            };
+            let function = self.function(name, Some(name), parameters, &arguments[2]);
+            self.emit(Instruction::Closure(function), span);
+            self.emit(Instruction::SetLocal(slot), span);
+            self.emit(Instruction::Pop, span);
+            self.defined(slot);
+        }
+        self.emit(Instruction::Null, span);
+    }
+
+    fn lambda(&mut self, arguments: &[Syntax], span: Span) {
+        if arguments.len() != 2 {
+            return self.argument_count(2, arguments.len(), span);
+        }
+        match parameters(&arguments[0]) {
+            Ok(parameters) => {
+                let function = self.function("fn", None, parameters, &arguments[1]);
+                self.emit(Instruction::Closure(function), span);
+            }
+            Err(err) => self.raise(err, span),
+        }
+    }
+
+    /// Compiles a function nested in the current one and returns its index in the function
+    /// pool. `own_name` is the name of a local function, which its body can call itself by.
+    fn function(
+        &mut self,
+        name: &str,
+        own_name: Option<&str>,
+        parameters: Vec<String>,
+        body: &Syntax,
+    ) -> u32 {
+        let mut state = FunctionState::new(
+            Function::new(name, parameters.clone(), Some(body.clone())),
+            1,
+        );
+        state.own_name = own_name.map(str::to_string);
+        for (slot, parameter) in parameters.into_iter().enumerate() {
+            state.locals.push(Local {
+                name: parameter,
+                binding: Binding::Variable,
+                slot: slot as u32,
+                depth: 1,
+                pending: false,
+            });
+        }
+        self.functions.push(state);
+        self.expression(body, true);
+        self.emit(Instruction::Return, body.span);
+        let function = self.functions.pop().expect("no function").function;
+
+        let functions = &mut self.current().function.functions;
+        functions.push(Rc::new(function));
+        functions.len() as u32 - 1
+    }
+
+    fn loop_form(&mut self, arguments: &[Syntax], span: Span) {
+        if arguments.is_empty() {
+            return self.argument_count(2, 0, span);
+        }
+        self.expression(&arguments[0], false);
+        let slot = self.declare("", Binding::Variable);
+        self.emit(Instruction::IterCount(slot), arguments[0].span);
+        self.iteration(slot, None, &arguments[1..], span);
+    }
+
+    fn while_form(&mut self, arguments: &[Syntax], span: Span) {
+        if arguments.is_empty() {
+            return self.argument_count(2, 0, span);
+        }
+        let height = self.height();
+        let start = self.here();
+        self.expression(&arguments[0], false);
+        let done = self.emit(Instruction::JumpIfFalse(0), span);
+        self.loop_body(start, done, height, &arguments[1..], span);
+    }
+
+    fn dotimes_for(&mut self, name: &str, arguments: &[Syntax], span: Span) {
+        if arguments.is_empty() {
+            return self.argument_count(2, 0, span);
+        }
+        // `(dotimes (i n) ...)` counts `i` from 0 to n - 1, and `(for (x xs) ...)` binds
+        // `x` to each element of a list or range in turn.
+        let SyntaxKind::List(binding) = &arguments[0].kind else {
+            return self.syntax_error(SyntaxType::List, &arguments[0], span);
+        };
+        let [variable, source] = binding.as_slice() else {
+            return self.argument_count(2, binding.len(), span);
+        };
+        let SyntaxKind::Identifier(variable) = &variable.kind else {
+            return self.syntax_error(SyntaxType::Identifier, variable, span);
+        };
+        self.expression(source, false);
+        let slot = self.declare("", Binding::Variable);
+        if name == "dotimes" {
+            self.emit(Instruction::IterCount(slot), source.span);
+        } else {
+            self.emit(Instruction::IterSequence(slot), source.span);
+        }
+        self.iteration(slot, Some(variable), &arguments[1..], span);
+    }
+
+    /// Compiles a loop over the iterator in `slot`. If the loop has a variable, each
+    /// element is bound to it in a scope of its own, so closures created in the body
+    /// capture the element of that iteration.
+    fn iteration(&mut self, slot: u32, variable: Option<&str>, body: &[Syntax], span: Span) {
+        let height = self.height();
+        let start = self.here();
+        let done = self.emit(Instruction::Next { slot, done: 0 }, span);
+        let Some(variable) = variable else {
+            self.emit(Instruction::Pop, span);
+            return self.loop_body(start, done, height, body, span);
+        };
+        self.begin_scope();
+        let variable = self.declare(variable, Binding::Variable);
+        self.emit(Instruction::DefineLocal(variable), span);
+        self.loop_body(start, done, height, body, span);
+        self.end_scope();
+    }
+
+    /// Compiles the body of a loop that starts at `start`, then its end, which the jump at
+    /// `done` and any `break` lead to. A loop that ends without `break` gives null.
+    fn loop_body(&mut self, start: u32, done: usize, height: u32, body: &[Syntax], span: Span) {
+        self.current().loops.push(Loop {
+            start,
+            height,
+            exits: Vec::new(),
+        });
+        for expression in body {
+            self.expression(expression, false);
+            self.emit(Instruction::Pop, expression.span);
+        }
+        let exits = self.current().loops.pop().expect("no loop").exits;
+        self.emit(Instruction::Jump(start), span);
+        self.set_height(height);
+        self.patch(done);
+        self.emit(Instruction::Null, span);
+        for exit in exits {
+            self.patch(exit);
+        }
+    }
+
+    fn break_form(&mut self, name: &str, arguments: &[Syntax], span: Span) {
+        if arguments.len() > 1 {
+            return self.argument_count(1, arguments.len(), span);
+        }
+        match arguments.first() {
+            Some(value) => self.expression(value, false),
+            None => {
+                self.emit(Instruction::Null, span);
+            }
+        }
+        let height = self.height();
+        let Some(target) = self.current().loops.last().map(|target| target.height) else {
+            self.emit(Instruction::Pop, span);
+            return self.raise(RuntimeError::OutsideLoop(name.to_string()), span);
+        };
+        self.emit(Instruction::Unwind(target), span);
+        let exit = self.emit(Instruction::Jump(0), span);
+        self.current()
+            .loops
+            .last_mut()
+            .expect("no loop")
+            .exits
+            .push(exit);
+        self.set_height(height);
+    }
+
+    fn continue_form(&mut self, name: &str, arguments: &[Syntax], span: Span) {
+        if !arguments.is_empty() {
+            return self.argument_count(0, arguments.len(), span);
+        }
+        let height = self.height();
+        let Some((start, target)) = self
+            .current()
+            .loops
+            .last()
+            .map(|target| (target.start, target.height))
+        else {
+            return self.raise(RuntimeError::OutsideLoop(name.to_string()), span);
+        };
+        self.emit(Instruction::Truncate(target), span);
+        self.emit(Instruction::Jump(start), span);
+        self.set_height(height + 1);
+    }
+
+    fn let_form(&mut self, arguments: &[Syntax], span: Span, tail: bool) {
+        if arguments.len() != 2 {
+            return self.argument_count(2, arguments.len(), span);
+        }
+        let SyntaxKind::List(bindings) = &arguments[0].kind else {
+            return self.syntax_error(SyntaxType::List, &arguments[0], span);
+        };
+        // Each binding can see the ones before it, and none outlive the body.
+        self.begin_scope();
+        let mut valid = true;
+        for binding in bindings {
+            let SyntaxKind::List(pair) = &binding.kind else {
+                self.syntax_error(SyntaxType::List, binding, span);
+                valid = false;
+                break;
+            };
+            if pair.len() != 2 {
+                self.argument_count(2, pair.len(), span);
+                valid = false;
+                break;
+            }
+            let SyntaxKind::Identifier(name) = &pair[0].kind else {
+                self.syntax_error(SyntaxType::Identifier, &pair[0], span);
+                valid = false;
+                break;
+            };
+            self.expression(&pair[1], false);
+            let slot = self.declare(name, Binding::Variable);
+            self.emit(Instruction::DefineLocal(slot), span);
+        }
+        if valid {
+            self.expression(&arguments[1], tail);
+        }
+        self.end_scope();
+    }
+
+    fn apply(&mut self, arguments: &[Syntax], span: Span, tail: bool) {
+        if arguments.len() != 2 {
+            return self.argument_count(2, arguments.len(), span);
+        }
+        match &arguments[1].kind {
+            SyntaxKind::List(list) => self.call(&arguments[0], list, span, tail),
+            _ => self.syntax_error(SyntaxType::List, &arguments[1], span),
+        }
+    }
+
+    fn compound_assign(&mut self, name: &str, arguments: &[Syntax], span: Span) {
+        if arguments.len() != 2 {
+            return self.argument_count(2, arguments.len(), span);
+        }
+        let SyntaxKind::Identifier(variable) = &arguments[0].kind else {
+            return self.syntax_error(SyntaxType::Identifier, &arguments[0], span);
+        };
+        let access = self.variable(variable);
+        self.get(access, span);
+        self.expression(&arguments[1], false);
+        let op = match name {
+            "+=" => BinaryOp::Add,
+            "-=" => BinaryOp::Sub,
+            "*=" => BinaryOp::Mul,
+            _ => BinaryOp::Div,
+        };
+        self.emit(Instruction::Binary(op), span);
+        self.set(access, span);
+    }
+
+    fn quote(&mut self, arguments: &[Syntax], span: Span) {
+        if arguments.len() != 1 {
+            return self.argument_count(1, arguments.len(), span);
+        }
+        self.literal(&arguments[0], span);
+    }
+
+    fn literal(&mut self, syntax: &Syntax, span: Span) {
+        match Value::from_syntax(syntax) {
+            Ok(value) => self.constant(value, span),
+            Err(err) => self.raise(err, span),
+        }
+    }
+
+    /// Compiles a quasiquoted form at the given nesting depth. Parts without an unquote
+    /// at depth 1 are built once, as constants.
+    fn quasiquote(&mut self, syntax: &Syntax, depth: usize) {
+        if !has_unquote(syntax, depth) {
+            return self.literal(syntax, syntax.span);
+        }
+        let elements = match &syntax.kind {
+            SyntaxKind::List(elements) | SyntaxKind::Vector(elements) => elements,
+            SyntaxKind::Map(entries) => {
+                self.emit(Instruction::Map, syntax.span);
+                for (key, value) in entries {
+                    self.quasiquote(key, depth);
+                    self.quasiquote(value, depth);
+                    self.emit(Instruction::MapInsert, key.span);
+                }
+                return;
+            }
+            _ => return self.literal(syntax, syntax.span),
+        };
+        match quasiquote_form(syntax) {
+            Some(("unquote", form)) if depth == 1 => return self.expression(form, false),
+            Some(("unquote_splicing", _)) if depth == 1 => {
+                let err = RuntimeError::UnquoteOutsideQuasiquote("unquote_splicing".to_string());
+                return self.raise(err, syntax.span);
+            }
+            Some((name, form)) => {
+                let depth = match name {
+                    "quasiquote" => depth + 1,
+                    _ => depth - 1,
+                };
+                self.constant(Value::Symbol(name.to_string()), syntax.span);
+                self.quasiquote(form, depth);
+                self.emit(Instruction::List(2), syntax.span);
+                return;
+            }
+            None => {}
+        }
+
+        self.emit(Instruction::List(0), syntax.span);
+        for element in elements {
+            match quasiquote_form(element) {
+                Some(("unquote_splicing", form)) if depth == 1 => {
+                    self.expression(form, false);
+                    self.emit(Instruction::ListExtend, form.span);
+                }
+                _ => {
+                    self.quasiquote(element, depth);
+                    self.emit(Instruction::ListPush, element.span);
+                }
+            }
+        }
+    }
+}
+
+/// How an instruction changes the height of the stack.
+fn effect(instruction: Instruction) -> i32 {
+    match instruction {
+        Instruction::Constant(_)
+        | Instruction::Null
+        | Instruction::GetLocal(_)
+        | Instruction::GetCapture(_)
+        | Instruction::CurrentClosure
+        | Instruction::GetGlobal(_)
+        | Instruction::Closure(_)
+        | Instruction::Next { .. }
+        | Instruction::Map
+        | Instruction::Raise(_) => 1,
+        Instruction::Pop
+        | Instruction::DefineLocal(_)
+        | Instruction::DefineGlobal(_)
+        | Instruction::DefineFunction(_)
+        | Instruction::JumpIfFalse(_)
+        | Instruction::JumpIfTrue(_)
+        | Instruction::JumpIfFalseOrPop(_)
+        | Instruction::JumpIfTrueOrPop(_)
+        | Instruction::ListPush
+        | Instruction::ListExtend
+        | Instruction::Binary(_)
+        | Instruction::IterCount(_)
+        | Instruction::IterSequence(_)
+        | Instruction::Return => -1,
+        Instruction::SetLocal(_)
+        | Instruction::SetCapture(_)
+        | Instruction::SetGlobal(_)
+        | Instruction::Jump(_)
+        | Instruction::GetOrElse { .. }
+        | Instruction::Unwind(_)
+        | Instruction::Truncate(_) => 0,
+        Instruction::MapInsert => -2,
+        Instruction::Call(arguments) | Instruction::TailCall(arguments) => -(arguments as i32),
+        Instruction::CallGlobal { arguments, .. }
+        | Instruction::TailCallGlobal { arguments, .. } => 1 - arguments as i32,
+        Instruction::List(count) => 1 - count as i32,
+    }
+}
+
+/// Returns true if a quasiquoted form contains an unquote at depth 1, which has to be
+/// evaluated each time the form is.
+fn has_unquote(syntax: &Syntax, depth: usize) -> bool {
+    match quasiquote_form(syntax) {
+        Some(("quasiquote", form)) => has_unquote(form, depth + 1),
+        Some((_, form)) => depth == 1 || has_unquote(form, depth - 1),
+        None => match &syntax.kind {
+            SyntaxKind::List(elements) | SyntaxKind::Vector(elements) => {
+                elements.iter().any(|element| has_unquote(element, depth))
+            }
+            SyntaxKind::Map(entries) => entries
+                .iter()
+                .any(|(key, value)| has_unquote(key, depth) || has_unquote(value, depth)),
+            _ => false,
+        },
+    }
+}
+
+/// Returns the parameter names of a function from a list of identifiers.
+pub(super) fn parameters(syntax: &Syntax) -> Result<Vec<String>, RuntimeError> {
+    let SyntaxKind::List(params) = &syntax.kind else {
+        return Err(RuntimeError::SyntaxError {
+            expected: SyntaxType::List,
+            found: syntax.syntax_type(),
+        });
+    };
+    params
+        .iter()
+        .map(|param| match &param.kind {
+            SyntaxKind::Identifier(name) => Ok(name.clone()),
+            _ => Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::Identifier,
+                found: param.syntax_type(),
+            }),
+        })
+        .collect()
+}
+
+/// Returns the name and argument of a `(quasiquote x)`, `(unquote x)` or
+/// `(unquote_splicing x)` form.
+pub(super) fn quasiquote_form(syntax: &Syntax) -> Option<(&str, &Syntax)> {
+    let SyntaxKind::List(elements) = &syntax.kind else {
+        return None;
+    };
+    match elements.as_slice() {
+        [head, form] => match &head.kind {
+            SyntaxKind::Identifier(name)
+                if matches!(name.as_str(), "quasiquote" | "unquote" | "unquote_splicing") =>
+            {
+                Some((name.as_str(), form))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+
+use crate::quantity::Unit;
+
+use super::{RuntimeError, value::Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+impl Waveform {
+    /// The name of the builtin that creates an oscillator with this waveform.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Waveform::Sine => "sine",
+            Waveform::Saw => "saw",
+            Waveform::Square => "square",
+            Waveform::Triangle => "triangle",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+}
+
+impl FilterKind {
+    /// The name of the builtin that creates a filter of this kind.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::LowPass => "lpf",
+            FilterKind::HighPass => "hpf",
+        }
+    }
+}
+
+/// A node in an audio graph.
+///
+/// Signal nodes describe how a signal is computed from other signals, while [`Node::Output`]
+/// and [`Node::Bus`] are sinks that signals can be patched into.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// The audio output (`dac`).
+    Output,
+    /// A named bus, which can be both patched into and read from.
+    Bus(String),
+    Constant(f64),
+    Oscillator {
+        waveform: Waveform,
+        frequency: Box<Node>,
+    },
+    /// A filter whose input is `None` until it is chained after another signal with `>>`.
+    Filter {
+        kind: FilterKind,
+        cutoff: Box<Node>,
+        input: Option<Box<Node>>,
+    },
+    Add(Box<Node>, Box<Node>),
+    Sub(Box<Node>, Box<Node>),
+    Mul(Box<Node>, Box<Node>),
+    Div(Box<Node>, Box<Node>),
+}
+
+impl Node {
+    /// Returns true if signals can be patched into this node.
+    pub fn is_sink(&self) -> bool {
+        matches!(self, Node::Output | Node::Bus(_))
+    }
+
+    /// Returns true if this node is a processor still waiting for an input signal.
+    pub fn is_unpatched(&self) -> bool {
+        matches!(self, Node::Filter { input: None, .. })
+    }
+
+    /// Converts a value into a signal that can be read from.
+    pub fn from_value(value: &Value) -> Result<Node, RuntimeError> {
+        match value {
+            Value::Number(n) => Ok(Node::Constant(*n)),
+            Value::Rational(r) => Ok(Node::Constant(r.to_f64())),
+            Value::Note(note) => Ok(Node::Constant(note.frequency())),
+            Value::Quantity(q) if q.unit == Unit::Hertz => Ok(Node::Constant(q.amount.to_f64())),
+            Value::Node(Node::Output) => Err(RuntimeError::CannotConvertToNode(value.value_type())),
+            Value::Node(node) => Ok(node.clone()),
+            _ => Err(RuntimeError::CannotConvertToNode(value.value_type())),
+        }
+    }
+
+    /// Returns a copy of this processor with `input` patched into it.
+    pub fn with_input(&self, input: Node) -> Node {
+        match self {
+            Node::Filter { kind, cutoff, .. } => Node::Filter {
+                kind: *kind,
+                cutoff: cutoff.clone(),
+                input: Some(Box::new(input)),
+            },
+            node => node.clone(),
+        }
+    }
+}
+
+/// Prints the node as the expression that creates it, e.g. `(lpf 800 (saw 110))`.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Output => write!(f, "dac"),
+            Node::Bus(name) => write!(f, "(bus {})", Value::Symbol(name.clone())),
+            Node::Constant(n) => write!(f, "{}", Value::Number(*n)),
+            Node::Oscillator {
+                waveform,
+                frequency,
+            } => write!(f, "({} {frequency})", waveform.name()),
+            Node::Filter {
+                kind,
+                cutoff,
+                input: None,
+            } => write!(f, "({} {cutoff})", kind.name()),
+            Node::Filter {
+                kind,
+                cutoff,
+                input: Some(input),
+            } => write!(f, "({} {cutoff} {input})", kind.name()),
+            Node::Add(a, b) => write!(f, "(+ {a} {b})"),
+            Node::Sub(a, b) => write!(f, "(- {a} {b})"),
+            Node::Mul(a, b) => write!(f, "(* {a} {b})"),
+            Node::Div(a, b) => write!(f, "(/ {a} {b})"),
+        }
+    }
+}
+
+/// A connection from a signal to one channel of a sink.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub source: Node,
+    pub target: Node,
+    pub channel: usize,
+}
+
+/// The connections made by `~` and `>>` while a program runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Graph {
+    pub connections: Vec<Connection>,
+}
+
+impl Graph {
+    pub fn connect(&mut self, source: Node, target: Node, channel: usize) {
+        self.connections.push(Connection {
+            source,
+            target,
+            channel,
+        });
+    }
+
+    /// Lets each connection from `start` on replace an identical one made before it, so
+    /// that running the same code again leaves one copy of each connection it makes.
+    pub fn replace_from(&mut self, start: usize) {
+        let mut old = start;
+        for connection in self.connections.split_off(start) {
+            if let Some(index) = self.connections[..old]
+                .iter()
+                .position(|made| *made == connection)
+            {
+                self.connections.remove(index);
+                old -= 1;
+            }
+            self.connections.push(connection);
+        }
+    }
+}
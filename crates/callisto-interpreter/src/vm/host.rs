@@ -0,0 +1,315 @@
+use std::rc::Rc;
+
+use super::{
+    RuntimeError,
+    value::{Value, ValueType},
+};
+
+/// A function the host application registered with [`Vm::register_fn`](super::Vm::register_fn).
+/// It receives its arguments already evaluated.
+pub type HostFunction = Rc<dyn Fn(&[Value]) -> Result<Value, RuntimeError>>;
+
+/// Converts a value of the language into a Rust type, failing with
+/// [`RuntimeError::TypeError`] if it has a different type.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError>;
+}
+
+/// Converts a Rust type into a value of the language.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+fn type_error(expected: ValueType, found: &Value) -> RuntimeError {
+    RuntimeError::TypeError {
+        expected,
+        found: found.value_type(),
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue for f64 {
+    /// Accepts any number, including rationals.
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value.as_scalar() {
+            Some(scalar) => Ok(scalar.to_f64()),
+            None => Err(type_error(ValueType::Number, value)),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::Boolean(value) => Ok(*value),
+            value => Err(type_error(ValueType::Boolean, value)),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::String(value) => Ok(value.clone()),
+            value => Err(type_error(ValueType::String, value)),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::List(values) => values.iter().map(T::from_value).collect(),
+            value => Err(type_error(ValueType::List, value)),
+        }
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Null
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Boolean(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::List(self.into_iter().map(IntoValue::into_value).collect())
+    }
+}
+
+/// Checks that a call has exactly `expected` arguments.
+fn expect_arguments(arguments: &[Value], expected: usize) -> Result<(), RuntimeError> {
+    if arguments.len() != expected {
+        return Err(RuntimeError::InvalidArgumentCount {
+            expected,
+            found: arguments.len(),
+        });
+    }
+    Ok(())
+}
+
+/// A Rust closure that can be registered as a [`HostFunction`].
+///
+/// Closures that take `&[Value]` receive the arguments as they are. Closures that take up
+/// to six arguments of types that implement [`FromValue`] have the number and types of
+/// their arguments checked before they are called. Either kind returns a `Result` of a
+/// type that implements [`IntoValue`].
+///
+/// `Args` only tells the implementations apart and is inferred from the closure.
+pub trait IntoHostFunction<Args: ?Sized> {
+    fn into_host_function(self) -> HostFunction;
+}
+
+impl<F, R> IntoHostFunction<[Value]> for F
+where
+    F: Fn(&[Value]) -> Result<R, RuntimeError> + 'static,
+    R: IntoValue,
+{
+    fn into_host_function(self) -> HostFunction {
+        Rc::new(move |arguments| self(arguments).map(IntoValue::into_value))
+    }
+}
+
+/// Implements the conversions for tuples, which are lists of a fixed length in the
+/// language, and [`IntoHostFunction`] for closures taking that many typed arguments.
+macro_rules! tuples {
+    ($(($($name:ident $index:tt),*);)*) => {$(
+        impl<$($name: FromValue),*> FromValue for ($($name,)*) {
+            /// Accepts a list of exactly as many elements as the tuple, and fails with
+            /// [`RuntimeError::InvalidArgumentCount`] otherwise.
+            fn from_value(value: &Value) -> Result<Self, RuntimeError> {
+                let Value::List(values) = value else {
+                    return Err(type_error(ValueType::List, value));
+                };
+                expect_arguments(values, [$($index),*].len())?;
+                Ok(($($name::from_value(&values[$index])?,)*))
+            }
+        }
+
+        impl<$($name: IntoValue),*> IntoValue for ($($name,)*) {
+            fn into_value(self) -> Value {
+                Value::List(vec![$(self.$index.into_value()),*])
+            }
+        }
+
+        impl<F, R, $($name),*> IntoHostFunction<($($name,)*)> for F
+        where
+            F: Fn($($name),*) -> Result<R, RuntimeError> + 'static,
+            R: IntoValue,
+            $($name: FromValue,)*
+        {
+            fn into_host_function(self) -> HostFunction {
+                Rc::new(move |arguments| {
+                    expect_arguments(arguments, [$($index),*].len())?;
+                    self($($name::from_value(&arguments[$index])?),*).map(IntoValue::into_value)
+                })
+            }
+        }
+    )*};
+}
+
+tuples! {
+    (A 0);
+    (A 0, B 1);
+    (A 0, B 1, C 2);
+    (A 0, B 1, C 2, D 3);
+    (A 0, B 1, C 2, D 3, E 4);
+    (A 0, B 1, C 2, D 3, E 4, G 5);
+}
+
+impl<F, R> IntoHostFunction<()> for F
+where
+    F: Fn() -> Result<R, RuntimeError> + 'static,
+    R: IntoValue,
+{
+    fn into_host_function(self) -> HostFunction {
+        Rc::new(move |arguments| {
+            expect_arguments(arguments, 0)?;
+            self().map(IntoValue::into_value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(f64::from_value(&Value::Number(1.5)), Ok(1.5));
+        assert_eq!(
+            bool::from_value(&Value::Number(1.0)),
+            Err(RuntimeError::TypeError {
+                expected: ValueType::Boolean,
+                found: ValueType::Number,
+            })
+        );
+        let list = vec![(1.0, "a"), (2.0, "b")].into_value();
+        assert_eq!(
+            <Vec<(f64, String)>>::from_value(&list),
+            Ok(vec![(1.0, "a".to_string()), (2.0, "b".to_string())])
+        );
+        assert_eq!(
+            <(f64, f64, f64)>::from_value(&vec![1.0, 2.0].into_value()),
+            Err(RuntimeError::InvalidArgumentCount {
+                expected: 3,
+                found: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_register_fn() -> Result<(), RuntimeError> {
+        let mut vm = Vm::new();
+        vm.register_fn("sum", |arguments: &[Value]| {
+            let mut total = 0.0;
+            for argument in arguments {
+                total += f64::from_value(argument)?;
+            }
+            Ok(total)
+        })?
+        .register_fn("repeat", |text: String, times: f64| {
+            Ok(text.repeat(times as usize))
+        })?
+        .register_fn("zip", |xs: Vec<f64>, ys: Vec<f64>| {
+            Ok(xs.into_iter().zip(ys).collect::<Vec<_>>())
+        })?;
+
+        assert_eq!(vm.execute_str("(sum)"), Ok(Value::Number(0.0)));
+        assert_eq!(
+            vm.execute_str("(sum 1 2 (sum 3 4))"),
+            Ok(Value::Number(10.0))
+        );
+        assert_eq!(
+            vm.execute_str(r#"(repeat "ab" 3)"#),
+            Ok(Value::String("ababab".to_string()))
+        );
+        assert_eq!(
+            vm.execute_str("(zip [1 2] [3 4 5])"),
+            vm.execute_str("[[1 3] [2 4]]")
+        );
+
+        let err = vm.execute_str(r#"(repeat "ab")"#).unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: 1,
+            }
+        );
+        let err = vm.execute_str("(repeat 1 2)").unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::TypeError {
+                expected: ValueType::String,
+                found: ValueType::Number,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_fn_resolution() -> Result<(), RuntimeError> {
+        let mut vm = Vm::new();
+        vm.register_fn("get", |_: Value, _: Value, _: Value| Ok("host"))?
+            .register_fn("hello", || Ok("hello"))?;
+
+        // Registered functions replace builtins of the same name, but are shadowed by
+        // definitions in the program like builtins are.
+        assert_eq!(
+            vm.execute_str("(get {:a 1} :a 2)"),
+            Ok(Value::String("host".to_string()))
+        );
+        assert_eq!(
+            vm.execute_str("(define hello (fn () :shadowed)) (hello)"),
+            Ok(Value::Symbol("shadowed".to_string()))
+        );
+        assert_eq!(vm.execute_str("(+ 1 2)"), Ok(Value::Number(3.0)));
+
+        // Special forms are resolved before any function, so they cannot be replaced.
+        for name in ["if", "define", "let", "fn"] {
+            assert_eq!(
+                vm.register_fn(name, || Ok("host")).err(),
+                Some(RuntimeError::SpecialFormName(name.to_string()))
+            );
+        }
+        assert_eq!(vm.execute_str("(if true 1 2)"), Ok(Value::Number(1.0)));
+        Ok(())
+    }
+}
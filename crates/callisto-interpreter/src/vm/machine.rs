@@ -0,0 +1,654 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+};
+
+use crate::parser::{parse_str, syntax::Syntax};
+
+use super::{
+    MAX_NESTED_CALLS, RuntimeError, STACK_OVERFLOW_FRAMES, Vm,
+    bytecode::{Capture, Function, Instruction},
+    compiler::compile,
+    graph::{Graph, Node},
+    host::HostFunction,
+    natives::{self, Native},
+    value::{Cell, Closure, MapKey, Range, Value, ValueType},
+};
+
+/// The global variables and functions of a [`Machine`], numbered so that compiled code can
+/// refer to them by index.
+#[derive(Debug, Clone, Default)]
+pub struct Globals {
+    indices: HashMap<String, u32>,
+    entries: Vec<Global>,
+}
+
+#[derive(Debug, Clone)]
+struct Global {
+    name: String,
+    variable: Option<Value>,
+    function: Option<Value>,
+    builtin: Option<Builtin>,
+}
+
+/// A function that is called by name when the program does not define it.
+#[derive(Clone)]
+enum Builtin {
+    Native(Native),
+    Host(HostFunction),
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Builtin::Native(native) => f.debug_tuple("Native").field(native).finish(),
+            Builtin::Host(_) => f.write_str("Host"),
+        }
+    }
+}
+
+impl Globals {
+    /// Returns the index of a global, adding it if it has not been seen before.
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(index) = self.indices.get(name) {
+            return *index;
+        }
+        let index = self.entries.len() as u32;
+        self.entries.push(Global {
+            name: name.to_string(),
+            variable: None,
+            function: None,
+            builtin: natives::lookup(name).map(Builtin::Native),
+        });
+        self.indices.insert(name.to_string(), index);
+        index
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        let index = self.indices.get(name)?;
+        self.entries[*index as usize].variable.as_ref()
+    }
+
+    pub fn define(&mut self, name: &str, value: Value) {
+        let index = self.intern(name);
+        self.entries[index as usize].variable = Some(value);
+    }
+
+    /// Makes a function of the host application callable by name, in place of any builtin
+    /// of the same name.
+    fn register(&mut self, name: &str, function: HostFunction) {
+        let index = self.intern(name);
+        self.entries[index as usize].builtin = Some(Builtin::Host(function));
+    }
+}
+
+/// What a program leaves behind when it finishes: its global definitions and the
+/// connections it made. A [`Vm`] keeps one between evaluations. It holds no running or
+/// scheduled tasks, which the language cannot create yet.
+pub struct Session {
+    globals: Globals,
+    graph: Graph,
+}
+
+impl Clone for Session {
+    /// Copies the globals along with the variables their closures captured, so that running
+    /// code in one session does not change the other.
+    fn clone(&self) -> Self {
+        let mut copies = HashMap::new();
+        let mut globals = self.globals.clone();
+        for global in &mut globals.entries {
+            for value in [&mut global.variable, &mut global.function]
+                .into_iter()
+                .flatten()
+            {
+                *value = value.deep_clone(&mut copies);
+            }
+        }
+        Self {
+            globals,
+            graph: self.graph.clone(),
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        let mut globals = Globals::default();
+        globals.define("dac", Value::Node(Node::Output));
+        Self {
+            globals,
+            graph: Graph::default(),
+        }
+    }
+}
+
+impl Session {
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+}
+
+/// A local slot of a running function.
+#[derive(Default)]
+enum Slot {
+    Value(Value),
+    /// A variable captured by a closure, which the function and the closure share.
+    Cell(Cell),
+    /// The iterator of a loop.
+    Iter(Box<dyn Iterator<Item = Value>>),
+    #[default]
+    Empty,
+}
+
+/// A call in progress.
+struct Frame {
+    function: Rc<Function>,
+    captures: Rc<[Cell]>,
+    /// The index of the next instruction.
+    ip: usize,
+    /// Where the slots of the call start.
+    slots: usize,
+    /// Where the stack of the call starts.
+    stack: usize,
+}
+
+/// The stack machine that runs compiled programs.
+///
+/// Values are computed on a single stack shared by all calls, and calls between compiled
+/// functions are made without recursing natively, so deep recursion only costs memory.
+/// Only builtins that call back into the program, such as `map`, nest native calls.
+pub struct Machine<'vm> {
+    vm: &'vm Vm,
+    globals: Globals,
+    stack: Vec<Value>,
+    slots: Vec<Slot>,
+    frames: Vec<Frame>,
+    /// How many calls from builtins are in progress.
+    nested_calls: usize,
+    pub(super) graph: Graph,
+    /// How many connections the graph had when the machine started.
+    resumed_connections: usize,
+}
+
+impl<'vm> Machine<'vm> {
+    pub fn new(vm: &'vm Vm) -> Self {
+        Self::resume(vm, Session::default())
+    }
+
+    /// Creates a machine that continues from the state an earlier one left behind.
+    pub fn resume(vm: &'vm Vm, session: Session) -> Self {
+        let Session { mut globals, graph } = session;
+        for (name, function) in &vm.functions {
+            globals.register(name, function.clone());
+        }
+        Self {
+            vm,
+            globals,
+            stack: Vec::new(),
+            slots: Vec::new(),
+            frames: Vec::new(),
+            nested_calls: 0,
+            resumed_connections: graph.connections.len(),
+            graph,
+        }
+    }
+
+    /// Stops the machine, keeping its globals and graph to resume from. Connections made
+    /// again since it resumed replace the earlier copies.
+    pub fn into_session(mut self) -> Session {
+        self.graph.replace_from(self.resumed_connections);
+        Session {
+            globals: self.globals,
+            graph: self.graph,
+        }
+    }
+
+    pub fn execute_str(&mut self, input: &str) -> Result<Value, RuntimeError> {
+        let syntax_tree = parse_str(input)?;
+        self.execute_syntax(syntax_tree)
+    }
+
+    pub fn execute_syntax(&mut self, syntax_tree: Vec<Syntax>) -> Result<Value, RuntimeError> {
+        let function = compile(&syntax_tree, &mut self.globals);
+        let depth = self.frames.len();
+        self.push_frame(Rc::new(function), Rc::from([]));
+        self.run(depth)
+    }
+
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    /// Calls a closure with already evaluated arguments and runs it to completion.
+    pub fn call(&mut self, callee: &Value, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        if self.nested_calls >= MAX_NESTED_CALLS {
+            return Err(self.stack_overflow(MAX_NESTED_CALLS));
+        }
+        let depth = self.frames.len();
+        let height = self.stack.len();
+        let count = arguments.len();
+        self.stack.extend(arguments);
+        if let Err(err) = self.invoke(callee.clone(), count, false) {
+            self.stack.truncate(height);
+            return Err(err);
+        }
+        self.nested_calls += 1;
+        let result = self.run(depth);
+        self.nested_calls -= 1;
+        result
+    }
+
+    /// Reports the innermost calls in progress, except the top level, which is not a call.
+    fn stack_overflow(&self, depth: usize) -> RuntimeError {
+        let start = self
+            .frames
+            .len()
+            .saturating_sub(STACK_OVERFLOW_FRAMES)
+            .max(1);
+        RuntimeError::StackOverflow {
+            depth,
+            frames: self.frames[start..]
+                .iter()
+                .map(|frame| frame.function.name.clone())
+                .collect(),
+        }
+    }
+
+    fn push_frame(&mut self, function: Rc<Function>, captures: Rc<[Cell]>) {
+        let slots = self.slots.len();
+        self.slots
+            .resize_with(slots + function.slots, Slot::default);
+        self.frames.push(Frame {
+            function,
+            captures,
+            ip: 0,
+            slots,
+            stack: self.stack.len(),
+        });
+    }
+
+    /// Runs instructions until the call at index `depth` of the frame stack returns.
+    fn run(&mut self, depth: usize) -> Result<Value, RuntimeError> {
+        loop {
+            match self.step(depth) {
+                Ok(None) => {}
+                Ok(Some(value)) => return Ok(value),
+                Err(err) => {
+                    let frame = self.frame();
+                    let ip = frame.ip - 1;
+                    let err = match (&err, frame.function.callees.get(&ip)) {
+                        (RuntimeError::NotCallable { .. }, Some(span)) => err.with_span(*span),
+                        _ => err.with_span(frame.function.spans[ip]),
+                    };
+                    let base = &self.frames[depth];
+                    self.slots.truncate(base.slots);
+                    self.stack.truncate(base.stack);
+                    self.frames.truncate(depth);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("no call in progress")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("no call in progress")
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn peek(&self) -> &Value {
+        self.stack.last().expect("stack underflow")
+    }
+
+    fn slot(&mut self, slot: u32) -> &mut Slot {
+        let index = self.frame().slots + slot as usize;
+        &mut self.slots[index]
+    }
+
+    /// Executes one instruction. Returns the result of the call at index `depth` once it
+    /// returns.
+    fn step(&mut self, depth: usize) -> Result<Option<Value>, RuntimeError> {
+        let frame = self.frame_mut();
+        let instruction = frame.function.code[frame.ip];
+        frame.ip += 1;
+
+        match instruction {
+            Instruction::Constant(index) => {
+                let value = self.frame().function.constants[index as usize].clone();
+                self.stack.push(value);
+            }
+            Instruction::Null => self.stack.push(Value::Null),
+            Instruction::Pop => {
+                self.pop();
+            }
+            Instruction::DefineLocal(slot) => {
+                let value = self.pop();
+                *self.slot(slot) = Slot::Value(value);
+            }
+            Instruction::GetLocal(slot) => {
+                let value = match self.slot(slot) {
+                    Slot::Value(value) => value.clone(),
+                    Slot::Cell(cell) => cell.borrow().clone(),
+                    Slot::Iter(_) | Slot::Empty => Value::Null,
+                };
+                self.stack.push(value);
+            }
+            Instruction::SetLocal(slot) => {
+                let value = self.peek().clone();
+                match self.slot(slot) {
+                    Slot::Cell(cell) => *cell.borrow_mut() = value,
+                    slot => *slot = Slot::Value(value),
+                }
+            }
+            Instruction::GetCapture(index) => {
+                let value = self.frame().captures[index as usize].borrow().clone();
+                self.stack.push(value);
+            }
+            Instruction::CurrentClosure => {
+                let closure = self.current_closure();
+                self.stack.push(closure);
+            }
+            Instruction::SetCapture(index) => {
+                let value = self.peek().clone();
+                *self.frame().captures[index as usize].borrow_mut() = value;
+            }
+            Instruction::GetGlobal(index) => {
+                let global = &self.globals.entries[index as usize];
+                match &global.variable {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => return Err(RuntimeError::UndefinedVariable(global.name.clone())),
+                }
+            }
+            Instruction::DefineGlobal(index) => {
+                let value = self.pop();
+                self.globals.entries[index as usize].variable = Some(value);
+            }
+            Instruction::SetGlobal(index) => {
+                let value = self.peek().clone();
+                let global = &mut self.globals.entries[index as usize];
+                match &mut global.variable {
+                    Some(variable) => *variable = value,
+                    None => return Err(RuntimeError::UndefinedVariable(global.name.clone())),
+                }
+            }
+            Instruction::DefineFunction(index) => {
+                let function = self.pop();
+                self.globals.entries[index as usize].function = Some(function);
+            }
+            Instruction::Jump(target) => self.frame_mut().ip = target as usize,
+            Instruction::JumpIfFalse(target) => {
+                if !self.pop().is_truthy() {
+                    self.frame_mut().ip = target as usize;
+                }
+            }
+            Instruction::JumpIfTrue(target) => {
+                if self.pop().is_truthy() {
+                    self.frame_mut().ip = target as usize;
+                }
+            }
+            Instruction::JumpIfFalseOrPop(target) => {
+                if self.peek().is_truthy() {
+                    self.pop();
+                } else {
+                    self.frame_mut().ip = target as usize;
+                }
+            }
+            Instruction::JumpIfTrueOrPop(target) => {
+                if self.peek().is_truthy() {
+                    self.frame_mut().ip = target as usize;
+                } else {
+                    self.pop();
+                }
+            }
+            Instruction::Call(arguments) | Instruction::TailCall(arguments) => {
+                let callee = self.stack.remove(self.stack.len() - arguments as usize - 1);
+                let tail = matches!(instruction, Instruction::TailCall(_));
+                self.invoke(callee, arguments as usize, tail)?;
+            }
+            Instruction::CallGlobal { global, arguments }
+            | Instruction::TailCallGlobal { global, arguments } => {
+                let tail = matches!(instruction, Instruction::TailCallGlobal { .. });
+                self.call_global(global, arguments as usize, tail)?;
+            }
+            Instruction::GetOrElse { global, found } => {
+                let global = &self.globals.entries[global as usize];
+                // Only the builtin `get` can be skipped; the host may have replaced it.
+                if global.variable.is_none()
+                    && global.function.is_none()
+                    && matches!(global.builtin, Some(Builtin::Native(_)))
+                {
+                    let [map, key] = &self.stack[self.stack.len() - 2..] else {
+                        unreachable!("get without a map and key");
+                    };
+                    let Value::Map(map) = map else {
+                        return Err(RuntimeError::TypeError {
+                            expected: ValueType::Map,
+                            found: map.value_type(),
+                        });
+                    };
+                    if let Some(value) = map.get(&MapKey::from_value(key.clone())?) {
+                        let value = value.clone();
+                        self.stack.truncate(self.stack.len() - 2);
+                        self.stack.push(value);
+                        self.frame_mut().ip = found as usize;
+                    }
+                }
+            }
+            Instruction::Return => {
+                let value = self.pop();
+                let frame = self.frames.pop().expect("no call in progress");
+                self.slots.truncate(frame.slots);
+                self.stack.truncate(frame.stack);
+                if self.frames.len() == depth {
+                    return Ok(Some(value));
+                }
+                self.stack.push(value);
+            }
+            Instruction::Closure(index) => {
+                let function = self.frame().function.functions[index as usize].clone();
+                let captures = function
+                    .captures
+                    .iter()
+                    .map(|capture| match capture {
+                        Capture::Local(slot) => self.capture(*slot),
+                        Capture::Enclosing(index) => self.frame().captures[*index as usize].clone(),
+                        Capture::Current => Rc::new(RefCell::new(self.current_closure())),
+                    })
+                    .collect();
+                self.stack.push(Value::Closure(Rc::new(Closure::Compiled {
+                    function,
+                    captures,
+                })));
+            }
+            Instruction::List(count) => {
+                let values = self.stack.split_off(self.stack.len() - count as usize);
+                self.stack.push(Value::List(values));
+            }
+            Instruction::Map => self.stack.push(Value::Map(BTreeMap::new())),
+            Instruction::MapInsert => {
+                let value = self.pop();
+                let key = MapKey::from_value(self.pop())?;
+                if let Some(Value::Map(map)) = self.stack.last_mut() {
+                    map.insert(key, value);
+                }
+            }
+            Instruction::ListPush => {
+                let value = self.pop();
+                if let Some(Value::List(list)) = self.stack.last_mut() {
+                    list.push(value);
+                }
+            }
+            Instruction::ListExtend => match self.pop() {
+                Value::List(values) => {
+                    if let Some(Value::List(list)) = self.stack.last_mut() {
+                        list.extend(values);
+                    }
+                }
+                value => {
+                    return Err(RuntimeError::TypeError {
+                        expected: ValueType::List,
+                        found: value.value_type(),
+                    });
+                }
+            },
+            Instruction::Binary(op) => {
+                let right = self.pop();
+                let left = self.pop();
+                self.stack.push(op.apply(&left, &right)?);
+            }
+            Instruction::IterCount(slot) => {
+                let value = self.pop();
+                let Some(count) = value.as_scalar() else {
+                    return Err(RuntimeError::TypeError {
+                        expected: ValueType::Number,
+                        found: value.value_type(),
+                    });
+                };
+                let range = Range::new(0.0, count.to_f64(), 1.0).expect("step is not zero");
+                *self.slot(slot) = Slot::Iter(Box::new(range.iter().map(Value::Number)));
+            }
+            Instruction::IterSequence(slot) => {
+                let sequence = self.pop().into_sequence()?;
+                *self.slot(slot) = Slot::Iter(sequence);
+            }
+            Instruction::Next { slot, done } => {
+                let next = match self.slot(slot) {
+                    Slot::Iter(iter) => iter.next(),
+                    _ => None,
+                };
+                match next {
+                    Some(value) => self.stack.push(value),
+                    None => {
+                        *self.slot(slot) = Slot::Empty;
+                        self.frame_mut().ip = done as usize;
+                    }
+                }
+            }
+            Instruction::Unwind(height) => {
+                let value = self.pop();
+                self.stack.truncate(self.frame().stack + height as usize);
+                self.stack.push(value);
+            }
+            Instruction::Truncate(height) => {
+                self.stack.truncate(self.frame().stack + height as usize);
+            }
+            Instruction::Raise(index) => {
+                return Err(self.frame().function.errors[index as usize].clone());
+            }
+        }
+        Ok(None)
+    }
+
+    /// Turns a local slot into a cell that a closure can share, if it is not one already.
+    /// Returns the closure of the running call, made anew from its function and captures.
+    fn current_closure(&self) -> Value {
+        let frame = self.frame();
+        Value::Closure(Rc::new(Closure::Compiled {
+            function: frame.function.clone(),
+            captures: frame.captures.clone(),
+        }))
+    }
+
+    fn capture(&mut self, slot: u32) -> Cell {
+        let slot = self.slot(slot);
+        match slot {
+            Slot::Cell(cell) => cell.clone(),
+            slot => {
+                let value = match std::mem::take(slot) {
+                    Slot::Value(value) => value,
+                    _ => Value::Null,
+                };
+                let cell = Rc::new(RefCell::new(value));
+                *slot = Slot::Cell(cell.clone());
+                cell
+            }
+        }
+    }
+
+    fn call_global(
+        &mut self,
+        index: u32,
+        arguments: usize,
+        tail: bool,
+    ) -> Result<(), RuntimeError> {
+        let global = &self.globals.entries[index as usize];
+        if let Some(callee) = global.variable.as_ref().or(global.function.as_ref()) {
+            return self.invoke(callee.clone(), arguments, tail);
+        }
+        let Some(builtin) = global.builtin.clone() else {
+            return Err(RuntimeError::UndefinedIdentifier(global.name.clone()));
+        };
+        let arguments = self.stack.split_off(self.stack.len() - arguments);
+        let result = match builtin {
+            Builtin::Native(native) => native(self, arguments)?,
+            Builtin::Host(function) => function(&arguments)?,
+        };
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Calls a value with the given number of arguments from the top of the stack. A
+    /// closure is entered, so its body runs as execution continues; in tail position, it
+    /// replaces the current call.
+    fn invoke(&mut self, callee: Value, arguments: usize, tail: bool) -> Result<(), RuntimeError> {
+        let (function, captures) = match &callee {
+            Value::Closure(closure) => match closure.as_ref() {
+                Closure::Compiled { function, captures } => (function.clone(), captures.clone()),
+                #[cfg(feature = "tree-walker")]
+                Closure::Interpreted { .. } => {
+                    return Err(RuntimeError::NotCallable {
+                        found: ValueType::Closure,
+                    });
+                }
+            },
+            value => {
+                return Err(RuntimeError::NotCallable {
+                    found: value.value_type(),
+                });
+            }
+        };
+        if function.parameters.len() != arguments {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: function.parameters.len(),
+                found: arguments,
+            });
+        }
+
+        let values = self.stack.split_off(self.stack.len() - arguments);
+        if tail {
+            let frame = self.frame();
+            let (slots, stack) = (frame.slots, frame.stack);
+            self.slots.truncate(slots);
+            self.stack.truncate(stack);
+            self.frames.pop();
+        } else {
+            // The top level is not a call, so it does not count towards the depth.
+            let depth = self.frames.len() - 1;
+            if depth >= self.vm.max_call_depth() {
+                return Err(self.stack_overflow(self.vm.max_call_depth()));
+            }
+        }
+        let slots = self.slots.len();
+        self.push_frame(function, captures);
+        for (slot, value) in self.slots[slots..].iter_mut().zip(values) {
+            *slot = Slot::Value(value);
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,28 @@
 use std::collections::HashMap;
 
+use compiler::SPECIAL_FORMS;
+use host::{HostFunction, IntoHostFunction};
+use machine::{Machine, Session};
 use thiserror::Error;
 use value::{Value, ValueType};
 
 use crate::{
     lexer::LexingError,
     parser::{
-        ParsingError, parse_str,
+        ParsingError,
         syntax::{Syntax, SyntaxType},
     },
+    span::Span,
 };
 
-pub mod builtins;
+pub mod bytecode;
+pub mod compiler;
+pub mod graph;
+pub mod host;
+pub mod machine;
+pub mod natives;
+#[cfg(feature = "tree-walker")]
+pub mod tree_walker;
 pub mod value;
 
 #[derive(Debug, Clone, PartialEq, Error)]
@@ -25,6 +36,15 @@ pub enum RuntimeError {
     #[error("Invalid argument count: expected {expected}, found {found}")]
     InvalidArgumentCount { expected: usize, found: usize },
 
+    /// For forms with an optional argument, such as `if`, which take `min` or `max`
+    /// arguments.
+    #[error("Invalid argument count: expected {min} or {max}, found {found}")]
+    InvalidOptionalArgumentCount {
+        min: usize,
+        max: usize,
+        found: usize,
+    },
+
     #[error("Syntax error: expected {expected:?}, found {found:?}")]
     SyntaxError {
         expected: SyntaxType,
@@ -49,6 +69,12 @@ pub enum RuntimeError {
     #[error("Undefined identifier: {0}")]
     UndefinedIdentifier(String),
 
+    #[error("Cannot register a function named {0}, which is a special form")]
+    SpecialFormName(String),
+
+    #[error("Cannot call a value of type {found:?}")]
+    NotCallable { found: ValueType },
+
     #[error("Division by zero")]
     DivisionByZero,
 
@@ -62,23 +88,103 @@ pub enum RuntimeError {
     #[error("Cannot convert to graph node: {0:?}")]
     CannotConvertToNode(ValueType),
 
+    #[error("Note out of range: MIDI number {0}")]
+    NoteOutOfRange(f64),
+
+    #[error("Unknown chord quality: {0}")]
+    UnknownChordQuality(String),
+
+    #[error("Cannot patch into {0:?}, expected an output or bus")]
+    InvalidPatchTarget(ValueType),
+
+    #[error("{0} used outside of quasiquote")]
+    UnquoteOutsideQuasiquote(String),
+
+    #[error("{0} used outside of a loop")]
+    OutsideLoop(String),
+
+    #[error("Range step cannot be zero")]
+    ZeroRangeStep,
+
+    #[error("{0} cannot be applied to an unbounded range")]
+    UnboundedRange(String),
+
+    #[error("Stack overflow: calls nested deeper than {depth}")]
+    StackOverflow {
+        depth: usize,
+        /// The names of the innermost calls, outermost first.
+        frames: Vec<String>,
+    },
+
     #[error("scope error: {0}")]
     Other(String),
+
+    #[error("{error}")]
+    Spanned {
+        error: Box<RuntimeError>,
+        span: Span,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct FunctionDef {
-    pub name: String,
-    pub parameters: Vec<String>,
-    pub body: Syntax,
+impl RuntimeError {
+    /// Attaches a source location to the error, unless it already has one.
+    pub fn with_span(self, span: Span) -> Self {
+        if self.span().is_some() {
+            return self;
+        }
+        RuntimeError::Spanned {
+            error: Box::new(self),
+            span,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            RuntimeError::LexingError(err) => Some(err.span()),
+            RuntimeError::ParsingError(err) => Some(err.span()),
+            RuntimeError::Spanned { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// Returns the error without any attached source location.
+    pub fn inner(&self) -> &RuntimeError {
+        match self {
+            RuntimeError::Spanned { error, .. } => error.inner(),
+            err => err,
+        }
+    }
 }
 
 pub fn execute_str(input: &str) -> Result<Value, RuntimeError> {
     Vm::default().execute_str(input)
 }
 
-#[derive(Clone, PartialEq)]
-pub struct Vm {}
+/// How deeply calls can nest before [`RuntimeError::StackOverflow`] is raised, unless
+/// configured with [`Vm::with_max_call_depth`].
+///
+/// Calls between compiled functions do not nest natively, so this only bounds the memory
+/// a runaway recursion can take. Calls from builtins back into the program do, and are
+/// limited separately by [`MAX_NESTED_CALLS`].
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// How many calls from builtins back into the program, such as those `map` makes, can be
+/// in progress at once. Each of them recurses natively, so the limit is kept low enough for
+/// unoptimized builds to stay within the 2 MiB stack Rust gives spawned threads.
+pub const MAX_NESTED_CALLS: usize = 100;
+
+/// How many of the innermost calls a [`RuntimeError::StackOverflow`] reports.
+pub(crate) const STACK_OVERFLOW_FRAMES: usize = 8;
+
+/// Runs programs, either one at a time with [`Vm::execute_str`], or as a session with
+/// [`Vm::eval`], where each evaluation continues from the definitions and connections the
+/// previous ones left behind.
+#[derive(Clone)]
+pub struct Vm {
+    max_call_depth: usize,
+    functions: HashMap<String, HostFunction>,
+    session: Session,
+}
 
 impl Default for Vm {
     fn default() -> Self {
@@ -88,147 +194,1176 @@ impl Default for Vm {
 
 impl Vm {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            functions: HashMap::new(),
+            session: Session::default(),
+        }
+    }
+
+    /// Sets how deeply calls of functions and closures can nest. Calls in tail position do
+    /// not count, as they replace the caller's frame.
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth
+    }
+
+    /// Registers a function of the host application, which programs call by name like a
+    /// builtin. It replaces any builtin or previously registered function of that name, and
+    /// is shadowed by functions and variables the program defines. Special forms such as
+    /// `if` cannot be replaced, so their names fail with [`RuntimeError::SpecialFormName`].
+    ///
+    /// The function either takes the evaluated arguments as a slice, or typed arguments
+    /// that are checked against the call, as described in [`IntoHostFunction`]:
+    ///
+    /// ```
+    /// # use callisto_interpreter::vm::{RuntimeError, Vm, value::Value};
+    /// let mut vm = Vm::new();
+    /// vm.register_fn("count", |arguments: &[Value]| Ok(arguments.len() as f64))?
+    ///     .register_fn("double", |x: f64| Ok(x * 2.0))?;
+    /// assert_eq!(vm.execute_str("(double (count 1 2))"), Ok(Value::Number(4.0)));
+    /// # Ok::<(), RuntimeError>(())
+    /// ```
+    pub fn register_fn<Args: ?Sized>(
+        &mut self,
+        name: &str,
+        function: impl IntoHostFunction<Args>,
+    ) -> Result<&mut Self, RuntimeError> {
+        if SPECIAL_FORMS.contains(&name) {
+            return Err(RuntimeError::SpecialFormName(name.to_string()));
+        }
+        self.functions
+            .insert(name.to_string(), function.into_host_function());
+        Ok(self)
     }
 
+    /// Runs a program on its own, without the session's definitions and without
+    /// changing them.
     pub fn execute_str(&self, input: &str) -> Result<Value, RuntimeError> {
-        Scope::new(self).execute_str(input)
+        Machine::new(self).execute_str(input)
+    }
+
+    pub fn execute_syntax(&self, syntax_tree: Vec<Syntax>) -> Result<Value, RuntimeError> {
+        Machine::new(self).execute_syntax(syntax_tree)
+    }
+
+    /// Runs a program in the session, where it can use and redefine what earlier
+    /// evaluations defined. Connections it makes that an earlier evaluation already made
+    /// replace them rather than being added twice, so a block can be evaluated again after
+    /// editing it. Whatever it defines or connects before an error is kept.
+    pub fn eval(&mut self, input: &str) -> Result<Value, RuntimeError> {
+        let session = std::mem::take(&mut self.session);
+        let mut machine = Machine::resume(self, session);
+        let result = machine.execute_str(input);
+        self.session = machine.into_session();
+        result
+    }
+
+    /// The definitions and connections evaluated so far.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Discards everything evaluated so far. Registered functions are kept.
+    pub fn reset(&mut self) {
+        self.session = Session::default();
+    }
+
+    /// Saves the session, to [`restore`](Vm::restore) it after evaluations that went wrong.
+    /// The variables closures captured are saved too, so restoring rolls them back.
+    ///
+    /// Running or scheduled tasks are not saved. The language cannot schedule any yet, and
+    /// whatever the host application schedules through registered functions is its own to
+    /// keep, so restoring does not cancel it.
+    pub fn snapshot(&self) -> Session {
+        self.session.clone()
     }
-}
 
-#[derive(Clone, PartialEq)]
-pub struct Scope<'vm> {
-    pub vm: &'vm Vm,
-    pub variables: HashMap<String, Value>,
-    pub functions: HashMap<String, FunctionDef>,
-    pub call_stack: Vec<String>,
+    pub fn restore(&mut self, snapshot: &Session) {
+        self.session = snapshot.clone();
+    }
 }
 
-impl<'vm> Scope<'vm> {
-    pub fn new(vm: &'vm Vm) -> Self {
-        Self {
-            vm,
-            variables: HashMap::new(),
-            functions: HashMap::new(),
-            call_stack: Vec::new(),
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        note::Note,
+        number::{Rational, Scalar},
+        quantity::{Quantity, Unit},
+        vm::{graph::Node, value::MapKey},
+    };
+
+    /// Runs a program with the machine and, when it is built, checks that the tree walker
+    /// agrees with it on the result. Where errors are reported may differ.
+    fn execute_str(input: &str) -> Result<Value, RuntimeError> {
+        let vm = Vm::new();
+        let result = vm.execute_str(input);
+        #[cfg(feature = "tree-walker")]
+        {
+            let expected = tree_walker::Scope::new(&vm).execute_str(input);
+            let printed = |result: &Result<Value, RuntimeError>| {
+                (result.as_ref())
+                    .map(ToString::to_string)
+                    .map_err(|err| err.inner().clone())
+            };
+            assert_eq!(printed(&result), printed(&expected), "{input}");
         }
+        result
     }
 
-    pub fn execute_str(&mut self, input: &str) -> Result<Value, RuntimeError> {
-        let syntax_tree = parse_str(input)?;
-        let mut result = Value::Null;
-        for syntax in syntax_tree {
-            result = self.execute(syntax)?;
-        }
-        Ok(result)
-    }
-
-    pub fn execute(&mut self, syntax: Syntax) -> Result<Value, RuntimeError> {
-        match syntax {
-            Syntax::Number(value) => Ok(Value::Number(value)),
-            Syntax::Boolean(value) => Ok(Value::Boolean(value)),
-            Syntax::Symbol(value) => Ok(Value::Symbol(value)),
-            Syntax::String(value) => Ok(Value::String(value)),
-            Syntax::Identifier(name) => {
-                if let Some(value) = self.variables.get(&name) {
-                    return Ok(value.clone());
-                }
-                Err(RuntimeError::UndefinedVariable(name))
+    #[test]
+    fn test_runtime_error_span() {
+        let err = execute_str("(define x 1)\n(+ x :foo)").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(13, 23, 2, 1)));
+        assert!(matches!(
+            err.inner(),
+            RuntimeError::InvalidOperation { operation, .. } if operation == "+"
+        ));
+    }
+
+    #[test]
+    fn test_undefined_variable_span() {
+        let err = execute_str("(+ 1\n  foo)").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(7, 10, 2, 3)));
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::UndefinedVariable("foo".to_string())
+        );
+    }
+
+    #[test]
+    fn test_string_literal() {
+        assert_eq!(
+            execute_str(r#"(+ "foo" "bar")"#),
+            Ok(Value::String("foobar".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_patch_to_dac() {
+        let vm = Vm::new();
+        let mut machine = Machine::new(&vm);
+        let input = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../examples/hello.callisto"
+        ))
+        .unwrap();
+        assert_eq!(machine.execute_str(&input), Ok(Value::Null));
+
+        let mix = Node::Mul(
+            Box::new(Node::Constant(0.5)),
+            Box::new(Node::Oscillator {
+                waveform: graph::Waveform::Sine,
+                frequency: Box::new(Node::Constant(440.0)),
+            }),
+        );
+        assert_eq!(
+            machine.graph().connections,
+            vec![
+                graph::Connection {
+                    source: mix.clone(),
+                    target: Node::Output,
+                    channel: 0,
+                },
+                graph::Connection {
+                    source: mix,
+                    target: Node::Output,
+                    channel: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_serial_chain() {
+        let vm = Vm::new();
+        let mut machine = Machine::new(&vm);
+        let result = machine
+            .execute_str("(>> (saw 110) (lpf 800) (bus :fx) (hpf 40) dac)")
+            .unwrap();
+
+        let filtered = Node::Filter {
+            kind: graph::FilterKind::LowPass,
+            cutoff: Box::new(Node::Constant(800.0)),
+            input: Some(Box::new(Node::Oscillator {
+                waveform: graph::Waveform::Saw,
+                frequency: Box::new(Node::Constant(110.0)),
+            })),
+        };
+        let output = Node::Filter {
+            kind: graph::FilterKind::HighPass,
+            cutoff: Box::new(Node::Constant(40.0)),
+            input: Some(Box::new(filtered.clone())),
+        };
+        assert_eq!(result, Value::Node(output.clone()));
+        assert_eq!(
+            machine.graph().connections,
+            vec![
+                graph::Connection {
+                    source: filtered,
+                    target: Node::Bus("fx".to_string()),
+                    channel: 0,
+                },
+                graph::Connection {
+                    source: output,
+                    target: Node::Output,
+                    channel: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patch_errors() {
+        assert_eq!(
+            execute_str("(~ 440 (sine 440))").unwrap_err().inner(),
+            &RuntimeError::InvalidPatchTarget(ValueType::Number)
+        );
+        assert_eq!(
+            execute_str("(~ dac :foo)").unwrap_err().inner(),
+            &RuntimeError::CannotConvertToNode(ValueType::Symbol)
+        );
+        assert_eq!(
+            execute_str("(~ dac dac)").unwrap_err().inner(),
+            &RuntimeError::CannotConvertToNode(ValueType::Node)
+        );
+    }
+
+    #[test]
+    fn test_note_values() {
+        let note = |name: &str| Value::Note(name.parse().unwrap());
+        assert_eq!(execute_str(":C#4"), Ok(note("C#4")));
+        assert_eq!(execute_str("(midi :C#4)"), Ok(Value::Number(61.0)));
+        assert_eq!(execute_str("(octave :Bb-1)"), Ok(Value::Number(-1.0)));
+        assert_eq!(execute_str("(pitch_class :Bb2)"), Ok(Value::Number(10.0)));
+        assert_eq!(execute_str("(freq :A4)"), Ok(Value::Number(440.0)));
+        assert_eq!(execute_str("(note 60)"), Ok(note("C4")));
+        assert_eq!(execute_str("(+ :C4 7)"), Ok(note("G4")));
+        assert_eq!(execute_str("(- :C4 1)"), Ok(note("B3")));
+        assert_eq!(execute_str("(- :G4 :C4)"), Ok(Value::Number(7.0)));
+
+        let out_of_range = |source: &str| execute_str(source).unwrap_err().inner().clone();
+        assert_eq!(
+            out_of_range("(+ :C4 3000000000)"),
+            RuntimeError::NoteOutOfRange(3000000060.0)
+        );
+        assert_eq!(
+            out_of_range("(- :C4 100000)"),
+            RuntimeError::NoteOutOfRange(-99940.0)
+        );
+        assert_eq!(
+            out_of_range("(note 100000)"),
+            RuntimeError::NoteOutOfRange(100000.0)
+        );
+        assert_eq!(
+            out_of_range("(note -1e10)"),
+            RuntimeError::NoteOutOfRange(-1e10)
+        );
+        assert_eq!(
+            out_of_range("(chord (note 1543) :maj)"),
+            RuntimeError::NoteOutOfRange(1550.0)
+        );
+    }
+
+    #[test]
+    fn test_chord() {
+        let note = |name: &str| Value::Note(name.parse().unwrap());
+        assert_eq!(
+            execute_str("(chord :C4 :maj7)"),
+            Ok(Value::List(vec![
+                note("C4"),
+                note("E4"),
+                note("G4"),
+                note("B4")
+            ]))
+        );
+        assert_eq!(
+            execute_str("(chord :E2 :min)"),
+            Ok(Value::List(vec![note("E2"), note("G2"), note("B2")]))
+        );
+        assert_eq!(
+            execute_str("(chord :E2 :foo)").unwrap_err().inner(),
+            &RuntimeError::UnknownChordQuality("foo".to_string())
+        );
+        assert_eq!(
+            execute_str("(chord 40 :min)").unwrap_err().inner(),
+            &RuntimeError::TypeError {
+                expected: ValueType::Note,
+                found: ValueType::Number,
             }
-            Syntax::List(elements) => {
-                if elements.is_empty() {
-                    return Ok(Value::Null);
-                }
-                let first = elements[0].clone();
-
-                if let Syntax::Identifier(name) | Syntax::Operator(name) = first {
-                    if let Some(value) = self.variables.get(&name) {
-                        return Ok(value.clone());
-                    }
-
-                    self.call_stack.push(name.clone());
-                    let result = self.execute_builtin_function(&name, &elements[1..]);
-                    self.call_stack.pop();
-                    match result {
-                        Ok(value) => return Ok(value),
-                        Err(RuntimeError::UndefinedFunction(_)) => {}
-                        Err(e) => return Err(e),
-                    }
-
-                    self.call_stack.push(name.clone());
-                    let result = self.execute_function(&name, &elements[1..]);
-                    self.call_stack.pop();
-                    match result {
-                        Ok(value) => return Ok(value),
-                        Err(RuntimeError::UndefinedFunction(_)) => {}
-                        Err(e) => return Err(e),
-                    }
-
-                    Err(RuntimeError::UndefinedIdentifier(name))
-                } else {
-                    let mut values = Vec::new();
-                    for element in elements {
-                        let value = self.execute(element)?;
-                        values.push(value);
-                    }
-                    Ok(Value::List(values))
-                }
+        );
+    }
+
+    #[test]
+    fn test_signed_and_scientific_numbers() {
+        assert_eq!(execute_str("(- 5 -3)"), Ok(Value::Number(8.0)));
+        assert_eq!(execute_str("(+ 0x7F 1e-3)"), Ok(Value::Number(127.001)));
+        assert_eq!(execute_str("(* .5 1_000)"), Ok(Value::Number(500.0)));
+    }
+
+    #[test]
+    fn test_rational_arithmetic() {
+        let rational = |numer, denom| Value::Rational(Rational::new(numer, denom).unwrap());
+        assert_eq!(execute_str("(+ 1/4 1/12)"), Ok(rational(1, 3)));
+        assert_eq!(execute_str("(* 3/4 4)"), Ok(rational(3, 1)));
+        assert_eq!(execute_str("(- 1 1/16)"), Ok(rational(15, 16)));
+        assert_eq!(execute_str("(/ 1/2 3)"), Ok(rational(1, 6)));
+        assert_eq!(execute_str("(+ 1/2 0.25)"), Ok(Value::Number(0.75)));
+        assert_eq!(
+            execute_str("(/ 1/2 0)").unwrap_err().inner(),
+            &RuntimeError::DivisionByZero
+        );
+    }
+
+    #[test]
+    fn test_quantity_arithmetic() {
+        let quantity = |numer, denom, unit| {
+            Value::Quantity(Quantity::new(
+                Scalar::Exact(Rational::new(numer, denom).unwrap()),
+                unit,
+            ))
+        };
+        assert_eq!(
+            execute_str("(+ 1/4b 1/8b)"),
+            Ok(quantity(3, 8, Unit::Beats))
+        );
+        assert_eq!(
+            execute_str("(+ 1s 250ms)"),
+            Ok(quantity(1250, 1, Unit::Milliseconds))
+        );
+        assert_eq!(execute_str("(* 3 1/16b)"), Ok(quantity(3, 16, Unit::Beats)));
+        assert_eq!(execute_str("(/ 1bar 4)"), Ok(quantity(1, 4, Unit::Bars)));
+        assert_eq!(
+            execute_str("(/ 1/2b 1/8b)"),
+            Ok(Value::Rational(Rational::from_integer(4)))
+        );
+        assert_eq!(
+            execute_str("(+ 1b 1s)").unwrap_err().inner(),
+            &RuntimeError::InvalidOperation {
+                operation: "+".to_string(),
+                left: ValueType::Quantity,
+                right: ValueType::Quantity,
             }
-            syntax => Err(RuntimeError::InvalidSyntax(syntax.syntax_type())),
-        }
+        );
     }
 
-    fn execute_function(
-        &mut self,
-        function: &str,
-        arguments: &[Syntax],
-    ) -> Result<Value, RuntimeError> {
-        let function = self.get_function(function)?;
-
-        if function.parameters.len() != arguments.len() {
-            return Err(RuntimeError::InvalidArgumentCount {
-                expected: function.parameters.len(),
-                found: arguments.len(),
-            });
+    #[test]
+    fn test_frequency_as_signal() {
+        assert_eq!(
+            execute_str("(sine 440hz)"),
+            Ok(Value::Node(Node::Oscillator {
+                waveform: graph::Waveform::Sine,
+                frequency: Box::new(Node::Constant(440.0)),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        let notes = ["C4", "E4", "G4"].map(|note| Value::Note(note.parse().unwrap()));
+        assert_eq!(
+            execute_str("'(:C4 :E4 :G4)"),
+            Ok(Value::List(notes.to_vec()))
+        );
+        assert_eq!(
+            execute_str("'(f x 1.5)"),
+            Ok(Value::List(vec![
+                Value::Symbol("f".to_string()),
+                Value::Symbol("x".to_string()),
+                Value::Number(1.5),
+            ]))
+        );
+        assert_eq!(execute_str("(quote x)"), Ok(Value::Symbol("x".to_string())));
+        assert_eq!(execute_str("'()"), Ok(Value::List(vec![])));
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        let number = |n: f64| Value::Number(n);
+        assert_eq!(
+            execute_str("(define x 2) (define xs '(3 4)) `(1 ,x ,@xs 5)"),
+            Ok(Value::List(vec![
+                number(1.0),
+                number(2.0),
+                number(3.0),
+                number(4.0),
+                number(5.0),
+            ]))
+        );
+        assert_eq!(
+            execute_str("(define x 2) `(a `(b ,(c ,x)))"),
+            Ok(Value::List(vec![
+                Value::Symbol("a".to_string()),
+                Value::List(vec![
+                    Value::Symbol("quasiquote".to_string()),
+                    Value::List(vec![
+                        Value::Symbol("b".to_string()),
+                        Value::List(vec![
+                            Value::Symbol("unquote".to_string()),
+                            Value::List(vec![Value::Symbol("c".to_string()), number(2.0)]),
+                        ]),
+                    ]),
+                ]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_unquote_errors() {
+        assert_eq!(
+            execute_str(",x").unwrap_err().inner(),
+            &RuntimeError::UnquoteOutsideQuasiquote("unquote".to_string())
+        );
+        assert_eq!(
+            execute_str("`,@'(1)").unwrap_err().inner(),
+            &RuntimeError::UnquoteOutsideQuasiquote("unquote_splicing".to_string())
+        );
+        let err = execute_str("`(1 ,@2)").unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::TypeError {
+                expected: ValueType::List,
+                found: ValueType::Number,
+            }
+        );
+        assert_eq!(err.span(), Some(Span::new(6, 7, 1, 7)));
+    }
+
+    fn map(entries: &[(&str, Value)]) -> Value {
+        Value::Map(
+            entries
+                .iter()
+                .map(|(key, value)| (MapKey::Symbol(key.to_string()), value.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_vector_literal() {
+        assert_eq!(
+            execute_str("(define x 2) [1 x (+ x 1)]"),
+            Ok(Value::List(vec![
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(3.0),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_map_literal() {
+        assert_eq!(
+            execute_str("{:cutoff (* 2 400) :res 0.3}"),
+            Ok(map(&[
+                ("cutoff", Value::Number(800.0)),
+                ("res", Value::Number(0.3)),
+            ]))
+        );
+        assert_eq!(
+            execute_str("{\"name\" 1}"),
+            Ok(Value::Map(
+                [(MapKey::String("name".to_string()), Value::Number(1.0))].into()
+            ))
+        );
+        assert_eq!(execute_str("'{a [b]}"), execute_str("{:a [:b]}"));
+        assert_eq!(
+            execute_str("(define m {:A4 440 :C#3 138.6 :Db3 138.6}) [(get m :A4) (keys m)]"),
+            execute_str("[440 [:C#3 :Db3 :A4]]")
+        );
+        assert_eq!(
+            execute_str("{:D2 1 :x 2}").map(|value| value.to_string()),
+            Ok("{:x 2 :D2 1}".to_string())
+        );
+        let err = execute_str("{:a 1 2 3}").unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::TypeError {
+                expected: ValueType::Symbol,
+                found: ValueType::Number,
+            }
+        );
+        assert_eq!(err.span(), Some(Span::new(6, 7, 1, 7)));
+    }
+
+    #[test]
+    fn test_map_builtins() {
+        let setup = "(define m {:cutoff 800 :res 0.3}) ";
+        let run = |code: &str| execute_str(&format!("{setup}{code}"));
+        assert_eq!(run("(get m :cutoff)"), Ok(Value::Number(800.0)));
+        assert_eq!(run("(get m :drive)"), Ok(Value::Null));
+        assert_eq!(run("(get m :drive 1)"), Ok(Value::Number(1.0)));
+        assert_eq!(
+            run("(assoc m :res 0.5 :drive 2)"),
+            Ok(map(&[
+                ("cutoff", Value::Number(800.0)),
+                ("drive", Value::Number(2.0)),
+                ("res", Value::Number(0.5)),
+            ]))
+        );
+        assert_eq!(
+            run("(dissoc m :res :drive)"),
+            Ok(map(&[("cutoff", Value::Number(800.0))]))
+        );
+        assert_eq!(
+            run("(keys m)"),
+            Ok(Value::List(vec![
+                Value::Symbol("cutoff".to_string()),
+                Value::Symbol("res".to_string()),
+            ]))
+        );
+        assert_eq!(
+            run("(vals m)"),
+            Ok(Value::List(vec![Value::Number(800.0), Value::Number(0.3)]))
+        );
+        assert_eq!(
+            run("(merge m {:res 0.9} {:drive 1})"),
+            Ok(map(&[
+                ("cutoff", Value::Number(800.0)),
+                ("drive", Value::Number(1.0)),
+                ("res", Value::Number(0.9)),
+            ]))
+        );
+        assert_eq!(run("(merge)"), Ok(map(&[])));
+        assert_eq!(
+            run("(get [1] :a)").unwrap_err().inner(),
+            &RuntimeError::TypeError {
+                expected: ValueType::Map,
+                found: ValueType::List,
+            }
+        );
+        assert_eq!(
+            run("(assoc m :res)").unwrap_err().inner(),
+            &RuntimeError::InvalidArgumentCount {
+                expected: 3,
+                found: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_quasiquote_collections() {
+        assert_eq!(
+            execute_str("(define x 2) `{:a ,x :b [,@'(1 2)]}"),
+            Ok(map(&[
+                ("a", Value::Number(2.0)),
+                (
+                    "b",
+                    Value::List(vec![Value::Number(1.0), Value::Number(2.0)])
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_let_statement() {
+        assert_eq!(
+            execute_str("let foo = 5\nlet bar = (* foo 2)\nbar"),
+            Ok(Value::Number(10.0))
+        );
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        assert_eq!(
+            execute_str("let foo = 5 (+= foo 2) (*= foo 3) (-= foo 1) (/= foo 4) foo"),
+            Ok(Value::Number(5.0))
+        );
+        assert_eq!(execute_str("let t = 1/4b (+= t 1/8b)"), execute_str("3/8b"));
+        let err = execute_str("(+= missing 1)").unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::UndefinedVariable("missing".to_string())
+        );
+        assert_eq!(err.span(), Some(Span::new(0, 14, 1, 1)));
+    }
+
+    #[test]
+    fn test_display_values() {
+        let display = |source: &str| execute_str(source).unwrap().to_string();
+        assert_eq!(display("(+ 40 2)"), "42");
+        assert_eq!(display("0.5"), "0.5");
+        assert_eq!(display("(/ 3 4/1)"), "3/4");
+        assert_eq!(display("3/4b"), "3/4b");
+        assert_eq!(
+            display(r#""a \"quoted\"\ntext""#),
+            r#""a \"quoted\"\ntext""#
+        );
+        assert_eq!(display("'(:C4 :E4 foo +)"), "(:C4 :E4 :foo '+)");
+        assert_eq!(display(r#"{:b [1 2] "a" true}"#), r#"{:b (1 2) "a" true}"#);
+        assert_eq!(display("(>> (saw 110) (lpf 800))"), "(lpf 800 (saw 110))");
+        assert_eq!(display("(bus :fx)"), "(bus :fx)");
+        assert_eq!(display("()"), "()");
+    }
+
+    #[test]
+    fn test_recursion() {
+        // `get` only evaluates its default when the key is missing, which is enough to end
+        // the recursion at the innermost map.
+        let source = "
+            (func depth (m) (get m :end (+ 1 (depth (get m :next)))))
+            (depth {:next {:next {:next {:end 0}}}})";
+        assert_eq!(execute_str(source), Ok(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_local_recursion() -> Result<(), RuntimeError> {
+        let make = "
+            (func make ()
+              (do
+                (func count (n) (if (<= n 0) 0 (+ 1 (count (- n 1)))))
+                (func nested (n) (if (<= n 0) 0 ((fn () (+ 1 (nested (- n 1)))))))
+                (fn (n) [(count n) (nested n)])))";
+        assert_eq!(
+            execute_str(&format!("{make} ((make) 3)")),
+            execute_str("[3 3]")
+        );
+
+        // A local function calls itself through the running closure rather than through
+        // the local that holds it, so once `make` returns, only the closure it returned
+        // keeps those locals alive.
+        let Value::Closure(closure) = Vm::new().execute_str(&format!("{make} (make)"))? else {
+            panic!("expected a closure");
+        };
+        #[allow(irrefutable_let_patterns)]
+        let value::Closure::Compiled { captures, .. } = &*closure else {
+            panic!("expected a compiled closure");
+        };
+        assert_eq!(captures.len(), 2);
+        for cell in captures.iter() {
+            assert_eq!(Rc::strong_count(cell), 1);
         }
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        let source = "
+            (func walk_a (m) (get m :end (+ 1 (walk_b (get m :next)))))
+            (func walk_b (m) (get m :end (+ 10 (walk_a (get m :next)))))
+            (walk_a {:next {:next {:next {:end 0}}}})";
+        assert_eq!(execute_str(source), Ok(Value::Number(12.0)));
+    }
+
+    #[test]
+    fn test_function_sees_globals() {
+        let source = "
+            (define scale 3)
+            (func scaled (x) (* x (+ scale offset)))
+            (define offset 1)
+            (scaled 2)";
+        assert_eq!(execute_str(source), Ok(Value::Number(8.0)));
 
-        let mut local_variables = HashMap::new();
-        for (param, arg) in function.parameters.iter().zip(arguments) {
-            let value = self.execute(arg.clone())?;
-            local_variables.insert(param.clone(), value);
+        let source = "
+            let count = 0
+            (func bump (n) (do (+= count n) (define local n) count))
+            (bump 2) (bump 3)
+            count";
+        assert_eq!(execute_str(source), Ok(Value::Number(5.0)));
+        assert_eq!(
+            execute_str("(func f (x) (define local x)) (f 1) local")
+                .unwrap_err()
+                .inner(),
+            &RuntimeError::UndefinedVariable("local".to_string())
+        );
+    }
+
+    #[test]
+    fn test_let_scope() {
+        assert_eq!(
+            execute_str("(define x 1) (let ((x 2) (y (+ x 1))) (+ x y))"),
+            Ok(Value::Number(5.0))
+        );
+        assert_eq!(
+            execute_str("(define x 1) (let ((x 2)) x) x"),
+            Ok(Value::Number(1.0))
+        );
+        assert_eq!(
+            execute_str("(func f (y) (+ x y)) (let ((x 1)) (f 2))")
+                .unwrap_err()
+                .inner(),
+            &RuntimeError::UndefinedVariable("x".to_string())
+        );
+    }
+
+    #[test]
+    fn test_closures() {
+        let source = "
+            (func make_adder (n) (fn (x) (+ x n)))
+            (define add2 (make_adder 2))
+            (add2 40)";
+        assert_eq!(execute_str(source), Ok(Value::Number(42.0)));
+        assert_eq!(
+            execute_str("((lambda (a b) (* a b)) 6 7)"),
+            Ok(Value::Number(42.0))
+        );
+        assert_eq!(
+            execute_str("((get {:f (fn (x) (+ x 1))} :f) 1)"),
+            Ok(Value::Number(2.0))
+        );
+        assert_eq!(
+            execute_str("(map (fn (f) (f 3)) [(fn (x) (+ x 1)) (fn (x) (* x 2))])"),
+            execute_str("[4 6]")
+        );
+        assert_eq!(
+            execute_str("let counter = 0 (define bump (fn () (+= counter 1))) (bump) (bump)"),
+            Ok(Value::Number(2.0))
+        );
+        assert_eq!(
+            execute_str("(define id (fn (x) x)) (id 1 2)")
+                .unwrap_err()
+                .inner(),
+            &RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: 2
+            }
+        );
+        assert_eq!(
+            execute_str("(fn (x y) (+ x y))").unwrap().to_string(),
+            "(fn (x y) (+ x y))"
+        );
+    }
+
+    #[test]
+    fn test_higher_order_builtins() {
+        assert_eq!(
+            execute_str("(map (fn (x) (* x 2)) [1 2 3])"),
+            execute_str("[2 4 6]")
+        );
+        assert_eq!(
+            execute_str(
+                "(map (fn (m) (get m :n))
+                      (filter (fn (m) (get m :on false)) [{:on true :n 1} {:n 2} {:on true :n 3}]))"
+            ),
+            execute_str("[1 3]")
+        );
+        assert_eq!(
+            execute_str("(reduce (fn (sum x) (+ sum x)) 0 [1 2 3 4])"),
+            Ok(Value::Number(10.0))
+        );
+        assert_eq!(
+            execute_str("let total = 0 (for_each (fn (x) (+= total x)) [1 2 3]) total"),
+            Ok(Value::Number(6.0))
+        );
+        assert_eq!(execute_str("(map (fn (x) x) ())"), Ok(Value::List(vec![])));
+        assert_eq!(
+            execute_str("(map 1 [1])").unwrap_err().inner(),
+            &RuntimeError::TypeError {
+                expected: ValueType::Closure,
+                found: ValueType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        assert_eq!(
+            execute_str("let foo = 42 (if (== foo 42) :yes :no)"),
+            Ok(Value::Symbol("yes".to_string()))
+        );
+        assert_eq!(execute_str("(if false 1)"), Ok(Value::Null));
+        assert_eq!(execute_str("(if 0 1 2)"), Ok(Value::Number(1.0)));
+        assert_eq!(execute_str("(if () 1 2)"), Ok(Value::Number(2.0)));
+        let err = execute_str("(if true)").unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::InvalidOptionalArgumentCount {
+                min: 2,
+                max: 3,
+                found: 1,
+            }
+        );
+        assert_eq!(
+            err.inner().to_string(),
+            "Invalid argument count: expected 2 or 3, found 1"
+        );
+        assert_eq!(
+            execute_str("(func fact (n) (if (<= n 1) 1 (* n (fact (- n 1))))) (fact 10)"),
+            Ok(Value::Number(3628800.0))
+        );
+
+        let source = "
+            (func sign (n) (cond ((< n 0) :negative) ((== n 0) :zero) (else :positive)))
+            [(sign -5) (sign 0) (sign 3)]";
+        assert_eq!(
+            execute_str(source),
+            execute_str("'(:negative :zero :positive)")
+        );
+        assert_eq!(execute_str("(cond (false 1))"), Ok(Value::Null));
+
+        assert_eq!(
+            execute_str("let x = 1 (when true (+= x 1) (+= x 1)) (unless true (+= x 10)) x"),
+            Ok(Value::Number(3.0))
+        );
+        assert_eq!(execute_str("(when false undefined)"), Ok(Value::Null));
+    }
+
+    #[test]
+    fn test_boolean_logic() {
+        assert_eq!(execute_str("(and 1 2 3)"), Ok(Value::Number(3.0)));
+        assert_eq!(
+            execute_str("(and 1 false undefined)"),
+            Ok(Value::Boolean(false))
+        );
+        assert_eq!(
+            execute_str("(or false () 2 undefined)"),
+            Ok(Value::Number(2.0))
+        );
+        assert_eq!(execute_str("(or false ())"), Ok(Value::Null));
+        assert_eq!(execute_str("[(and) (or)]"), execute_str("[true false]"));
+        assert_eq!(execute_str("(not ())"), Ok(Value::Boolean(true)));
+        assert_eq!(execute_str("(not 0)"), Ok(Value::Boolean(false)));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let truths = [
+            "(== 1 1.0 2/2)",
+            "(== 0.5 1/2)",
+            "(!= 1 2)",
+            "(< 1/3 0.5 1)",
+            "(<= 1 1.0 2)",
+            "(> 2 1)",
+            "(>= 2 2 1/2)",
+            "(== [1 2 {:a 3}] '(1.0 2 {:a 3/1}))",
+            "(!= [1 2] [1 2 3])",
+            "(!= {:a 1} {:b 1})",
+            "(== 1s 1000ms)",
+            "(< 500ms 1s)",
+            "(== :C#4 :Db4)",
+            "(< :C4 :E4)",
+            r#"(< "abc" "abd")"#,
+            r#"(== "a" "a")"#,
+            "(!= :a \"a\")",
+        ];
+        for source in truths {
+            assert_eq!(execute_str(source), Ok(Value::Boolean(true)), "{source}");
         }
+        assert_eq!(execute_str("(< 1 3 2)"), Ok(Value::Boolean(false)));
+        assert_eq!(
+            execute_str("(< 1b 1s)").unwrap_err().inner(),
+            &RuntimeError::InvalidOperation {
+                operation: "<".to_string(),
+                left: ValueType::Quantity,
+                right: ValueType::Quantity,
+            }
+        );
+        assert_eq!(
+            execute_str("(>= :a 1)").unwrap_err().inner(),
+            &RuntimeError::InvalidOperation {
+                operation: ">=".to_string(),
+                left: ValueType::Symbol,
+                right: ValueType::Number,
+            }
+        );
+    }
+
+    #[test]
+    fn test_call_dispatch() {
+        let err = execute_str("(define x 5)\n(x 1 2)").unwrap_err();
+        assert_eq!(
+            err.inner(),
+            &RuntimeError::NotCallable {
+                found: ValueType::Number
+            }
+        );
+        assert_eq!(err.span(), Some(Span::new(14, 15, 2, 2)));
+        assert_eq!(
+            execute_str("(1 2 3)").unwrap_err().inner(),
+            &RuntimeError::NotCallable {
+                found: ValueType::Number
+            }
+        );
 
-        let mut scope = Scope::new(self.vm);
-        scope.variables.extend(local_variables);
+        // Special forms cannot be shadowed by variables.
+        assert_eq!(execute_str("(define do 1) (do 2)"), Ok(Value::Number(2.0)));
+        assert_eq!(
+            execute_str("(let ((if 0)) (if false 1 2))"),
+            Ok(Value::Number(2.0))
+        );
 
-        let result = scope.execute(function.body)?;
+        // Builtin functions can be.
+        assert_eq!(
+            execute_str("(define chord (fn (root quality) root)) (chord :C4 :maj)"),
+            Ok(Value::Note(Note::from_midi(60).unwrap()))
+        );
+        assert_eq!(
+            execute_str("(func freq (x) (* x 2)) (freq 21)"),
+            Ok(Value::Number(42.0))
+        );
 
-        Ok(result)
+        assert_eq!(
+            execute_str("(define add (fn (a b) (+ a b))) (apply add (1 2))"),
+            Ok(Value::Number(3.0))
+        );
+        assert_eq!(execute_str("(apply + (1 2))"), Ok(Value::Number(3.0)));
     }
 
-    pub fn set_variable(&mut self, name: String, value: Value) {
-        self.variables.insert(name, value);
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            execute_str("let n = 0 (loop 10 (+= n 1)) n"),
+            Ok(Value::Number(10.0))
+        );
+        assert_eq!(
+            execute_str("let n = 1 (while (< n 100) (*= n 2)) n"),
+            Ok(Value::Number(128.0))
+        );
+        assert_eq!(
+            execute_str("let sum = 0 (dotimes (i 5) (+= sum i)) sum"),
+            Ok(Value::Number(10.0))
+        );
+        assert_eq!(
+            execute_str("let notes = [] (for (n [:C4 :E4 :G4]) (+= notes [(+ n 12)])) notes"),
+            execute_str("[:C5 :E5 :G5]")
+        );
+        assert_eq!(
+            execute_str("(define fs []) (dotimes (i 3) (+= fs [(fn () i)])) (map (fn (f) (f)) fs)"),
+            execute_str("[0 1 2]")
+        );
+        assert_eq!(execute_str("(loop 3 1)"), Ok(Value::Null));
     }
 
-    pub fn get_variable(&self, name: &str) -> Result<Value, RuntimeError> {
-        self.variables
-            .get(name)
-            .cloned()
-            .ok_or(RuntimeError::UndefinedVariable(name.to_string()))
+    #[test]
+    fn test_break_and_continue() {
+        let source = "
+            let sum = 0
+            (for (i (range 10))
+              (when (== i 2) (continue))
+              (when (== i 5) (break))
+              (+= sum i))
+            sum";
+        assert_eq!(execute_str(source), Ok(Value::Number(8.0)));
+        assert_eq!(
+            execute_str("(for (i (range)) (when (> (* i i) 50) (break i)))"),
+            Ok(Value::Number(8.0))
+        );
+        assert_eq!(
+            execute_str("let n = 0 (while true (+= n 1) (if (< n 3) (continue) (break :done)))"),
+            Ok(Value::Symbol("done".to_string()))
+        );
+        // A break ends only the innermost loop, and stops the forms it interrupts without
+        // their errors or side effects.
+        let source = "
+            let count = 0
+            (loop 3 (loop 5 (+ 1 (break)) (define never 1)) (+= count 1))
+            count";
+        assert_eq!(execute_str(source), Ok(Value::Number(3.0)));
+        assert_eq!(
+            execute_str("(loop 1 (define x (break))) x")
+                .unwrap_err()
+                .inner(),
+            &RuntimeError::UndefinedVariable("x".to_string())
+        );
+
+        assert_eq!(
+            execute_str("(break)").unwrap_err().inner(),
+            &RuntimeError::OutsideLoop("break".to_string())
+        );
+        assert_eq!(
+            execute_str("(func f () (continue)) (loop 1 (f))")
+                .unwrap_err()
+                .inner(),
+            &RuntimeError::OutsideLoop("continue".to_string())
+        );
     }
 
-    pub fn set_function(&mut self, function: FunctionDef) {
-        self.functions.insert(function.name.clone(), function);
+    #[test]
+    fn test_range() {
+        assert_eq!(
+            execute_str("(== (range 4) [0 1 2 3])"),
+            Ok(Value::Boolean(true))
+        );
+        assert_eq!(
+            execute_str("(map (fn (x) x) (range 1 2 1/4))"),
+            execute_str("[1 1.25 1.5 1.75]")
+        );
+        assert_eq!(
+            execute_str("(reduce (fn (a b) (+ a b)) 0 (range 10 0 -2))"),
+            Ok(Value::Number(30.0))
+        );
+        assert_eq!(
+            execute_str("(range 5 0)").unwrap().to_string(),
+            "(range 5 0 1)"
+        );
+        assert_eq!(execute_str("(range)").unwrap().to_string(), "(range)");
+        assert_eq!(
+            execute_str("(range 0 1 0)").unwrap_err().inner(),
+            &RuntimeError::ZeroRangeStep
+        );
+        assert_eq!(
+            execute_str("(map (fn (x) x) (range 5 0))"),
+            Ok(Value::List(vec![]))
+        );
+        for (function, source) in [
+            ("map", "(map (fn (x) x) (range))"),
+            ("filter", "(filter (fn (x) true) (range))"),
+            ("for_each", "(for_each (fn (x) x) (range))"),
+            ("reduce", "(reduce (fn (a b) (+ a b)) 0 (range))"),
+        ] {
+            assert_eq!(
+                execute_str(source).unwrap_err().inner(),
+                &RuntimeError::UnboundedRange(function.to_string())
+            );
+        }
     }
 
-    pub fn get_function(&self, name: &str) -> Result<FunctionDef, RuntimeError> {
-        self.functions
-            .get(name)
-            .cloned()
-            .ok_or(RuntimeError::UndefinedFunction(name.to_string()))
+    #[test]
+    fn test_tail_calls() {
+        let source = "
+            (func count (n acc) (if (<= n 0) acc (count (- n 1) (+ acc 1))))
+            (count 100000 0)";
+        assert_eq!(execute_str(source), Ok(Value::Number(100000.0)));
+
+        let source = "
+            (func is_even (n) (cond ((== n 0) true) (else (is_odd (- n 1)))))
+            (func is_odd (n) (and (!= n 0) (is_even (- n 1))))
+            [(is_even 10000) (is_odd 10000)]";
+        assert_eq!(execute_str(source), execute_str("[true false]"));
+
+        let source = "
+            (define countdown (fn (n) (when (> n 0) (countdown (- n 1)))))
+            (countdown 100000)";
+        assert_eq!(execute_str(source), Ok(Value::Null));
     }
 
-    pub fn call_stack(&self) -> &[String] {
-        &self.call_stack
+    #[test]
+    fn test_forward_references() {
+        // Functions defined in a block can refer to names defined later in it.
+        let source = "
+            (func outer (n)
+              (do (func ev (k) (if (== k 0) true (od (- k 1))))
+                  (func od (k) (if (== k 0) false (ev (- k 1))))
+                  (ev n)))
+            (outer 4)";
+        assert_eq!(execute_str(source), Ok(Value::Boolean(true)));
+        let source = "(func f () (do (define g (fn () y)) (define y 5) (g))) (f)";
+        assert_eq!(execute_str(source), Ok(Value::Number(5.0)));
+
+        // Until its definition runs, a name still refers to any outer binding.
+        let source = "let x = 1 (func f () (do (define y x) (define x (+ x 1)) [y x])) (f)";
+        assert_eq!(execute_str(source), execute_str("[1 2]"));
+        assert_eq!(
+            execute_str("(func f () (do (define y z) (define z 1))) (f)")
+                .unwrap_err()
+                .inner(),
+            &RuntimeError::UndefinedVariable("z".to_string())
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // The tree walker has a lower limit, so only the machine is run.
+        let err = super::execute_str("(func f (n) (+ 1 (f n))) (f 1)").unwrap_err();
+        let RuntimeError::StackOverflow { depth, frames } = err.inner() else {
+            panic!("expected a stack overflow, found {err:?}");
+        };
+        assert_eq!(*depth, DEFAULT_MAX_CALL_DEPTH);
+        assert_eq!(frames, &vec!["f"; 8]);
+
+        let vm = Vm::new().with_max_call_depth(50);
+        let source = "(func f (n) (if (<= n 0) 0 (+ 1 (f (- n 1)))))";
+        assert_eq!(
+            vm.execute_str(&format!("{source} (f 5)")),
+            Ok(Value::Number(5.0))
+        );
+        assert!(matches!(
+            vm.execute_str(&format!("{source} (f 50)"))
+                .unwrap_err()
+                .inner(),
+            RuntimeError::StackOverflow { depth: 50, .. }
+        ));
+
+        // Calls between compiled functions do not recurse natively, but calls back into
+        // the program from builtins do, so those are limited separately.
+        let source = "(func f (n) (if (<= n 0) 0 (+ 1 (f (- n 1)))))";
+        assert_eq!(
+            super::execute_str(&format!("{source} (f 5000)")),
+            Ok(Value::Number(5000.0))
+        );
+        let source = "(func f (n) (if (<= n 0) 0 (+ 1 (reduce (fn (a x) (f (- n 1))) 0 [1]))))";
+        assert!(matches!(
+            super::execute_str(&format!("{source} (f 1000)"))
+                .unwrap_err()
+                .inner(),
+            RuntimeError::StackOverflow {
+                depth: MAX_NESTED_CALLS,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_session() {
+        let mut vm = Vm::new();
+        vm.eval("let x = 1").unwrap();
+        vm.eval("(func inc (n) (+ n x))").unwrap();
+        assert_eq!(vm.eval("(inc 1)"), Ok(Value::Number(2.0)));
+        assert_eq!(vm.session().globals().get("x"), Some(&Value::Number(1.0)));
+        // One-off programs neither see nor change the session.
+        assert!(vm.execute_str("(inc 1)").is_err());
+
+        vm.eval("(~ dac (saw 440))").unwrap();
+        let snapshot = vm.snapshot();
+        assert!(vm.eval("(+= x 9) (~ dac (saw 220)) (undefined)").is_err());
+        assert_eq!(vm.eval("(inc 1)"), Ok(Value::Number(11.0)));
+        assert_eq!(vm.session().graph().connections.len(), 2);
+
+        vm.restore(&snapshot);
+        assert_eq!(vm.eval("(inc 1)"), Ok(Value::Number(2.0)));
+        assert_eq!(vm.session().graph().connections.len(), 1);
+
+        // Evaluating a block again replaces the connections it made before.
+        vm.eval("(~ dac (saw 440)) (~ dac (saw 440))").unwrap();
+        vm.eval("(~ dac (saw 440)) (~ dac (saw 440))").unwrap();
+        assert_eq!(vm.session().graph().connections.len(), 2);
+
+        // Restoring also rolls back the variables closures captured, which closures that
+        // shared them still share.
+        vm.eval(
+            "(define counter (let ((n 0)) (fn () (do (+= n 1) n))))
+             (func make () (let ((n 0)) {:bump (fn () (do (+= n 1) n)) :peek (fn () n)}))
+             (define pair (make))
+             (define bump (get pair :bump))
+             (define peek (get pair :peek))",
+        )
+        .unwrap();
+        let snapshot = vm.snapshot();
+        vm.eval("(counter) (counter) (bump)").unwrap();
+        vm.restore(&snapshot);
+        assert_eq!(vm.eval("(counter)"), Ok(Value::Number(1.0)));
+        assert_eq!(vm.eval("(bump) (peek)"), Ok(Value::Number(1.0)));
+        vm.restore(&snapshot);
+        assert_eq!(vm.eval("(counter)"), Ok(Value::Number(1.0)));
+
+        vm.register_fn("twice", |n: f64| Ok(n * 2.0)).unwrap();
+        vm.reset();
+        assert!(vm.eval("(inc 1)").is_err());
+        assert_eq!(vm.session().graph().connections.len(), 0);
+        assert_eq!(vm.eval("(twice 2)"), Ok(Value::Number(4.0)));
+
+        // A session holds no tasks: the scheduling form the examples sketch does not exist,
+        // and what the host schedules survives a restore.
+        assert_eq!(
+            vm.eval("(every :quarter (do ()))").unwrap_err().inner(),
+            &RuntimeError::UndefinedIdentifier("every".to_string())
+        );
+        let scheduled = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let queue = scheduled.clone();
+        vm.register_fn("schedule", move |beat: f64| {
+            queue.borrow_mut().push(beat);
+            Ok(())
+        })
+        .unwrap();
+        let snapshot = vm.snapshot();
+        vm.eval("(schedule 4)").unwrap();
+        vm.restore(&snapshot);
+        assert_eq!(*scheduled.borrow(), [4.0]);
     }
 }
@@ -0,0 +1,391 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use crate::note::{Note, chord_intervals};
+
+use super::{
+    RuntimeError,
+    graph::{FilterKind, Node, Waveform},
+    machine::Machine,
+    value::{MapKey, Range, Value, ValueType, note_from_midi},
+};
+
+/// A builtin function, which receives its arguments already evaluated.
+pub type Native = fn(&mut Machine<'_>, Vec<Value>) -> Result<Value, RuntimeError>;
+
+/// Returns the builtin function with the given name.
+///
+/// Builtins are resolved after global variables and functions, so those can shadow them.
+pub fn lookup(name: &str) -> Option<Native> {
+    let native: Native = match name {
+        "not" => not,
+        "+" => add,
+        "-" => sub,
+        "*" => mul,
+        "/" => div,
+        "==" => |_, arguments| compare("==", arguments),
+        "!=" => |_, arguments| compare("!=", arguments),
+        "<" => |_, arguments| compare("<", arguments),
+        "<=" => |_, arguments| compare("<=", arguments),
+        ">" => |_, arguments| compare(">", arguments),
+        ">=" => |_, arguments| compare(">=", arguments),
+        "~" => patch,
+        ">>" => chain,
+        "sine" => |_, arguments| oscillator(Waveform::Sine, arguments),
+        "saw" => |_, arguments| oscillator(Waveform::Saw, arguments),
+        "square" => |_, arguments| oscillator(Waveform::Square, arguments),
+        "triangle" => |_, arguments| oscillator(Waveform::Triangle, arguments),
+        "lpf" => |_, arguments| filter(FilterKind::LowPass, arguments),
+        "hpf" => |_, arguments| filter(FilterKind::HighPass, arguments),
+        "bus" => bus,
+        "midi" => |_, arguments| note_property(arguments, |note| note.midi() as f64),
+        "octave" => |_, arguments| note_property(arguments, |note| note.octave() as f64),
+        "pitch_class" => |_, arguments| note_property(arguments, |note| note.pitch_class() as f64),
+        "freq" => |_, arguments| note_property(arguments, Note::frequency),
+        "note" => note,
+        "chord" => chord,
+        "map" => map,
+        "filter" => filter_sequence,
+        "for_each" => for_each,
+        "reduce" => reduce,
+        "range" => range,
+        "get" => get,
+        "assoc" => assoc,
+        "dissoc" => dissoc,
+        "keys" => keys,
+        "vals" => vals,
+        "merge" => merge,
+        _ => return None,
+    };
+    Some(native)
+}
+
+/// Checks that there are exactly `N` arguments and returns them as an array.
+fn exactly<const N: usize>(arguments: Vec<Value>) -> Result<[Value; N], RuntimeError> {
+    arguments
+        .try_into()
+        .map_err(|arguments: Vec<Value>| RuntimeError::InvalidArgumentCount {
+            expected: N,
+            found: arguments.len(),
+        })
+}
+
+fn expect_at_least(arguments: &[Value], expected: usize) -> Result<(), RuntimeError> {
+    if arguments.len() < expected {
+        return Err(RuntimeError::InvalidArgumentCount {
+            expected,
+            found: arguments.len(),
+        });
+    }
+    Ok(())
+}
+
+fn type_error(expected: ValueType, found: &Value) -> RuntimeError {
+    RuntimeError::TypeError {
+        expected,
+        found: found.value_type(),
+    }
+}
+
+fn number(value: &Value) -> Result<f64, RuntimeError> {
+    match value.as_scalar() {
+        Some(scalar) => Ok(scalar.to_f64()),
+        None => Err(type_error(ValueType::Number, value)),
+    }
+}
+
+fn note_value(value: &Value) -> Result<Note, RuntimeError> {
+    match value {
+        Value::Note(note) => Ok(*note),
+        value => Err(type_error(ValueType::Note, value)),
+    }
+}
+
+fn map_value(value: Value) -> Result<BTreeMap<MapKey, Value>, RuntimeError> {
+    match value {
+        Value::Map(map) => Ok(map),
+        value => Err(type_error(ValueType::Map, &value)),
+    }
+}
+
+fn closure_value(value: &Value) -> Result<&Value, RuntimeError> {
+    match value {
+        Value::Closure(_) => Ok(value),
+        value => Err(type_error(ValueType::Closure, value)),
+    }
+}
+
+fn not(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [value] = exactly(arguments)?;
+    Ok(Value::Boolean(!value.is_truthy()))
+}
+
+fn add(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_at_least(&arguments, 2)?;
+    let mut arguments = arguments.into_iter();
+    let first = arguments.next().expect("checked count");
+    arguments.try_fold(first, |result, value| result.add(&value))
+}
+
+fn sub(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [a, b] = exactly(arguments)?;
+    a.sub(&b)
+}
+
+fn mul(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_at_least(&arguments, 2)?;
+    let mut arguments = arguments.into_iter();
+    let first = arguments.next().expect("checked count");
+    arguments.try_fold(first, |result, value| result.mul(&value))
+}
+
+fn div(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [a, b] = exactly(arguments)?;
+    a.div(&b)
+}
+
+fn compare(operation: &str, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_at_least(&arguments, 2)?;
+    // With more than two arguments, every neighboring pair must satisfy the comparison,
+    // so `(< 1 x 10)` checks that `x` is between 1 and 10.
+    for pair in arguments.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        let holds = match operation {
+            "==" => a.structural_eq(b),
+            "!=" => !a.structural_eq(b),
+            _ => {
+                let ordering = a.compare(b, operation)?;
+                match operation {
+                    "<" => ordering == Some(Ordering::Less),
+                    "<=" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    ">" => ordering == Some(Ordering::Greater),
+                    _ => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                }
+            }
+        };
+        if !holds {
+            return Ok(Value::Boolean(false));
+        }
+    }
+    Ok(Value::Boolean(true))
+}
+
+fn patch(machine: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_at_least(&arguments, 2)?;
+    let mut arguments = arguments.into_iter();
+    let target = match arguments.next().expect("checked count") {
+        Value::Node(node) if node.is_sink() => node,
+        value => return Err(RuntimeError::InvalidPatchTarget(value.value_type())),
+    };
+    for (channel, value) in arguments.enumerate() {
+        let source = Node::from_value(&value)?;
+        machine.graph.connect(source, target.clone(), channel);
+    }
+    Ok(Value::Null)
+}
+
+fn chain(machine: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_at_least(&arguments, 2)?;
+    let mut signal = Node::from_value(&arguments[0])?;
+    for value in arguments.into_iter().skip(1) {
+        match value {
+            Value::Node(node) if node.is_sink() => {
+                machine.graph.connect(signal.clone(), node, 0);
+            }
+            Value::Node(node) if node.is_unpatched() => {
+                signal = node.with_input(signal);
+            }
+            value => return Err(RuntimeError::InvalidPatchTarget(value.value_type())),
+        }
+    }
+    Ok(Value::Node(signal))
+}
+
+fn oscillator(waveform: Waveform, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [frequency] = exactly(arguments)?;
+    Ok(Value::Node(Node::Oscillator {
+        waveform,
+        frequency: Box::new(Node::from_value(&frequency)?),
+    }))
+}
+
+fn filter(kind: FilterKind, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    if arguments.is_empty() || arguments.len() > 2 {
+        return Err(RuntimeError::InvalidArgumentCount {
+            expected: 1,
+            found: arguments.len(),
+        });
+    }
+    let input = match arguments.get(1) {
+        Some(input) => Some(Box::new(Node::from_value(input)?)),
+        None => None,
+    };
+    Ok(Value::Node(Node::Filter {
+        kind,
+        cutoff: Box::new(Node::from_value(&arguments[0])?),
+        input,
+    }))
+}
+
+fn bus(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    match exactly(arguments)? {
+        [Value::Symbol(name) | Value::String(name)] => Ok(Value::Node(Node::Bus(name))),
+        [value] => Err(type_error(ValueType::Symbol, &value)),
+    }
+}
+
+fn note_property(arguments: Vec<Value>, property: fn(&Note) -> f64) -> Result<Value, RuntimeError> {
+    let [note] = exactly(arguments)?;
+    Ok(Value::Number(property(&note_value(&note)?)))
+}
+
+fn note(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [value] = exactly(arguments)?;
+    match &value {
+        Value::Number(midi) => Ok(Value::Note(note_from_midi(midi.round())?)),
+        Value::Note(note) => Ok(Value::Note(*note)),
+        value => Err(type_error(ValueType::Number, value)),
+    }
+}
+
+fn chord(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [root, quality] = exactly(arguments)?;
+    let root = note_value(&root)?;
+    let Value::Symbol(quality) = quality else {
+        return Err(type_error(ValueType::Symbol, &quality));
+    };
+    let intervals = chord_intervals(&quality).ok_or(RuntimeError::UnknownChordQuality(quality))?;
+    Ok(Value::List(
+        intervals
+            .iter()
+            .map(|interval| {
+                let note = root.transpose(*interval);
+                note.map(Value::Note).ok_or(RuntimeError::NoteOutOfRange(
+                    (root.midi() + interval) as f64,
+                ))
+            })
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+fn map(machine: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [function, sequence] = exactly(arguments)?;
+    let function = closure_value(&function)?;
+    let mut results = Vec::new();
+    for element in sequence.into_bounded_sequence("map")? {
+        results.push(machine.call(function, vec![element])?);
+    }
+    Ok(Value::List(results))
+}
+
+fn filter_sequence(machine: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [function, sequence] = exactly(arguments)?;
+    let function = closure_value(&function)?;
+    let mut results = Vec::new();
+    for element in sequence.into_bounded_sequence("filter")? {
+        if machine.call(function, vec![element.clone()])?.is_truthy() {
+            results.push(element);
+        }
+    }
+    Ok(Value::List(results))
+}
+
+fn for_each(machine: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [function, sequence] = exactly(arguments)?;
+    let function = closure_value(&function)?;
+    for element in sequence.into_bounded_sequence("for_each")? {
+        machine.call(function, vec![element])?;
+    }
+    Ok(Value::Null)
+}
+
+fn reduce(machine: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [function, mut accumulator, sequence] = exactly(arguments)?;
+    let function = closure_value(&function)?;
+    for element in sequence.into_bounded_sequence("reduce")? {
+        accumulator = machine.call(function, vec![accumulator, element])?;
+    }
+    Ok(accumulator)
+}
+
+fn range(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let bounds = arguments
+        .iter()
+        .map(number)
+        .collect::<Result<Vec<_>, _>>()?;
+    let (start, end, step) = match bounds.as_slice() {
+        [] => (0.0, f64::INFINITY, 1.0),
+        [end] => (0.0, *end, 1.0),
+        [start, end] => (*start, *end, 1.0),
+        [start, end, step] => (*start, *end, *step),
+        _ => {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 3,
+                found: arguments.len(),
+            });
+        }
+    };
+    Range::new(start, end, step)
+        .map(Value::Range)
+        .ok_or(RuntimeError::ZeroRangeStep)
+}
+
+fn get(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    if !(2..=3).contains(&arguments.len()) {
+        return Err(RuntimeError::InvalidArgumentCount {
+            expected: 2,
+            found: arguments.len(),
+        });
+    }
+    let mut arguments = arguments.into_iter();
+    let map = map_value(arguments.next().expect("checked count"))?;
+    let key = MapKey::from_value(arguments.next().expect("checked count"))?;
+    match map.get(&key) {
+        Some(value) => Ok(value.clone()),
+        None => Ok(arguments.next().unwrap_or(Value::Null)),
+    }
+}
+
+fn assoc(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    if arguments.len() < 3 || arguments.len().is_multiple_of(2) {
+        return Err(RuntimeError::InvalidArgumentCount {
+            expected: arguments.len().max(2) + 1,
+            found: arguments.len(),
+        });
+    }
+    let mut arguments = arguments.into_iter();
+    let mut map = map_value(arguments.next().expect("checked count"))?;
+    while let (Some(key), Some(value)) = (arguments.next(), arguments.next()) {
+        map.insert(MapKey::from_value(key)?, value);
+    }
+    Ok(Value::Map(map))
+}
+
+fn dissoc(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_at_least(&arguments, 1)?;
+    let mut arguments = arguments.into_iter();
+    let mut map = map_value(arguments.next().expect("checked count"))?;
+    for key in arguments {
+        map.remove(&MapKey::from_value(key)?);
+    }
+    Ok(Value::Map(map))
+}
+
+fn keys(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [map] = exactly(arguments)?;
+    Ok(Value::List(
+        map_value(map)?.keys().map(MapKey::to_value).collect(),
+    ))
+}
+
+fn vals(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let [map] = exactly(arguments)?;
+    Ok(Value::List(map_value(map)?.into_values().collect()))
+}
+
+fn merge(_: &mut Machine, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut merged = BTreeMap::new();
+    for map in arguments {
+        merged.extend(map_value(map)?);
+    }
+    Ok(Value::Map(merged))
+}
@@ -0,0 +1,1040 @@
+use std::{cmp::Ordering, collections::BTreeMap, rc::Rc};
+
+use crate::{
+    note::{Note, chord_intervals},
+    parser::syntax::{Syntax, SyntaxKind, SyntaxType},
+};
+
+use super::{FunctionDef, Scope, Signal};
+use crate::vm::{
+    RuntimeError,
+    compiler::{parameters, quasiquote_form},
+    graph::{FilterKind, Node, Waveform},
+    value::{Closure, MapKey, Range, Value, ValueType, note_from_midi},
+};
+
+impl Scope<'_> {
+    /// Evaluates a special form, which receives its arguments unevaluated and decides
+    /// itself which of them to evaluate. Special forms are resolved before any variable or
+    /// function, so they cannot be shadowed. Returns `UndefinedFunction` if `function` is
+    /// not a special form.
+    ///
+    /// `tail` is true if the form is the last thing a function body evaluates, in which
+    /// case so is whichever of its arguments it evaluates last.
+    pub fn execute_special_form(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        // Each form and builtin is a method of its own, so that their locals do not all add
+        // to the native stack frame of every nested call.
+        match function {
+            "define" => self.special_define(arguments),
+            "do" => self.special_do(arguments, tail),
+            "if" => self.special_if(arguments, tail),
+            "when" | "unless" => self.special_when_unless(function, arguments, tail),
+            "cond" => self.special_cond(arguments, tail),
+            "and" | "or" => self.special_and_or(function, arguments, tail),
+            "func" => self.special_func(arguments),
+            "loop" => self.special_loop(arguments),
+            "while" => self.special_while(arguments),
+            "dotimes" | "for" => self.special_dotimes_for(function, arguments),
+            "break" => self.special_break(function, arguments),
+            "continue" => self.special_continue(function, arguments),
+            "fn" | "lambda" => self.special_lambda(arguments),
+            "let" => self.special_let(arguments, tail),
+            "apply" => self.special_apply(arguments, tail),
+            "+=" | "-=" | "*=" | "/=" => self.special_compound_assign(function, arguments),
+            "quote" => self.special_quote(arguments),
+            "quasiquote" => self.special_quasiquote(arguments),
+            "unquote" | "unquote_splicing" => self.special_unquote(function),
+
+            _ => Err(RuntimeError::UndefinedFunction(function.to_string())),
+        }
+    }
+
+    fn special_define(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        if let SyntaxKind::Identifier(name) = &arguments[0].kind {
+            let value = self.execute(arguments[1].clone())?;
+            // A `break` in the value leaves the variable unbound.
+            if self.signal.is_none() {
+                self.set_variable(name.clone(), value);
+            }
+            Ok(Value::Null)
+        } else {
+            Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::Identifier,
+                found: arguments[0].syntax_type(),
+            })
+        }
+    }
+
+    fn special_do(&mut self, arguments: &[Syntax], tail: bool) -> Result<Value, RuntimeError> {
+        if arguments.is_empty() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        self.execute_block(arguments, tail)
+    }
+
+    fn special_if(&mut self, arguments: &[Syntax], tail: bool) -> Result<Value, RuntimeError> {
+        if !(2..=3).contains(&arguments.len()) {
+            return Err(RuntimeError::InvalidOptionalArgumentCount {
+                min: 2,
+                max: 3,
+                found: arguments.len(),
+            });
+        }
+        if self.execute(arguments[0].clone())?.is_truthy() {
+            self.execute_tail(arguments[1].clone(), tail)
+        } else {
+            match arguments.get(2) {
+                Some(otherwise) => self.execute_tail(otherwise.clone(), tail),
+                None => Ok(Value::Null),
+            }
+        }
+    }
+
+    fn special_when_unless(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() < 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let condition = self.execute(arguments[0].clone())?.is_truthy();
+        if condition != (function == "when") {
+            return Ok(Value::Null);
+        }
+        self.execute_block(&arguments[1..], tail)
+    }
+
+    fn special_cond(&mut self, arguments: &[Syntax], tail: bool) -> Result<Value, RuntimeError> {
+        // Each clause is a list of a test followed by the expressions to evaluate if
+        // it is the first truthy one. A test of `else` always matches.
+        for clause in arguments {
+            let SyntaxKind::List(clause) = &clause.kind else {
+                return Err(RuntimeError::SyntaxError {
+                    expected: SyntaxType::List,
+                    found: clause.syntax_type(),
+                });
+            };
+            let Some((test, body)) = clause.split_first() else {
+                return Err(RuntimeError::InvalidArgumentCount {
+                    expected: 1,
+                    found: 0,
+                });
+            };
+            let matched = match &test.kind {
+                SyntaxKind::Identifier(name) if name == "else" => true,
+                _ => self.execute(test.clone())?.is_truthy(),
+            };
+            if matched {
+                return self.execute_block(body, tail);
+            }
+        }
+        Ok(Value::Null)
+    }
+
+    fn special_and_or(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        // Stops at the first value that decides the result and returns it, so
+        // `(or x default)` gives `x` unless it is false or null.
+        let mut result = Value::Boolean(function == "and");
+        for (i, arg) in arguments.iter().enumerate() {
+            let last = i + 1 == arguments.len();
+            result = self.execute_tail(arg.clone(), tail && last)?;
+            if result.is_truthy() != (function == "and") {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    fn special_func(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 3 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 3,
+                found: arguments.len(),
+            });
+        }
+        if let SyntaxKind::Identifier(name) = &arguments[0].kind {
+            let function_def = FunctionDef {
+                name: name.clone(),
+                parameters: parameters(&arguments[1])?,
+                body: arguments[2].clone(),
+            };
+            self.set_function(function_def);
+            Ok(Value::Null)
+        } else {
+            Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::Identifier,
+                found: arguments[0].syntax_type(),
+            })
+        }
+    }
+
+    fn special_loop(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.is_empty() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let count = self.execute_number(&arguments[0])?;
+        let mut i = 0.0;
+        while i < count {
+            if let Some(value) = self.execute_iteration(&arguments[1..])? {
+                return Ok(value);
+            }
+            i += 1.0;
+        }
+        Ok(Value::Null)
+    }
+
+    fn special_while(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.is_empty() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        while self.execute(arguments[0].clone())?.is_truthy() {
+            if let Some(value) = self.execute_iteration(&arguments[1..])? {
+                return Ok(value);
+            }
+        }
+        Ok(Value::Null)
+    }
+
+    fn special_dotimes_for(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.is_empty() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        // `(dotimes (i n) ...)` counts `i` from 0 to n - 1, and `(for (x xs) ...)`
+        // binds `x` to each element of a list or range in turn.
+        let binding = match &arguments[0].kind {
+            SyntaxKind::List(binding) => binding,
+            _ => {
+                return Err(RuntimeError::SyntaxError {
+                    expected: SyntaxType::List,
+                    found: arguments[0].syntax_type(),
+                });
+            }
+        };
+        let [variable, source] = binding.as_slice() else {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: binding.len(),
+            });
+        };
+        let SyntaxKind::Identifier(name) = &variable.kind else {
+            return Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::Identifier,
+                found: variable.syntax_type(),
+            });
+        };
+        let sequence = if function == "dotimes" {
+            let count = self.execute_number(source)?;
+            Box::new(
+                Range::new(0.0, count, 1.0)
+                    .unwrap()
+                    .iter()
+                    .map(Value::Number),
+            )
+        } else {
+            self.execute_sequence(source)?
+        };
+        let previous = self.env.clone();
+        for element in sequence {
+            // Each iteration gets its own environment, so closures created in the
+            // body capture the element of that iteration.
+            self.env = previous.child();
+            self.env.define(name.clone(), element);
+            let result = self.execute_iteration(&arguments[1..]);
+            self.env = previous.clone();
+            if let Some(value) = result? {
+                return Ok(value);
+            }
+        }
+        Ok(Value::Null)
+    }
+
+    fn special_break(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() > 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        let value = match arguments.first() {
+            Some(value) => self.execute(value.clone())?,
+            None => Value::Null,
+        };
+        self.raise_signal(function, Signal::Break(value))
+    }
+
+    fn special_continue(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if !arguments.is_empty() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 0,
+                found: arguments.len(),
+            });
+        }
+        self.raise_signal(function, Signal::Continue)
+    }
+
+    fn special_lambda(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        Ok(Value::Closure(Rc::new(Closure::Interpreted {
+            parameters: parameters(&arguments[0])?,
+            body: arguments[1].clone(),
+            env: self.env.clone(),
+        })))
+    }
+
+    fn special_let(&mut self, arguments: &[Syntax], tail: bool) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        if let SyntaxKind::List(bindings) = &arguments[0].kind {
+            // Each binding can see the ones before it, and none outlive the body.
+            let env = self.env.child();
+            for binding in bindings {
+                if let SyntaxKind::List(pair) = &binding.kind {
+                    if pair.len() != 2 {
+                        return Err(RuntimeError::InvalidArgumentCount {
+                            expected: 2,
+                            found: pair.len(),
+                        });
+                    }
+                    if let SyntaxKind::Identifier(name) = &pair[0].kind {
+                        let value = self.execute_in(env.clone(), pair[1].clone(), false)?;
+                        env.define(name.clone(), value);
+                    } else {
+                        return Err(RuntimeError::SyntaxError {
+                            expected: SyntaxType::Identifier,
+                            found: pair[0].syntax_type(),
+                        });
+                    }
+                } else {
+                    return Err(RuntimeError::SyntaxError {
+                        expected: SyntaxType::List,
+                        found: binding.syntax_type(),
+                    });
+                }
+            }
+            self.execute_in(env, arguments[1].clone(), tail)
+        } else {
+            Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::List,
+                found: arguments[0].syntax_type(),
+            })
+        }
+    }
+
+    fn special_apply(&mut self, arguments: &[Syntax], tail: bool) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        match &arguments[1].kind {
+            SyntaxKind::List(args) => self.execute_call(&arguments[0], args, tail),
+            _ => Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::List,
+                found: arguments[1].syntax_type(),
+            }),
+        }
+    }
+
+    fn special_compound_assign(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let SyntaxKind::Identifier(name) = &arguments[0].kind else {
+            return Err(RuntimeError::SyntaxError {
+                expected: SyntaxType::Identifier,
+                found: arguments[0].syntax_type(),
+            });
+        };
+        let current = self.get_variable(name)?;
+        let operand = self.execute(arguments[1].clone())?;
+        let result = match function {
+            "+=" => current.add(&operand)?,
+            "-=" => current.sub(&operand)?,
+            "*=" => current.mul(&operand)?,
+            _ => current.div(&operand)?,
+        };
+        self.assign_variable(name, result.clone())?;
+        Ok(result)
+    }
+
+    fn special_quote(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        Value::from_syntax(&arguments[0])
+    }
+
+    fn special_quasiquote(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        self.execute_quasiquote(&arguments[0], 1)
+    }
+
+    fn special_unquote(&mut self, function: &str) -> Result<Value, RuntimeError> {
+        Err(RuntimeError::UnquoteOutsideQuasiquote(function.to_string()))
+    }
+
+    /// Calls a builtin function. Builtins are resolved after variables and user-defined
+    /// functions, so those can shadow them. Returns `UndefinedFunction` if there is no
+    /// builtin named `function`.
+    pub fn execute_builtin_function(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        match function {
+            "not" => self.builtin_not(arguments),
+            "+" => self.builtin_add(arguments),
+            "-" => self.builtin_sub(arguments),
+            "*" => self.builtin_mul(arguments),
+            "/" => self.builtin_div(arguments),
+            "==" | "!=" | "<" | "<=" | ">" | ">=" => self.builtin_compare(function, arguments),
+            "~" => self.builtin_patch(arguments),
+            ">>" => self.builtin_chain(arguments),
+            "sine" | "saw" | "square" | "triangle" => self.builtin_oscillator(function, arguments),
+            "lpf" | "hpf" => self.builtin_filter(function, arguments),
+            "bus" => self.builtin_bus(arguments),
+            "midi" | "octave" | "pitch_class" | "freq" => {
+                self.builtin_note_property(function, arguments)
+            }
+            "note" => self.builtin_note(arguments),
+            "chord" => self.builtin_chord(arguments),
+            "map" | "filter" | "for_each" => self.builtin_map(function, arguments),
+            "reduce" => self.builtin_reduce(arguments),
+            "range" => self.builtin_range(arguments),
+            "get" => self.builtin_get(arguments),
+            "assoc" => self.builtin_assoc(arguments),
+            "dissoc" => self.builtin_dissoc(arguments),
+            "keys" | "vals" => self.builtin_keys_vals(function, arguments),
+            "merge" => self.builtin_merge(arguments),
+
+            _ => Err(RuntimeError::UndefinedFunction(function.to_string())),
+        }
+    }
+
+    fn builtin_not(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        Ok(Value::Boolean(
+            !self.execute(arguments[0].clone())?.is_truthy(),
+        ))
+    }
+
+    fn builtin_add(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() < 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let mut result = self.execute(arguments[0].clone())?;
+        for arg in &arguments[1..] {
+            let value = self.execute(arg.clone())?;
+            result = result.add(&value)?;
+        }
+        Ok(result)
+    }
+
+    fn builtin_sub(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let a = self.execute(arguments[0].clone())?;
+        let b = self.execute(arguments[1].clone())?;
+        a.sub(&b)
+    }
+
+    fn builtin_mul(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() < 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let mut result = self.execute(arguments[0].clone())?;
+        for arg in &arguments[1..] {
+            let value = self.execute(arg.clone())?;
+            result = result.mul(&value)?;
+        }
+        Ok(result)
+    }
+
+    fn builtin_div(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let a = self.execute(arguments[0].clone())?;
+        let b = self.execute(arguments[1].clone())?;
+        a.div(&b)
+    }
+
+    fn builtin_compare(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() < 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let mut values = Vec::new();
+        for arg in arguments {
+            values.push(self.execute(arg.clone())?);
+        }
+        // With more than two arguments, every neighboring pair must satisfy the
+        // comparison, so `(< 1 x 10)` checks that `x` is between 1 and 10.
+        for pair in values.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            let holds = match function {
+                "==" => a.structural_eq(b),
+                "!=" => !a.structural_eq(b),
+                _ => {
+                    let ordering = a.compare(b, function)?;
+                    match function {
+                        "<" => ordering == Some(Ordering::Less),
+                        "<=" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                        ">" => ordering == Some(Ordering::Greater),
+                        _ => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                    }
+                }
+            };
+            if !holds {
+                return Ok(Value::Boolean(false));
+            }
+        }
+        Ok(Value::Boolean(true))
+    }
+
+    fn builtin_patch(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() < 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let target = match self.execute(arguments[0].clone())? {
+            Value::Node(node) if node.is_sink() => node,
+            value => return Err(RuntimeError::InvalidPatchTarget(value.value_type())),
+        };
+        for (channel, arg) in arguments[1..].iter().enumerate() {
+            let source = Node::from_value(&self.execute(arg.clone())?)?;
+            self.graph.connect(source, target.clone(), channel);
+        }
+        Ok(Value::Null)
+    }
+
+    fn builtin_chain(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() < 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let mut signal = Node::from_value(&self.execute(arguments[0].clone())?)?;
+        for arg in &arguments[1..] {
+            match self.execute(arg.clone())? {
+                Value::Node(node) if node.is_sink() => {
+                    self.graph.connect(signal.clone(), node, 0);
+                }
+                Value::Node(node) if node.is_unpatched() => {
+                    signal = node.with_input(signal);
+                }
+                value => return Err(RuntimeError::InvalidPatchTarget(value.value_type())),
+            }
+        }
+        Ok(Value::Node(signal))
+    }
+
+    fn builtin_oscillator(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        let waveform = match function {
+            "sine" => Waveform::Sine,
+            "saw" => Waveform::Saw,
+            "square" => Waveform::Square,
+            _ => Waveform::Triangle,
+        };
+        let frequency = Node::from_value(&self.execute(arguments[0].clone())?)?;
+        Ok(Value::Node(Node::Oscillator {
+            waveform,
+            frequency: Box::new(frequency),
+        }))
+    }
+
+    fn builtin_filter(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.is_empty() || arguments.len() > 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        let kind = if function == "lpf" {
+            FilterKind::LowPass
+        } else {
+            FilterKind::HighPass
+        };
+        let cutoff = Node::from_value(&self.execute(arguments[0].clone())?)?;
+        let input = match arguments.get(1) {
+            Some(arg) => Some(Box::new(Node::from_value(&self.execute(arg.clone())?)?)),
+            None => None,
+        };
+        Ok(Value::Node(Node::Filter {
+            kind,
+            cutoff: Box::new(cutoff),
+            input,
+        }))
+    }
+
+    fn builtin_bus(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        match self.execute(arguments[0].clone())? {
+            Value::Symbol(name) | Value::String(name) => Ok(Value::Node(Node::Bus(name))),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::Symbol,
+                found: value.value_type(),
+            }),
+        }
+    }
+
+    fn builtin_note_property(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        let note = self.execute_note(&arguments[0])?;
+        let result = match function {
+            "midi" => note.midi() as f64,
+            "octave" => note.octave() as f64,
+            "pitch_class" => note.pitch_class() as f64,
+            _ => note.frequency(),
+        };
+        Ok(Value::Number(result))
+    }
+
+    fn builtin_note(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        match self.execute(arguments[0].clone())? {
+            Value::Number(midi) => Ok(Value::Note(note_from_midi(midi.round())?)),
+            Value::Note(note) => Ok(Value::Note(note)),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::Number,
+                found: value.value_type(),
+            }),
+        }
+    }
+
+    fn builtin_chord(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let root = self.execute_note(&arguments[0])?;
+        let quality = match self.execute(arguments[1].clone())? {
+            Value::Symbol(quality) => quality,
+            value => {
+                return Err(RuntimeError::TypeError {
+                    expected: ValueType::Symbol,
+                    found: value.value_type(),
+                });
+            }
+        };
+        let intervals =
+            chord_intervals(&quality).ok_or(RuntimeError::UnknownChordQuality(quality))?;
+        Ok(Value::List(
+            intervals
+                .iter()
+                .map(|interval| {
+                    let note = root.transpose(*interval);
+                    note.map(Value::Note).ok_or(RuntimeError::NoteOutOfRange(
+                        (root.midi() + interval) as f64,
+                    ))
+                })
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    fn builtin_map(&mut self, function: &str, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 2 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let closure = self.execute_closure(&arguments[0])?;
+        let sequence = self.execute_bounded_sequence(function, &arguments[1])?;
+        let mut results = Vec::new();
+        for element in sequence {
+            let result = self.apply_closure(&closure, vec![element.clone()])?;
+            match function {
+                "map" => results.push(result),
+                "filter" if result.is_truthy() => results.push(element),
+                _ => {}
+            }
+        }
+        match function {
+            "for_each" => Ok(Value::Null),
+            _ => Ok(Value::List(results)),
+        }
+    }
+
+    fn builtin_reduce(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() != 3 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 3,
+                found: arguments.len(),
+            });
+        }
+        let closure = self.execute_closure(&arguments[0])?;
+        let mut accumulator = self.execute(arguments[1].clone())?;
+        for element in self.execute_bounded_sequence("reduce", &arguments[2])? {
+            accumulator = self.apply_closure(&closure, vec![accumulator, element])?;
+        }
+        Ok(accumulator)
+    }
+
+    fn builtin_range(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        let mut bounds = Vec::new();
+        for arg in arguments {
+            bounds.push(self.execute_number(arg)?);
+        }
+        let (start, end, step) = match bounds.as_slice() {
+            [] => (0.0, f64::INFINITY, 1.0),
+            [end] => (0.0, *end, 1.0),
+            [start, end] => (*start, *end, 1.0),
+            [start, end, step] => (*start, *end, *step),
+            _ => {
+                return Err(RuntimeError::InvalidArgumentCount {
+                    expected: 3,
+                    found: arguments.len(),
+                });
+            }
+        };
+        Range::new(start, end, step)
+            .map(Value::Range)
+            .ok_or(RuntimeError::ZeroRangeStep)
+    }
+
+    fn builtin_get(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if !(2..=3).contains(&arguments.len()) {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 2,
+                found: arguments.len(),
+            });
+        }
+        let map = self.execute_map(&arguments[0])?;
+        let key = self.execute_map_key(&arguments[1])?;
+        match map.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => match arguments.get(2) {
+                Some(default) => self.execute(default.clone()),
+                None => Ok(Value::Null),
+            },
+        }
+    }
+
+    fn builtin_assoc(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.len() < 3 || arguments.len().is_multiple_of(2) {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: arguments.len().max(2) + 1,
+                found: arguments.len(),
+            });
+        }
+        let mut map = self.execute_map(&arguments[0])?;
+        for pair in arguments[1..].chunks(2) {
+            let key = self.execute_map_key(&pair[0])?;
+            map.insert(key, self.execute(pair[1].clone())?);
+        }
+        Ok(Value::Map(map))
+    }
+
+    fn builtin_dissoc(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        if arguments.is_empty() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        let mut map = self.execute_map(&arguments[0])?;
+        for key in &arguments[1..] {
+            map.remove(&self.execute_map_key(key)?);
+        }
+        Ok(Value::Map(map))
+    }
+
+    fn builtin_keys_vals(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() != 1 {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: 1,
+                found: arguments.len(),
+            });
+        }
+        let map = self.execute_map(&arguments[0])?;
+        Ok(Value::List(if function == "keys" {
+            map.keys().map(MapKey::to_value).collect()
+        } else {
+            map.into_values().collect()
+        }))
+    }
+
+    fn builtin_merge(&mut self, arguments: &[Syntax]) -> Result<Value, RuntimeError> {
+        let mut merged = BTreeMap::new();
+        for arg in arguments {
+            merged.extend(self.execute_map(arg)?);
+        }
+        Ok(Value::Map(merged))
+    }
+
+    /// Converts a quasiquoted form into data, evaluating the forms inside `unquote` and
+    /// splicing the lists produced by `unquote_splicing`.
+    ///
+    /// Quasiquotes can be nested; `depth` counts how many are open, and only unquotes that
+    /// close the outermost one are evaluated.
+    fn execute_quasiquote(&mut self, syntax: &Syntax, depth: usize) -> Result<Value, RuntimeError> {
+        let elements = match &syntax.kind {
+            SyntaxKind::List(elements) | SyntaxKind::Vector(elements) => elements,
+            SyntaxKind::Map(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    let key = MapKey::from_value(self.execute_quasiquote(key, depth)?)
+                        .map_err(|err| err.with_span(key.span))?;
+                    map.insert(key, self.execute_quasiquote(value, depth)?);
+                }
+                return Ok(Value::Map(map));
+            }
+            _ => return Value::from_syntax(syntax),
+        };
+        match quasiquote_form(syntax) {
+            Some(("unquote", form)) if depth == 1 => return self.execute(form.clone()),
+            Some(("unquote_splicing", _)) if depth == 1 => {
+                return Err(
+                    RuntimeError::UnquoteOutsideQuasiquote("unquote_splicing".to_string())
+                        .with_span(syntax.span),
+                );
+            }
+            Some((name, form)) => {
+                let depth = match name {
+                    "quasiquote" => depth + 1,
+                    _ => depth - 1,
+                };
+                return Ok(Value::List(vec![
+                    Value::Symbol(name.to_string()),
+                    self.execute_quasiquote(form, depth)?,
+                ]));
+            }
+            None => {}
+        }
+
+        let mut values = Vec::new();
+        for element in elements {
+            match quasiquote_form(element) {
+                Some(("unquote_splicing", form)) if depth == 1 => {
+                    match self.execute(form.clone())? {
+                        Value::List(spliced) => values.extend(spliced),
+                        value => {
+                            return Err(RuntimeError::TypeError {
+                                expected: ValueType::List,
+                                found: value.value_type(),
+                            }
+                            .with_span(form.span));
+                        }
+                    }
+                }
+                _ => values.push(self.execute_quasiquote(element, depth)?),
+            }
+        }
+        Ok(Value::List(values))
+    }
+
+    /// Evaluates expressions in order and returns the value of the last one, which is in
+    /// tail position if `tail` is true.
+    fn execute_block(&mut self, body: &[Syntax], tail: bool) -> Result<Value, RuntimeError> {
+        let mut result = Value::Null;
+        for (i, expression) in body.iter().enumerate() {
+            let last = i + 1 == body.len();
+            result = self.execute_tail(expression.clone(), tail && last)?;
+        }
+        Ok(result)
+    }
+
+    fn execute_closure(&mut self, syntax: &Syntax) -> Result<Rc<Closure>, RuntimeError> {
+        match self.execute(syntax.clone())? {
+            Value::Closure(closure) => Ok(closure),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::Closure,
+                found: value.value_type(),
+            }
+            .with_span(syntax.span)),
+        }
+    }
+
+    fn execute_sequence(
+        &mut self,
+        syntax: &Syntax,
+    ) -> Result<Box<dyn Iterator<Item = Value>>, RuntimeError> {
+        self.execute(syntax.clone())?
+            .into_sequence()
+            .map_err(|err| err.with_span(syntax.span))
+    }
+
+    fn execute_bounded_sequence(
+        &mut self,
+        function: &str,
+        syntax: &Syntax,
+    ) -> Result<Box<dyn Iterator<Item = Value>>, RuntimeError> {
+        self.execute(syntax.clone())?
+            .into_bounded_sequence(function)
+            .map_err(|err| err.with_span(syntax.span))
+    }
+
+    fn execute_number(&mut self, syntax: &Syntax) -> Result<f64, RuntimeError> {
+        let value = self.execute(syntax.clone())?;
+        match value.as_scalar() {
+            Some(scalar) => Ok(scalar.to_f64()),
+            None => Err(RuntimeError::TypeError {
+                expected: ValueType::Number,
+                found: value.value_type(),
+            }
+            .with_span(syntax.span)),
+        }
+    }
+
+    fn execute_map(&mut self, syntax: &Syntax) -> Result<BTreeMap<MapKey, Value>, RuntimeError> {
+        match self.execute(syntax.clone())? {
+            Value::Map(map) => Ok(map),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::Map,
+                found: value.value_type(),
+            }
+            .with_span(syntax.span)),
+        }
+    }
+
+    fn execute_map_key(&mut self, syntax: &Syntax) -> Result<MapKey, RuntimeError> {
+        MapKey::from_value(self.execute(syntax.clone())?).map_err(|err| err.with_span(syntax.span))
+    }
+
+    fn execute_note(&mut self, syntax: &Syntax) -> Result<Note, RuntimeError> {
+        match self.execute(syntax.clone())? {
+            Value::Note(note) => Ok(note),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::Note,
+                found: value.value_type(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use super::FunctionDef;
+use crate::vm::value::Value;
+
+/// A lexical environment: the variables and functions bound in one frame, together with a
+/// link to the enclosing environment.
+///
+/// Lookups start in the innermost frame and fall back through the chain to the global
+/// environment. Environments are shared, so cloning one gives another handle to the same
+/// frame, and two handles are equal only if they refer to the same frame.
+#[derive(Clone, Default)]
+pub struct Env(Rc<RefCell<Frame>>);
+
+#[derive(Default)]
+struct Frame {
+    variables: HashMap<String, Value>,
+    functions: HashMap<String, FunctionDef>,
+    parent: Option<Env>,
+}
+
+impl Env {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty environment nested inside this one.
+    pub fn child(&self) -> Env {
+        Env(Rc::new(RefCell::new(Frame {
+            parent: Some(self.clone()),
+            ..Frame::default()
+        })))
+    }
+
+    pub fn parent(&self) -> Option<Env> {
+        self.0.borrow().parent.clone()
+    }
+
+    /// Binds a variable in this frame, shadowing any binding further out.
+    pub fn define(&self, name: String, value: Value) {
+        self.0.borrow_mut().variables.insert(name, value);
+    }
+
+    /// Updates the innermost existing binding of a variable. Returns false if the variable
+    /// is not bound anywhere in the chain.
+    pub fn assign(&self, name: &str, value: Value) -> bool {
+        let mut frame = self.0.borrow_mut();
+        if let Some(slot) = frame.variables.get_mut(name) {
+            *slot = value;
+            return true;
+        }
+        match &frame.parent {
+            Some(parent) => parent.assign(name, value),
+            None => false,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        let frame = self.0.borrow();
+        match frame.variables.get(name) {
+            Some(value) => Some(value.clone()),
+            None => frame.parent.as_ref()?.get(name),
+        }
+    }
+
+    pub fn define_function(&self, function: FunctionDef) {
+        self.0
+            .borrow_mut()
+            .functions
+            .insert(function.name.clone(), function);
+    }
+
+    /// Looks up a function, returning it together with the environment it was defined in,
+    /// which its body is evaluated in.
+    pub fn get_function(&self, name: &str) -> Option<(FunctionDef, Env)> {
+        let frame = self.0.borrow();
+        match frame.functions.get(name) {
+            Some(function) => Some((function.clone(), self.clone())),
+            None => frame.parent.as_ref()?.get_function(name),
+        }
+    }
+}
+
+impl PartialEq for Env {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.0.borrow();
+        let mut variables: Vec<_> = frame.variables.keys().collect();
+        variables.sort();
+        f.debug_struct("Env")
+            .field("variables", &variables)
+            .field("parent", &frame.parent)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_falls_back_to_parent() {
+        let global = Env::new();
+        global.define("x".to_string(), Value::Number(1.0));
+        let local = global.child();
+        local.define("y".to_string(), Value::Number(2.0));
+
+        assert_eq!(local.get("x"), Some(Value::Number(1.0)));
+        assert_eq!(local.get("y"), Some(Value::Number(2.0)));
+        assert_eq!(global.get("y"), None);
+        assert_eq!(local.parent(), Some(global));
+    }
+
+    #[test]
+    fn test_assign_updates_innermost_binding() {
+        let global = Env::new();
+        global.define("x".to_string(), Value::Number(1.0));
+        let local = global.child();
+
+        assert!(local.assign("x", Value::Number(2.0)));
+        assert_eq!(global.get("x"), Some(Value::Number(2.0)));
+
+        local.define("x".to_string(), Value::Number(3.0));
+        assert!(local.assign("x", Value::Number(4.0)));
+        assert_eq!(global.get("x"), Some(Value::Number(2.0)));
+        assert!(!local.assign("missing", Value::Null));
+    }
+}
@@ -0,0 +1,533 @@
+use std::collections::BTreeMap;
+
+use env::Env;
+
+use crate::{
+    parser::{
+        parse_str,
+        syntax::{Syntax, SyntaxKind},
+    },
+    span::Span,
+};
+
+use super::{
+    MAX_NESTED_CALLS, RuntimeError, STACK_OVERFLOW_FRAMES, Vm,
+    graph::{Graph, Node},
+    value::{Closure, MapKey, Value, ValueType},
+};
+
+mod builtins;
+pub mod env;
+
+/// How deeply expressions can nest in the tree walker, counting those in the bodies of the
+/// functions they call, whatever the [`Vm`] allows. Every expression recurses natively
+/// here, so this is limited for the same reason as [`MAX_NESTED_CALLS`], which also limits
+/// calls from builtins here.
+pub const MAX_NESTED_EXPRESSIONS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Syntax,
+}
+
+/// A call in tail position of a function body. Rather than being made from inside the
+/// body, it is handed back to the function call being evaluated, which then runs the
+/// callee's body in place of its own, so tail calls do not grow the stack.
+#[derive(Debug, Clone, PartialEq)]
+struct TailCall {
+    name: String,
+    env: Env,
+    body: Syntax,
+}
+
+/// A request to leave or restart the innermost loop, raised by `break` or `continue`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    /// Ends the loop, which then evaluates to the given value.
+    Break(Value),
+    /// Skips the rest of the loop body and starts the next iteration.
+    Continue,
+}
+
+#[derive(Clone)]
+pub struct Scope<'vm> {
+    pub vm: &'vm Vm,
+    /// The environment expressions are currently evaluated in.
+    pub env: Env,
+    pub call_stack: Vec<String>,
+    pub graph: Graph,
+    /// The `break` or `continue` currently unwinding to its loop, if any.
+    signal: Option<Signal>,
+    /// How many loops enclose the current expression within the current function body.
+    loop_depth: usize,
+    /// The tail call the current function body ended with, if any.
+    tail_call: Option<TailCall>,
+    /// How many expressions are being evaluated, each within the next.
+    nesting: usize,
+    /// How many calls from builtins are in progress.
+    nested_calls: usize,
+}
+
+impl<'vm> Scope<'vm> {
+    pub fn new(vm: &'vm Vm) -> Self {
+        let env = Env::new();
+        env.define("dac".to_string(), Value::Node(Node::Output));
+        Self {
+            vm,
+            env,
+            call_stack: Vec::new(),
+            graph: Graph::default(),
+            signal: None,
+            loop_depth: 0,
+            tail_call: None,
+            nesting: 0,
+            nested_calls: 0,
+        }
+    }
+
+    pub fn execute_str(&mut self, input: &str) -> Result<Value, RuntimeError> {
+        let syntax_tree = parse_str(input)?;
+        self.execute_syntax(syntax_tree)
+    }
+
+    pub fn execute_syntax(&mut self, syntax_tree: Vec<Syntax>) -> Result<Value, RuntimeError> {
+        let mut result = Value::Null;
+        for syntax in syntax_tree {
+            result = self.execute(syntax)?;
+        }
+        Ok(result)
+    }
+
+    pub fn execute(&mut self, syntax: Syntax) -> Result<Value, RuntimeError> {
+        self.execute_tail(syntax, false)
+    }
+
+    /// Evaluates the syntax, which is the last thing a function body evaluates if `tail`
+    /// is true. A call to a function there is deferred until the body has returned.
+    pub fn execute_tail(&mut self, syntax: Syntax, tail: bool) -> Result<Value, RuntimeError> {
+        // While a `break` or `continue` unwinds to its loop, nothing is evaluated, and the
+        // values and errors of the forms it interrupted are discarded.
+        if self.signal.is_some() {
+            return Ok(Value::Null);
+        }
+        if self.nesting >= MAX_NESTED_EXPRESSIONS {
+            return Err(self.stack_overflow(MAX_NESTED_EXPRESSIONS));
+        }
+        let span = syntax.span;
+        self.nesting += 1;
+        let result = self
+            .execute_kind(syntax, tail)
+            .map_err(|err| err.with_span(span));
+        self.nesting -= 1;
+        if self.signal.is_some() {
+            return Ok(Value::Null);
+        }
+        result
+    }
+
+    fn execute_kind(&mut self, syntax: Syntax, tail: bool) -> Result<Value, RuntimeError> {
+        let syntax_type = syntax.syntax_type();
+        match syntax.kind {
+            SyntaxKind::Integer(value) => Ok(Value::Number(value as f64)),
+            SyntaxKind::Float(value) => Ok(Value::Number(value)),
+            SyntaxKind::Rational(value) => Ok(Value::Rational(value)),
+            SyntaxKind::Quantity(value) => Ok(Value::Quantity(value)),
+            SyntaxKind::Boolean(value) => Ok(Value::Boolean(value)),
+            SyntaxKind::Symbol(value) => Ok(Value::Symbol(value)),
+            SyntaxKind::Note(value) => Ok(Value::Note(value)),
+            SyntaxKind::String(value) => Ok(Value::String(value)),
+            SyntaxKind::Identifier(name) => self.get_variable(&name),
+            SyntaxKind::List(elements) => match elements.split_first() {
+                Some((head, arguments)) => self.execute_call(head, arguments, tail),
+                None => Ok(Value::Null),
+            },
+            SyntaxKind::Vector(elements) => self.execute_vector(elements),
+            SyntaxKind::Map(entries) => self.execute_map_literal(entries),
+            _ => Err(RuntimeError::InvalidSyntax(syntax_type)),
+        }
+    }
+
+    fn execute_vector(&mut self, elements: Vec<Syntax>) -> Result<Value, RuntimeError> {
+        let mut values = Vec::new();
+        for element in elements {
+            values.push(self.execute(element)?);
+        }
+        Ok(Value::List(values))
+    }
+
+    fn execute_map_literal(
+        &mut self,
+        entries: Vec<(Syntax, Syntax)>,
+    ) -> Result<Value, RuntimeError> {
+        let mut map = BTreeMap::new();
+        for (key, value) in entries {
+            let span = key.span;
+            let key = MapKey::from_value(self.execute(key)?).map_err(|err| err.with_span(span))?;
+            map.insert(key, self.execute(value)?);
+        }
+        Ok(Value::Map(map))
+    }
+
+    /// Calls whatever the head of a list refers to.
+    ///
+    /// A name is resolved as a special form first, so those cannot be shadowed, then as a
+    /// variable, a user-defined function and finally a builtin function. Any other head is
+    /// evaluated, and the value it produces is called.
+    pub fn execute_call(
+        &mut self,
+        head: &Syntax,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        let (SyntaxKind::Identifier(name) | SyntaxKind::Operator(name)) = &head.kind else {
+            let callee = self.execute(head.clone())?;
+            return self.call_value(callee, head.span, arguments, tail);
+        };
+        self.execute_named_call(name, head.span, arguments, tail)
+    }
+
+    /// Enters a call of a function or closure, which only builtins and special forms do not
+    /// count as.
+    fn push_frame(&mut self, name: String) -> Result<(), RuntimeError> {
+        if self.call_stack.len() >= self.vm.max_call_depth() {
+            return Err(self.stack_overflow(self.vm.max_call_depth()));
+        }
+        self.call_stack.push(name);
+        Ok(())
+    }
+
+    /// Reports the innermost calls in progress.
+    fn stack_overflow(&self, depth: usize) -> RuntimeError {
+        let start = self.call_stack.len().saturating_sub(STACK_OVERFLOW_FRAMES);
+        RuntimeError::StackOverflow {
+            depth,
+            frames: self.call_stack[start..].to_vec(),
+        }
+    }
+
+    fn execute_named_call(
+        &mut self,
+        name: &str,
+        span: Span,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        match self.execute_special_form(name, arguments, tail) {
+            Err(RuntimeError::UndefinedFunction(_)) => {}
+            result => return result,
+        }
+        if let Some(callee) = self.env.get(name) {
+            return self.call_value(callee, span, arguments, tail);
+        }
+        match self.execute_function(name, arguments, tail) {
+            Err(RuntimeError::UndefinedFunction(_)) => {}
+            result => return result,
+        }
+        if let Some(function) = self.vm.functions.get(name).cloned() {
+            let mut values = Vec::new();
+            for arg in arguments {
+                values.push(self.execute(arg.clone())?);
+            }
+            return function(&values);
+        }
+        match self.execute_builtin_function(name, arguments) {
+            Err(RuntimeError::UndefinedFunction(_)) => {}
+            result => return result,
+        }
+        Err(RuntimeError::UndefinedIdentifier(name.to_string()))
+    }
+
+    /// Calls a value, which fails unless it is callable. `span` is where the value came
+    /// from, for reporting that it cannot be called.
+    fn call_value(
+        &mut self,
+        callee: Value,
+        span: Span,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        match callee {
+            Value::Closure(closure) => {
+                let mut values = Vec::new();
+                for arg in arguments {
+                    values.push(self.execute(arg.clone())?);
+                }
+                let (env, body) = bind(&closure, values)?;
+                self.enter_body("fn", env, body, tail)
+            }
+            value => Err(RuntimeError::NotCallable {
+                found: value.value_type(),
+            }
+            .with_span(span)),
+        }
+    }
+
+    fn execute_function(
+        &mut self,
+        function: &str,
+        arguments: &[Syntax],
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        let (function, env) = self.get_function(function)?;
+
+        if function.parameters.len() != arguments.len() {
+            return Err(RuntimeError::InvalidArgumentCount {
+                expected: function.parameters.len(),
+                found: arguments.len(),
+            });
+        }
+
+        // The arguments are evaluated in the caller's environment, but the body sees the
+        // environment the function was defined in, so it can reach globals and other
+        // functions, including itself.
+        let local = env.child();
+        for (param, arg) in function.parameters.iter().zip(arguments) {
+            let value = self.execute(arg.clone())?;
+            local.define(param.clone(), value);
+        }
+        self.enter_body(&function.name, local, function.body, tail)
+    }
+
+    /// Calls a closure with already evaluated arguments. The body runs in a new environment
+    /// nested inside the one the closure was created in.
+    pub fn apply_closure(
+        &mut self,
+        closure: &Closure,
+        arguments: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        if self.nested_calls >= MAX_NESTED_CALLS {
+            return Err(self.stack_overflow(MAX_NESTED_CALLS));
+        }
+        let (env, body) = bind(closure, arguments)?;
+        self.nested_calls += 1;
+        let result = self.execute_body("fn", env, body);
+        self.nested_calls -= 1;
+        result
+    }
+
+    /// Runs a function body, or if the call is in tail position, hands it back to the
+    /// enclosing function call to run instead.
+    fn enter_body(
+        &mut self,
+        name: &str,
+        env: Env,
+        body: Syntax,
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        if tail {
+            self.tail_call = Some(TailCall {
+                name: name.to_string(),
+                env,
+                body,
+            });
+            return Ok(Value::Null);
+        }
+        self.execute_body(name, env, body)
+    }
+
+    /// Evaluates the body of the function `name`, then the body of each function it tail
+    /// calls in turn, which take over its frame on the call stack. Loops around the call do
+    /// not extend into the body, so `break` and `continue` only apply to loops within the
+    /// function itself.
+    fn execute_body(
+        &mut self,
+        name: &str,
+        mut env: Env,
+        mut body: Syntax,
+    ) -> Result<Value, RuntimeError> {
+        self.push_frame(name.to_string())?;
+        let loop_depth = std::mem::take(&mut self.loop_depth);
+        let result = loop {
+            let result = self.execute_in(env, body, true);
+            match self.tail_call.take() {
+                Some(call) if result.is_ok() => {
+                    if let Some(frame) = self.call_stack.last_mut() {
+                        *frame = call.name;
+                    }
+                    env = call.env;
+                    body = call.body;
+                }
+                _ => break result,
+            }
+        };
+        self.loop_depth = loop_depth;
+        self.call_stack.pop();
+        result
+    }
+
+    /// Runs one iteration of a loop body. Returns the value to end the loop with if the
+    /// body used `break`.
+    pub fn execute_iteration(&mut self, body: &[Syntax]) -> Result<Option<Value>, RuntimeError> {
+        self.loop_depth += 1;
+        let mut result = Ok(());
+        for expression in body {
+            if let Err(err) = self.execute(expression.clone()) {
+                result = Err(err);
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        result?;
+        match self.signal.take() {
+            Some(Signal::Break(value)) => Ok(Some(value)),
+            Some(Signal::Continue) | None => Ok(None),
+        }
+    }
+
+    /// Starts unwinding to the innermost loop, or fails if there is none.
+    pub fn raise_signal(&mut self, name: &str, signal: Signal) -> Result<Value, RuntimeError> {
+        if self.loop_depth == 0 {
+            return Err(RuntimeError::OutsideLoop(name.to_string()));
+        }
+        self.signal = Some(signal);
+        Ok(Value::Null)
+    }
+
+    /// Evaluates the syntax in the given environment, then switches back to the current one.
+    pub fn execute_in(
+        &mut self,
+        env: Env,
+        syntax: Syntax,
+        tail: bool,
+    ) -> Result<Value, RuntimeError> {
+        let previous = std::mem::replace(&mut self.env, env);
+        let result = self.execute_tail(syntax, tail);
+        self.env = previous;
+        result
+    }
+
+    /// Binds a variable in the current environment.
+    pub fn set_variable(&mut self, name: String, value: Value) {
+        self.env.define(name, value);
+    }
+
+    /// Updates an existing variable in whichever enclosing environment binds it.
+    pub fn assign_variable(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        if self.env.assign(name, value) {
+            Ok(())
+        } else {
+            Err(RuntimeError::UndefinedVariable(name.to_string()))
+        }
+    }
+
+    pub fn get_variable(&self, name: &str) -> Result<Value, RuntimeError> {
+        self.env
+            .get(name)
+            .ok_or(RuntimeError::UndefinedVariable(name.to_string()))
+    }
+
+    pub fn set_function(&mut self, function: FunctionDef) {
+        self.env.define_function(function);
+    }
+
+    /// Looks up a function along with the environment it was defined in.
+    pub fn get_function(&self, name: &str) -> Result<(FunctionDef, Env), RuntimeError> {
+        self.env
+            .get_function(name)
+            .ok_or(RuntimeError::UndefinedFunction(name.to_string()))
+    }
+
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    pub fn call_stack(&self) -> &[String] {
+        &self.call_stack
+    }
+}
+
+/// Creates the environment the body of a closure runs in, with the parameters bound to
+/// `arguments`, and returns it together with the body. Closures compiled for the
+/// [`Machine`](super::machine::Machine) cannot be called by the tree walker.
+fn bind(closure: &Closure, arguments: Vec<Value>) -> Result<(Env, Syntax), RuntimeError> {
+    let Closure::Interpreted {
+        parameters,
+        body,
+        env,
+    } = closure
+    else {
+        return Err(RuntimeError::NotCallable {
+            found: ValueType::Closure,
+        });
+    };
+    if parameters.len() != arguments.len() {
+        return Err(RuntimeError::InvalidArgumentCount {
+            expected: parameters.len(),
+            found: arguments.len(),
+        });
+    }
+    let env = env.child();
+    for (param, value) in parameters.iter().zip(arguments) {
+        env.define(param.clone(), value);
+    }
+    Ok((env, body.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::machine::Machine;
+
+    /// Runs every example with both the tree walker and the compiler, which must agree on
+    /// the result and on the graph the example builds.
+    #[test]
+    fn test_examples_match_machine() {
+        let examples = concat!(env!("CARGO_MANIFEST_DIR"), "/../../examples");
+        let mut paths: Vec<_> = std::fs::read_dir(examples)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        let vm = Vm::new();
+        for path in paths {
+            let source = std::fs::read_to_string(&path).unwrap();
+            let mut scope = Scope::new(&vm);
+            let mut machine = Machine::new(&vm);
+            // Closures differ in how they are represented, so results are compared by how
+            // they print.
+            let expected = scope.execute_str(&source).map(|value| value.to_string());
+            let found = machine.execute_str(&source).map(|value| value.to_string());
+            assert_eq!(found, expected, "{}", path.display());
+            assert_eq!(machine.graph(), scope.graph(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_call_depth_counts_function_calls() {
+        let source = "
+            (func fact (n) (if (<= n 1) 1 (* n (fact (- n 1)))))
+            (define twice (fn (n) (* 2 (fact n))))";
+        let vm = Vm::new().with_max_call_depth(60);
+        let run = |program: &str| Scope::new(&vm).execute_str(&format!("{source} {program}"));
+        // Only calls of functions and closures count, not the builtins and special forms
+        // in their bodies.
+        assert!(run("(fact 60)").is_ok());
+        assert!(run("(twice 59)").is_ok());
+        for program in ["(fact 61)", "(twice 60)"] {
+            let err = run(program).unwrap_err();
+            assert_eq!(
+                err.inner(),
+                &RuntimeError::StackOverflow {
+                    depth: 60,
+                    frames: vec!["fact".to_string(); STACK_OVERFLOW_FRAMES],
+                },
+                "{program}"
+            );
+        }
+
+        // Recursion is stopped before it runs out of native stack, whatever the limit.
+        let vm = Vm::new();
+        let err = Scope::new(&vm)
+            .execute_str(&format!("{source} (fact 1000)"))
+            .unwrap_err();
+        let RuntimeError::StackOverflow { depth, frames } = err.inner() else {
+            panic!("expected a stack overflow, found {err:?}");
+        };
+        assert_eq!(*depth, MAX_NESTED_EXPRESSIONS);
+        assert_eq!(frames, &vec!["fact"; STACK_OVERFLOW_FRAMES]);
+    }
+}
@@ -1,12 +1,36 @@
-use super::RuntimeError;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+};
+
+use crate::{
+    note::Note,
+    number::{Rational, Scalar},
+    parser::syntax::{Syntax, SyntaxKind, write_sequence, write_string_literal},
+    quantity::Quantity,
+};
+
+#[cfg(feature = "tree-walker")]
+use super::tree_walker::env::Env;
+use super::{RuntimeError, bytecode::Function, graph::Node};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ValueType {
     Number,
+    Rational,
+    Quantity,
     Symbol,
+    Note,
     String,
     Boolean,
     List,
+    Map,
+    Node,
+    Closure,
+    Range,
     Null,
 }
 
@@ -14,10 +38,17 @@ impl ValueType {
     pub fn from_value(value: &Value) -> Self {
         match value {
             Value::Number(_) => ValueType::Number,
+            Value::Rational(_) => ValueType::Rational,
+            Value::Quantity(_) => ValueType::Quantity,
             Value::Symbol(_) => ValueType::Symbol,
+            Value::Note(_) => ValueType::Note,
             Value::String(_) => ValueType::String,
             Value::Boolean(_) => ValueType::Boolean,
             Value::List(_) => ValueType::List,
+            Value::Map(_) => ValueType::Map,
+            Value::Node(_) => ValueType::Node,
+            Value::Closure(_) => ValueType::Closure,
+            Value::Range(_) => ValueType::Range,
             Value::Null => ValueType::Null,
         }
     }
@@ -26,21 +57,447 @@ impl ValueType {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
+    Rational(Rational),
+    Quantity(Quantity),
     Symbol(String),
+    Note(Note),
     String(String),
     Boolean(bool),
     List(Vec<Value>),
+    Map(BTreeMap<MapKey, Value>),
+    Node(Node),
+    Closure(Rc<Closure>),
+    Range(Range),
     Null,
 }
 
+/// A variable shared between a function and the closures that capture it.
+pub type Cell = Rc<RefCell<Value>>;
+
+/// A function created by `fn`, `lambda` or `func`. It keeps the variables it uses from the
+/// scope it was created in, so its body can use them even after that scope has ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Closure {
+    Compiled {
+        function: Rc<Function>,
+        captures: Rc<[Cell]>,
+    },
+    /// A closure created by the tree-walking interpreter, which keeps its whole
+    /// environment.
+    #[cfg(feature = "tree-walker")]
+    Interpreted {
+        parameters: Vec<String>,
+        body: Syntax,
+        env: Env,
+    },
+}
+
+impl Closure {
+    pub fn parameters(&self) -> &[String] {
+        match self {
+            Closure::Compiled { function, .. } => &function.parameters,
+            #[cfg(feature = "tree-walker")]
+            Closure::Interpreted { parameters, .. } => parameters,
+        }
+    }
+
+    pub fn body(&self) -> Option<&Syntax> {
+        match self {
+            Closure::Compiled { function, .. } => function.body.as_ref(),
+            #[cfg(feature = "tree-walker")]
+            Closure::Interpreted { body, .. } => Some(body),
+        }
+    }
+}
+
+/// A lazy sequence of evenly spaced numbers, created by `range`. The numbers are only
+/// computed as the sequence is iterated, so a range can be unbounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    /// The first number past the end of the range, which is never reached. Infinite for an
+    /// unbounded range.
+    pub end: f64,
+    /// The distance between numbers, which may be negative but never zero.
+    pub step: f64,
+}
+
+impl Range {
+    /// Creates a range, or returns `None` if `step` is zero.
+    pub fn new(start: f64, end: f64, step: f64) -> Option<Range> {
+        (step != 0.0).then_some(Range { start, end, step })
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.end.is_finite()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = f64> + use<> {
+        let Range { start, end, step } = *self;
+        // Each number is computed from the start rather than by repeated addition, so
+        // rounding errors do not accumulate.
+        (0u64..)
+            .map(move |i| start + i as f64 * step)
+            .take_while(move |n| if step > 0.0 { *n < end } else { *n > end })
+    }
+}
+
+/// A key in a [`Value::Map`]. Only symbols, strings and notes can be used as keys, and
+/// they are distinct, so `:cutoff` and `"cutoff"` are different keys. Notes are compared
+/// as they are spelled, so `:C#4` and `:Db4` are different keys too.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MapKey {
+    Symbol(String),
+    String(String),
+    Note(Note),
+}
+
+impl MapKey {
+    pub fn from_value(value: Value) -> Result<MapKey, RuntimeError> {
+        match value {
+            Value::Symbol(name) => Ok(MapKey::Symbol(name)),
+            Value::String(s) => Ok(MapKey::String(s)),
+            Value::Note(note) => Ok(MapKey::Note(note)),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::Symbol,
+                found: value.value_type(),
+            }),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Symbol(name) => Value::Symbol(name.clone()),
+            MapKey::String(s) => Value::String(s.clone()),
+            MapKey::Note(note) => Value::Note(*note),
+        }
+    }
+}
+
+/// Prints the value as the Callisto source for it, e.g. `42`, `"text"` or `(:C4 :E4)`.
+/// Integral numbers are printed without a decimal point, and symbols that cannot be
+/// written as `:name` are printed quoted, as `'name`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Value::Number(n) => write!(f, "{n:?}"),
+            Value::Rational(r) => write!(f, "{r}"),
+            Value::Quantity(q) => write!(f, "{q}"),
+            Value::Symbol(name) if is_symbol_name(name) => write!(f, ":{name}"),
+            Value::Symbol(name) => write!(f, "'{name}"),
+            Value::Note(note) => write!(f, ":{note}"),
+            Value::String(s) => write_string_literal(f, s),
+            Value::Boolean(b) => write!(f, "{b}"),
+            Value::List(elements) => write_sequence(f, "(", elements, ")"),
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{} {value}", key.to_value())?;
+                }
+                write!(f, "}}")
+            }
+            Value::Node(node) => write!(f, "{node}"),
+            Value::Range(range) if !range.is_bounded() => write!(f, "(range)"),
+            Value::Range(range) => write!(
+                f,
+                "(range {} {} {})",
+                Value::Number(range.start),
+                Value::Number(range.end),
+                Value::Number(range.step)
+            ),
+            Value::Closure(closure) => {
+                write!(f, "(fn ")?;
+                write_sequence(f, "(", closure.parameters(), ")")?;
+                match closure.body() {
+                    Some(body) => write!(f, " {body})"),
+                    None => write!(f, ")"),
+                }
+            }
+            Value::Null => write!(f, "()"),
+        }
+    }
+}
+
+/// Returns true if `:name` lexes as a symbol with this name, rather than as a note or
+/// not at all.
+fn is_symbol_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.parse::<Note>().is_err()
+}
+
+/// Returns the note with a MIDI number computed by a program, failing if the number is not
+/// whole or the note is out of range.
+pub(crate) fn note_from_midi(midi: f64) -> Result<Note, RuntimeError> {
+    if midi.fract() == 0.0
+        && (i32::MIN as f64..=i32::MAX as f64).contains(&midi)
+        && let Some(note) = Note::from_midi(midi as i32)
+    {
+        return Ok(note);
+    }
+    Err(RuntimeError::NoteOutOfRange(midi))
+}
+
 impl Value {
     pub fn value_type(&self) -> ValueType {
         ValueType::from_value(self)
     }
 
+    /// Copies the value together with the variables its closures captured, so that calling
+    /// the copies does not affect the original. `copies` maps the variables copied so far to
+    /// their copies, so closures that shared a variable share its copy.
+    pub(crate) fn deep_clone(&self, copies: &mut HashMap<*const RefCell<Value>, Cell>) -> Value {
+        match self {
+            Value::List(values) => Value::List(
+                values
+                    .iter()
+                    .map(|value| value.deep_clone(copies))
+                    .collect(),
+            ),
+            Value::Map(map) => Value::Map(
+                map.iter()
+                    .map(|(key, value)| (key.clone(), value.deep_clone(copies)))
+                    .collect(),
+            ),
+            Value::Closure(closure) => match closure.as_ref() {
+                Closure::Compiled { function, captures } => {
+                    let captures = captures
+                        .iter()
+                        .map(|cell| {
+                            if let Some(copy) = copies.get(&Rc::as_ptr(cell)) {
+                                return copy.clone();
+                            }
+                            // The copy is recorded before its contents are copied, as they
+                            // may refer back to it.
+                            let copy = Rc::new(RefCell::new(Value::Null));
+                            copies.insert(Rc::as_ptr(cell), copy.clone());
+                            *copy.borrow_mut() = cell.borrow().deep_clone(copies);
+                            copy
+                        })
+                        .collect();
+                    Value::Closure(Rc::new(Closure::Compiled {
+                        function: function.clone(),
+                        captures,
+                    }))
+                }
+                #[cfg(feature = "tree-walker")]
+                Closure::Interpreted { .. } => self.clone(),
+            },
+            value => value.clone(),
+        }
+    }
+
+    /// Returns false for `false` and null, and true for every other value, including `0`,
+    /// empty strings and empty lists.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Boolean(false) | Value::Null)
+    }
+
+    /// Returns the elements of a list or range, computing those of a range as they are
+    /// consumed. Null is treated as an empty list.
+    pub fn into_sequence(self) -> Result<Box<dyn Iterator<Item = Value>>, RuntimeError> {
+        match self {
+            Value::List(list) => Ok(Box::new(list.into_iter())),
+            Value::Range(range) => Ok(Box::new(range.iter().map(Value::Number))),
+            Value::Null => Ok(Box::new(std::iter::empty())),
+            value => Err(RuntimeError::TypeError {
+                expected: ValueType::List,
+                found: value.value_type(),
+            }),
+        }
+    }
+
+    /// Returns the elements like [`Value::into_sequence`], but fails for an unbounded range,
+    /// which `function` would otherwise never finish consuming.
+    pub fn into_bounded_sequence(
+        self,
+        function: &str,
+    ) -> Result<Box<dyn Iterator<Item = Value>>, RuntimeError> {
+        match self {
+            Value::Range(range) if !range.is_bounded() => {
+                Err(RuntimeError::UnboundedRange(function.to_string()))
+            }
+            value => value.into_sequence(),
+        }
+    }
+
+    /// Compares two values by what they represent rather than how they are stored.
+    ///
+    /// Numbers are equal if they have the same value, whether they are integers, floats or
+    /// rationals, quantities are compared after converting to a common unit, notes are equal
+    /// if they have the same pitch, and lists and maps are equal if their elements are. A
+    /// bounded range is equal to the list of its numbers.
+    pub fn structural_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Range(range), list @ Value::List(_))
+            | (list @ Value::List(_), Value::Range(range))
+                if range.is_bounded() =>
+            {
+                Value::List(range.iter().map(Value::Number).collect()).structural_eq(list)
+            }
+            (Value::Quantity(a), Value::Quantity(b)) => a
+                .unify(b)
+                .is_some_and(|(a, b, _)| a.compare(b) == Some(Ordering::Equal)),
+            (Value::Note(a), Value::Note(b)) => a.midi() == b.midi(),
+            (Value::List(a), Value::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.structural_eq(b))
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b)
+                        .all(|((key_a, a), (key_b, b))| key_a == key_b && a.structural_eq(b))
+            }
+            _ => match (self.as_scalar(), other.as_scalar()) {
+                (Some(a), Some(b)) => a.compare(b) == Some(Ordering::Equal),
+                _ => self == other,
+            },
+        }
+    }
+
+    /// Orders two numbers, quantities with compatible units, notes by pitch, or strings.
+    /// Returns `None` if either number is NaN, and fails for values that cannot be ordered.
+    pub fn compare(
+        &self,
+        other: &Value,
+        operation: &str,
+    ) -> Result<Option<Ordering>, RuntimeError> {
+        if let (Some(a), Some(b)) = (self.as_scalar(), other.as_scalar()) {
+            return Ok(a.compare(b));
+        }
+        match (self, other) {
+            (Value::Quantity(a), Value::Quantity(b)) => {
+                let (a, b, _) = a
+                    .unify(b)
+                    .ok_or_else(|| self.invalid_operation(other, operation))?;
+                Ok(a.compare(b))
+            }
+            (Value::Note(a), Value::Note(b)) => Ok(Some(a.midi().cmp(&b.midi()))),
+            (Value::String(a), Value::String(b)) => Ok(Some(a.cmp(b))),
+            _ => Err(self.invalid_operation(other, operation)),
+        }
+    }
+
+    /// Returns the value as a scalar if it is a number. Integral floats are treated as
+    /// exact so that they combine with rationals without losing precision.
+    pub fn as_scalar(&self) -> Option<Scalar> {
+        match self {
+            Value::Number(n) => {
+                Some(Rational::from_integral(*n).map_or(Scalar::Float(*n), Scalar::Exact))
+            }
+            Value::Rational(r) => Some(Scalar::Exact(*r)),
+            _ => None,
+        }
+    }
+
+    pub fn from_scalar(scalar: Scalar) -> Value {
+        match scalar {
+            Scalar::Exact(r) => Value::Rational(r),
+            Scalar::Float(f) => Value::Number(f),
+        }
+    }
+
+    /// Converts a quoted form into data. Identifiers and operators become symbols, and
+    /// lists, vectors and maps are converted element by element without being evaluated.
+    /// Fails only if a map has a key that is not a symbol or string.
+    pub fn from_syntax(syntax: &Syntax) -> Result<Value, RuntimeError> {
+        let value = match &syntax.kind {
+            SyntaxKind::Integer(n) => Value::Number(*n as f64),
+            SyntaxKind::Float(n) => Value::Number(*n),
+            SyntaxKind::Rational(r) => Value::Rational(*r),
+            SyntaxKind::Quantity(q) => Value::Quantity(*q),
+            SyntaxKind::String(s) => Value::String(s.clone()),
+            SyntaxKind::Boolean(b) => Value::Boolean(*b),
+            SyntaxKind::Identifier(name)
+            | SyntaxKind::Symbol(name)
+            | SyntaxKind::Operator(name) => Value::Symbol(name.clone()),
+            SyntaxKind::Note(note) => Value::Note(*note),
+            SyntaxKind::List(elements) | SyntaxKind::Vector(elements) => Value::List(
+                elements
+                    .iter()
+                    .map(Value::from_syntax)
+                    .collect::<Result<_, _>>()?,
+            ),
+            SyntaxKind::Map(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    let key = MapKey::from_value(Value::from_syntax(key)?)
+                        .map_err(|err| err.with_span(key.span))?;
+                    map.insert(key, Value::from_syntax(value)?);
+                }
+                Value::Map(map)
+            }
+        };
+        Ok(value)
+    }
+
+    /// Returns both operands as scalars if at least one of them is rational, so that
+    /// arithmetic between them stays exact.
+    fn exact_operands(&self, other: &Value) -> Option<(Scalar, Scalar)> {
+        match (self, other) {
+            (Value::Rational(_), _) | (_, Value::Rational(_)) => {
+                Some((self.as_scalar()?, other.as_scalar()?))
+            }
+            _ => None,
+        }
+    }
+
+    fn invalid_operation(&self, other: &Value, operation: &str) -> RuntimeError {
+        RuntimeError::InvalidOperation {
+            operation: operation.to_string(),
+            left: self.value_type(),
+            right: other.value_type(),
+        }
+    }
+
+    fn is_signal_operand(&self, other: &Value) -> bool {
+        matches!(self, Value::Node(_)) || matches!(other, Value::Node(_))
+    }
+
+    /// Combines two values into a signal node, treating numbers as constant signals.
+    fn signal_op(
+        &self,
+        other: &Value,
+        operation: &str,
+        node: fn(Box<Node>, Box<Node>) -> Node,
+    ) -> Result<Value, RuntimeError> {
+        match (Node::from_value(self), Node::from_value(other)) {
+            (Ok(a), Ok(b)) => Ok(Value::Node(node(Box::new(a), Box::new(b)))),
+            _ => Err(RuntimeError::InvalidOperation {
+                operation: operation.to_string(),
+                left: self.value_type(),
+                right: other.value_type(),
+            }),
+        }
+    }
+
     pub fn add(&self, other: &Value) -> Result<Value, RuntimeError> {
+        if self.is_signal_operand(other) {
+            return self.signal_op(other, "+", Node::Add);
+        }
+        if let Some((a, b)) = self.exact_operands(other) {
+            return Ok(Value::from_scalar(a + b));
+        }
         match (self, other) {
+            (Value::Quantity(a), Value::Quantity(b)) => {
+                let (a, b, unit) = a
+                    .unify(b)
+                    .ok_or_else(|| self.invalid_operation(other, "+"))?;
+                Ok(Value::Quantity(Quantity::new(a + b, unit)))
+            }
             (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            (Value::Note(note), Value::Number(n)) | (Value::Number(n), Value::Note(note))
+                if n.fract() == 0.0 =>
+            {
+                Ok(Value::Note(note_from_midi(note.midi() as f64 + n)?))
+            }
             (Value::String(a), Value::String(b)) => Ok(Value::String(a.clone() + b)),
             (Value::List(a), Value::List(b)) => {
                 let mut new_list = a.clone();
@@ -56,8 +513,24 @@ impl Value {
     }
 
     pub fn sub(&self, other: &Value) -> Result<Value, RuntimeError> {
+        if self.is_signal_operand(other) {
+            return self.signal_op(other, "-", Node::Sub);
+        }
+        if let Some((a, b)) = self.exact_operands(other) {
+            return Ok(Value::from_scalar(a - b));
+        }
         match (self, other) {
+            (Value::Quantity(a), Value::Quantity(b)) => {
+                let (a, b, unit) = a
+                    .unify(b)
+                    .ok_or_else(|| self.invalid_operation(other, "-"))?;
+                Ok(Value::Quantity(Quantity::new(a - b, unit)))
+            }
             (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
+            (Value::Note(note), Value::Number(n)) if n.fract() == 0.0 => {
+                Ok(Value::Note(note_from_midi(note.midi() as f64 - n)?))
+            }
+            (Value::Note(a), Value::Note(b)) => Ok(Value::Number((a.midi() - b.midi()) as f64)),
             _ => Err(RuntimeError::InvalidOperation {
                 operation: "-".to_string(),
                 left: self.value_type(),
@@ -67,7 +540,17 @@ impl Value {
     }
 
     pub fn mul(&self, other: &Value) -> Result<Value, RuntimeError> {
+        if self.is_signal_operand(other) {
+            return self.signal_op(other, "*", Node::Mul);
+        }
+        if let Some((a, b)) = self.exact_operands(other) {
+            return Ok(Value::from_scalar(a * b));
+        }
         match (self, other) {
+            (Value::Quantity(q), value) | (value, Value::Quantity(q)) => match value.as_scalar() {
+                Some(scalar) => Ok(Value::Quantity(Quantity::new(q.amount * scalar, q.unit))),
+                None => Err(self.invalid_operation(other, "*")),
+            },
             (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
 
             _ => Err(RuntimeError::InvalidOperation {
@@ -79,7 +562,34 @@ impl Value {
     }
 
     pub fn div(&self, other: &Value) -> Result<Value, RuntimeError> {
+        if self.is_signal_operand(other) {
+            return self.signal_op(other, "/", Node::Div);
+        }
+        if let Some((a, b)) = self.exact_operands(other) {
+            return a
+                .checked_div(b)
+                .map(Value::from_scalar)
+                .ok_or(RuntimeError::DivisionByZero);
+        }
         match (self, other) {
+            (Value::Quantity(a), Value::Quantity(b)) => {
+                let (a, b, _) = a
+                    .unify(b)
+                    .ok_or_else(|| self.invalid_operation(other, "/"))?;
+                a.checked_div(b)
+                    .map(Value::from_scalar)
+                    .ok_or(RuntimeError::DivisionByZero)
+            }
+            (Value::Quantity(q), value) => match value.as_scalar() {
+                Some(scalar) => {
+                    let amount = q
+                        .amount
+                        .checked_div(scalar)
+                        .ok_or(RuntimeError::DivisionByZero)?;
+                    Ok(Value::Quantity(Quantity::new(amount, q.unit)))
+                }
+                None => Err(self.invalid_operation(other, "/")),
+            },
             (Value::Number(a), Value::Number(b)) => {
                 if *b == 0.0 {
                     return Err(RuntimeError::DivisionByZero);